@@ -0,0 +1,59 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use rust_tinynix_core::parser::{parse, parse_recovering};
+use std::fmt::Write;
+use std::hint::black_box;
+use std::path::Path;
+
+/// A package set in the style of nixpkgs, with `count` packages. It sticks to the syntax
+/// the previous parser accepted, so the two can be compared.
+fn package_set(count: usize) -> String {
+    let mut source = String::from("let\n  lib = import ./lib.nix;\n  # Shared settings.\n");
+    source.push_str("  common = { license = \"mit\"; platforms = [ \"x86_64-linux\" ]; };\nin\n");
+    source.push_str("with lib;\nrec {\n");
+    for i in 0..count {
+        let deps = if i > 1 {
+            format!("p{} p{}", i - 1, i / 2)
+        } else {
+            String::new()
+        };
+        writeln!(
+            source,
+            r#"  p{i} = {{
+    pname = "p{i}";
+    version = "1.${{toString {i}}}";
+    src = ./pkgs/p{i}/src.tar.gz;
+    deps = [ {deps} ];
+    meta = common // {{
+      description = "Package number {i}, built with ${{builder}}.";
+      homepage.url = "https://example.org/p{i}";
+    }};
+    build = args: mkDerivation (args // {{ inherit (common) license; }});
+  }};"#
+        )
+        .unwrap();
+    }
+    source.push_str("}\n");
+    source
+}
+
+fn parsing(c: &mut Criterion) {
+    let source = package_set(1_000);
+    let root = Path::new("/");
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("package_set", |b| {
+        b.iter(|| parse(black_box(&source), root).unwrap())
+    });
+    // A single broken binding makes every package go through recovery.
+    let broken = source.replacen("pname = \"p500\";", "pname = ;", 1);
+    group.bench_function("package_set_recovering", |b| {
+        b.iter(|| {
+            let parsed = parse_recovering(black_box(&broken), root);
+            assert_eq!(parsed.errors.len(), 1);
+            parsed
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsing);
+criterion_main!(benches);
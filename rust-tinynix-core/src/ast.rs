@@ -0,0 +1,534 @@
+use crate::span::Span;
+use crate::{NixBinaryOp, NixExpr, NixExprKind, NixStringPart, NixUnaryOp, NixValue};
+use indexmap::IndexMap;
+use std::borrow::Cow;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Index;
+use std::path::Path;
+
+/// A syntax tree stored in a handful of flat tables instead of one allocation per node.
+///
+/// Nodes refer to each other by [`ExprId`] and to their lists of children by [`Slice`],
+/// and names and literals borrow from the source text wherever they appear in it
+/// verbatim. This is what the parser produces and the evaluator consumes; the owned
+/// [`NixExpr`] is available through [`Ast::to_nix_expr`], e.g. for code generation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ast<'src> {
+    exprs: Vec<Expr<'src>>,
+    items: Vec<ExprId>,
+    bindings: Vec<Binding<'src>>,
+    parts: Vec<StringPart<'src>>,
+    root: Option<ExprId>,
+}
+
+/// The index of a node in an [`Ast`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u32);
+
+/// A run of consecutive entries in one of the tables of an [`Ast`].
+pub struct Slice<T> {
+    start: u32,
+    len: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Slice<T> {
+    pub fn len(self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+
+    fn range(self) -> std::ops::Range<usize> {
+        self.start as usize..(self.start + self.len) as usize
+    }
+}
+
+impl<T> Clone for Slice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Slice<T> {}
+
+impl<T> PartialEq for Slice<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.start, self.len) == (other.start, other.len)
+    }
+}
+
+impl<T> fmt::Debug for Slice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.start + self.len)
+    }
+}
+
+/// A node of an [`Ast`]. The variants mirror those of [`NixExprKind`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr<'src> {
+    pub kind: ExprKind<'src>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind<'src> {
+    Value(Literal<'src>),
+    InterpolatedString(Slice<StringPart<'src>>),
+    InterpolatedPath(Slice<StringPart<'src>>),
+    Ref(Cow<'src, str>),
+    Inherit(&'src str),
+    List(Slice<ExprId>),
+    AttrSet {
+        recursive: bool,
+        bindings: Slice<Binding<'src>>,
+    },
+    UnaryOp {
+        op: NixUnaryOp,
+        expr: ExprId,
+    },
+    BinaryOp {
+        op: NixBinaryOp,
+        left: ExprId,
+        right: ExprId,
+    },
+    SearchPath(&'src str),
+    LetIn {
+        bindings: Slice<Binding<'src>>,
+        body: ExprId,
+    },
+    With {
+        environment: ExprId,
+        body: ExprId,
+    },
+    Assert {
+        condition: ExprId,
+        body: ExprId,
+    },
+    Apply {
+        function: ExprId,
+        argument: ExprId,
+    },
+    Lambda {
+        param: &'src str,
+        body: ExprId,
+    },
+    Error,
+}
+
+/// A constant, like [`NixValue`] but borrowing from the source where it can.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal<'src> {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(Cow<'src, str>),
+    Path(Cow<'src, Path>),
+    Null,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringPart<'src> {
+    Literal(Cow<'src, str>),
+    Interpolation(ExprId),
+}
+
+/// An attribute of a set or a variable of a `let`, after dotted paths have been merged
+/// into nested sets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding<'src> {
+    pub name: Cow<'src, str>,
+    pub value: ExprId,
+}
+
+impl<'src> Ast<'src> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The outermost expression. Panics if the tree is still being built.
+    pub fn root(&self) -> ExprId {
+        self.root.expect("the root of the tree has been set")
+    }
+
+    pub fn set_root(&mut self, root: ExprId) {
+        self.root = Some(root);
+    }
+
+    pub fn push(&mut self, kind: ExprKind<'src>, span: Span) -> ExprId {
+        self.exprs.push(Expr { kind, span });
+        ExprId(index(self.exprs.len() - 1))
+    }
+
+    pub fn push_items(&mut self, items: impl IntoIterator<Item = ExprId>) -> Slice<ExprId> {
+        push_slice(&mut self.items, items)
+    }
+
+    pub fn push_bindings(
+        &mut self,
+        bindings: impl IntoIterator<Item = Binding<'src>>,
+    ) -> Slice<Binding<'src>> {
+        push_slice(&mut self.bindings, bindings)
+    }
+
+    pub fn push_parts(
+        &mut self,
+        parts: impl IntoIterator<Item = StringPart<'src>>,
+    ) -> Slice<StringPart<'src>> {
+        push_slice(&mut self.parts, parts)
+    }
+
+    pub fn items(&self, items: Slice<ExprId>) -> &[ExprId] {
+        &self.items[items.range()]
+    }
+
+    pub fn bindings(&self, bindings: Slice<Binding<'src>>) -> &[Binding<'src>] {
+        &self.bindings[bindings.range()]
+    }
+
+    pub fn parts(&self, parts: Slice<StringPart<'src>>) -> &[StringPart<'src>] {
+        &self.parts[parts.range()]
+    }
+
+    /// The expressions directly nested in `id`, in source order.
+    pub fn children(&self, id: ExprId) -> Vec<ExprId> {
+        match &self[id].kind {
+            ExprKind::List(items) => self.items(*items).to_vec(),
+            ExprKind::AttrSet { bindings, .. } => self
+                .bindings(*bindings)
+                .iter()
+                .map(|binding| binding.value)
+                .collect(),
+            ExprKind::LetIn { bindings, body } => self
+                .bindings(*bindings)
+                .iter()
+                .map(|binding| binding.value)
+                .chain([*body])
+                .collect(),
+            ExprKind::InterpolatedString(parts) | ExprKind::InterpolatedPath(parts) => self
+                .parts(*parts)
+                .iter()
+                .filter_map(|part| match part {
+                    StringPart::Interpolation(expr) => Some(*expr),
+                    StringPart::Literal(_) => None,
+                })
+                .collect(),
+            ExprKind::UnaryOp { expr, .. } | ExprKind::Lambda { body: expr, .. } => vec![*expr],
+            ExprKind::BinaryOp {
+                left: first,
+                right: second,
+                ..
+            }
+            | ExprKind::With {
+                environment: first,
+                body: second,
+            }
+            | ExprKind::Assert {
+                condition: first,
+                body: second,
+            }
+            | ExprKind::Apply {
+                function: first,
+                argument: second,
+            } => vec![*first, *second],
+            ExprKind::Value(_)
+            | ExprKind::Ref(_)
+            | ExprKind::Inherit(_)
+            | ExprKind::SearchPath(_)
+            | ExprKind::Error => Vec::new(),
+        }
+    }
+
+    /// Builds the owned tree of the root expression.
+    pub fn to_nix_expr(&self) -> NixExpr {
+        self.to_nix_expr_from(self.root())
+    }
+
+    /// Builds the owned tree of the expression `id`, without recursing on the native
+    /// stack.
+    pub fn to_nix_expr_from(&self, id: ExprId) -> NixExpr {
+        enum Task {
+            Visit(ExprId),
+            Build(ExprId, usize),
+        }
+        let mut tasks = vec![Task::Visit(id)];
+        let mut built: Vec<NixExpr> = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(id) => {
+                    let children = self.children(id);
+                    tasks.push(Task::Build(id, children.len()));
+                    tasks.extend(children.into_iter().rev().map(Task::Visit));
+                }
+                Task::Build(id, len) => {
+                    let children = built.split_off(built.len() - len);
+                    let expr = &self[id];
+                    built.push(NixExpr::new(
+                        self.owned_kind(&expr.kind, children),
+                        expr.span,
+                    ));
+                }
+            }
+        }
+        built.pop().expect("the root has been built")
+    }
+
+    /// The owned counterpart of `kind`, given its already built children in the order
+    /// of [`Ast::children`].
+    fn owned_kind(&self, kind: &ExprKind<'src>, children: Vec<NixExpr>) -> NixExprKind {
+        let mut children = children.into_iter();
+        let mut next = || Box::new(children.next().expect("one child per nested expression"));
+        match kind {
+            ExprKind::Value(literal) => NixExprKind::Value(match literal {
+                Literal::Int(i) => NixValue::Int(*i),
+                Literal::Float(f) => NixValue::Float(*f),
+                Literal::Bool(b) => NixValue::Bool(*b),
+                Literal::String(s) => NixValue::String(s.to_string()),
+                Literal::Path(path) => NixValue::Path(path.to_path_buf()),
+                Literal::Null => NixValue::Null,
+            }),
+            ExprKind::InterpolatedString(parts) | ExprKind::InterpolatedPath(parts) => {
+                let parts = self
+                    .parts(*parts)
+                    .iter()
+                    .map(|part| match part {
+                        StringPart::Literal(s) => NixStringPart::Literal(s.to_string()),
+                        StringPart::Interpolation(_) => NixStringPart::Interpolation(next()),
+                    })
+                    .collect();
+                match kind {
+                    ExprKind::InterpolatedPath(_) => NixExprKind::InterpolatedPath(parts),
+                    _ => NixExprKind::InterpolatedString(parts),
+                }
+            }
+            ExprKind::Ref(name) => NixExprKind::Ref(name.to_string()),
+            ExprKind::Inherit(name) => NixExprKind::Inherit(name.to_string()),
+            ExprKind::List(_) => NixExprKind::List(children.collect()),
+            ExprKind::AttrSet {
+                recursive,
+                bindings,
+            } => NixExprKind::AttrSet {
+                recursive: *recursive,
+                bindings: self.owned_bindings(*bindings, &mut children),
+            },
+            ExprKind::LetIn { bindings, .. } => NixExprKind::LetIn {
+                bindings: self.owned_bindings(*bindings, &mut children),
+                body: Box::new(children.next().expect("a let has a body")),
+            },
+            ExprKind::UnaryOp { op, .. } => NixExprKind::UnaryOp {
+                op: *op,
+                expr: next(),
+            },
+            ExprKind::BinaryOp { op, .. } => NixExprKind::BinaryOp {
+                op: *op,
+                left: next(),
+                right: next(),
+            },
+            ExprKind::SearchPath(lookup) => NixExprKind::SearchPath(lookup.to_string()),
+            ExprKind::With { .. } => NixExprKind::With {
+                environment: next(),
+                body: next(),
+            },
+            ExprKind::Assert { .. } => NixExprKind::Assert {
+                condition: next(),
+                body: next(),
+            },
+            ExprKind::Apply { .. } => NixExprKind::Apply {
+                function: next(),
+                argument: next(),
+            },
+            ExprKind::Lambda { param, .. } => NixExprKind::Lambda {
+                param: param.to_string(),
+                body: next(),
+            },
+            ExprKind::Error => NixExprKind::Error,
+        }
+    }
+
+    fn owned_bindings(
+        &self,
+        bindings: Slice<Binding<'src>>,
+        children: &mut impl Iterator<Item = NixExpr>,
+    ) -> IndexMap<String, NixExpr> {
+        self.bindings(bindings)
+            .iter()
+            .map(|binding| binding.name.to_string())
+            .zip(children)
+            .collect()
+    }
+
+    /// A tree with the same structure as `expr`, borrowing its names and literals.
+    pub fn from_nix_expr(expr: &'src NixExpr) -> Self {
+        enum Task<'a> {
+            Visit(&'a NixExpr),
+            Build(&'a NixExpr, usize),
+        }
+        let mut ast = Ast::new();
+        let mut tasks = vec![Task::Visit(expr)];
+        let mut built: Vec<ExprId> = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(expr) => {
+                    let children = expr.children();
+                    tasks.push(Task::Build(expr, children.len()));
+                    tasks.extend(children.into_iter().rev().map(Task::Visit));
+                }
+                Task::Build(expr, len) => {
+                    let children = built.split_off(built.len() - len);
+                    let kind = ast.borrowed_kind(&expr.kind, children);
+                    built.push(ast.push(kind, expr.span));
+                }
+            }
+        }
+        ast.set_root(built.pop().expect("the root has been built"));
+        ast
+    }
+
+    fn borrowed_kind(&mut self, kind: &'src NixExprKind, children: Vec<ExprId>) -> ExprKind<'src> {
+        let mut children = children.into_iter();
+        let mut next = || children.next().expect("one child per nested expression");
+        match kind {
+            NixExprKind::Value(value) => ExprKind::Value(match value {
+                NixValue::Int(i) => Literal::Int(*i),
+                NixValue::Float(f) => Literal::Float(*f),
+                NixValue::Bool(b) => Literal::Bool(*b),
+                NixValue::String(s) => Literal::String(Cow::Borrowed(s)),
+                NixValue::Path(path) => Literal::Path(Cow::Borrowed(path)),
+                NixValue::Null => Literal::Null,
+            }),
+            NixExprKind::InterpolatedString(parts) | NixExprKind::InterpolatedPath(parts) => {
+                let parts: Vec<_> = parts
+                    .iter()
+                    .map(|part| match part {
+                        NixStringPart::Literal(s) => StringPart::Literal(Cow::Borrowed(s)),
+                        NixStringPart::Interpolation(_) => StringPart::Interpolation(next()),
+                    })
+                    .collect();
+                let parts = self.push_parts(parts);
+                match kind {
+                    NixExprKind::InterpolatedPath(_) => ExprKind::InterpolatedPath(parts),
+                    _ => ExprKind::InterpolatedString(parts),
+                }
+            }
+            NixExprKind::Ref(name) => ExprKind::Ref(Cow::Borrowed(name)),
+            NixExprKind::Inherit(name) => ExprKind::Inherit(name),
+            NixExprKind::List(_) => ExprKind::List(self.push_items(children)),
+            NixExprKind::AttrSet {
+                recursive,
+                bindings,
+            } => ExprKind::AttrSet {
+                recursive: *recursive,
+                bindings: self.borrowed_bindings(bindings, &mut children),
+            },
+            NixExprKind::LetIn { bindings, .. } => ExprKind::LetIn {
+                bindings: self.borrowed_bindings(bindings, &mut children),
+                body: children.next().expect("a let has a body"),
+            },
+            NixExprKind::UnaryOp { op, .. } => ExprKind::UnaryOp {
+                op: *op,
+                expr: next(),
+            },
+            NixExprKind::BinaryOp { op, .. } => ExprKind::BinaryOp {
+                op: *op,
+                left: next(),
+                right: next(),
+            },
+            NixExprKind::SearchPath(lookup) => ExprKind::SearchPath(lookup),
+            NixExprKind::With { .. } => ExprKind::With {
+                environment: next(),
+                body: next(),
+            },
+            NixExprKind::Assert { .. } => ExprKind::Assert {
+                condition: next(),
+                body: next(),
+            },
+            NixExprKind::Apply { .. } => ExprKind::Apply {
+                function: next(),
+                argument: next(),
+            },
+            NixExprKind::Lambda { param, .. } => ExprKind::Lambda {
+                param,
+                body: next(),
+            },
+            NixExprKind::Error => ExprKind::Error,
+        }
+    }
+
+    fn borrowed_bindings(
+        &mut self,
+        bindings: &'src IndexMap<String, NixExpr>,
+        children: &mut impl Iterator<Item = ExprId>,
+    ) -> Slice<Binding<'src>> {
+        let bindings: Vec<_> = bindings
+            .keys()
+            .zip(children)
+            .map(|(name, value)| Binding {
+                name: Cow::Borrowed(name),
+                value,
+            })
+            .collect();
+        self.push_bindings(bindings)
+    }
+}
+
+impl<'src> Index<ExprId> for Ast<'src> {
+    type Output = Expr<'src>;
+
+    fn index(&self, id: ExprId) -> &Self::Output {
+        &self.exprs[id.0 as usize]
+    }
+}
+
+fn push_slice<T>(table: &mut Vec<T>, entries: impl IntoIterator<Item = T>) -> Slice<T> {
+    let start = table.len();
+    table.extend(entries);
+    Slice {
+        start: index(start),
+        len: index(table.len() - start),
+        marker: PhantomData,
+    }
+}
+
+fn index(index: usize) -> u32 {
+    u32::try_from(index).expect("trees have fewer than 2^32 nodes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{EvalContext, Scope, nix_eval_ast};
+    use crate::parser;
+    use crate::span::FileId;
+
+    #[test]
+    fn owned_trees_convert_back_unchanged() {
+        let source = r#"
+            let
+              name = "world";
+              set = rec { a = 1; b = a + 1; inherit name; nested.c = -b; };
+              f = x: y: x // y;
+            in {
+              greeting = "hello ${name}\n";
+              list = [ set.a (set.b - 1) set.nested.c ./file.nix ];
+              merged = f set { a = 3; };
+              quotient = builtins.div 4 2 - 2 + 1 - 1;
+              negated = [ (!true) false null ];
+              removed = builtins.removeAttrs set [ "b" ];
+            }
+        "#;
+        let ast = parser::parse_ast(source, Path::new("/"), FileId::UNKNOWN).unwrap();
+        let expr = ast.to_nix_expr();
+        let converted = Ast::from_nix_expr(&expr);
+        assert_eq!(converted.to_nix_expr(), expr);
+
+        let [value, converted_value] = [&ast, &converted].map(|ast| {
+            nix_eval_ast(ast, &Scope::new(), &EvalContext::default())
+                .unwrap()
+                .to_string()
+        });
+        assert_eq!(value, converted_value);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::eval::env::Environment;
+use crate::eval::{
+    CycleMember, EvalContext, EvaluationError, Program, Scope, TraceFrameKind, eval_lowered,
+};
+use crate::parser;
+use crate::symbol::SymbolTable;
+use crate::value::{AttrSet, PrimOp, Value};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+struct PrimOpInfo {
+    name: &'static str,
+    arity: usize,
+    /// Whether the builtin is also reachable without the `builtins.` prefix.
+    global: bool,
+    /// The arguments that are forced deeply rather than to weak head normal form.
+    deep_args: &'static [usize],
+}
+
+const PRIMOPS: &[PrimOpInfo] = &[
+    PrimOpInfo {
+        name: "import",
+        arity: 1,
+        global: true,
+        deep_args: &[],
+    },
+    PrimOpInfo {
+        name: "scopedImport",
+        arity: 2,
+        global: true,
+        deep_args: &[],
+    },
+    PrimOpInfo {
+        name: "abort",
+        arity: 1,
+        global: true,
+        deep_args: &[],
+    },
+    PrimOpInfo {
+        name: "throw",
+        arity: 1,
+        global: true,
+        deep_args: &[],
+    },
+    PrimOpInfo {
+        name: "div",
+        arity: 2,
+        global: false,
+        deep_args: &[],
+    },
+    PrimOpInfo {
+        name: "trace",
+        arity: 2,
+        global: false,
+        deep_args: &[],
+    },
+    PrimOpInfo {
+        name: "removeAttrs",
+        arity: 2,
+        global: true,
+        deep_args: &[1],
+    },
+];
+
+fn primop(info: &PrimOpInfo) -> Value {
+    Value::Primop(Rc::new(PrimOp {
+        name: info.name,
+        arity: info.arity,
+        args: Vec::new(),
+    }))
+}
+
+/// The global builtin scope, which variables fall back to when they are not bound in
+/// the lexical scope, with its names interned in `symbols`.
+pub(crate) fn globals(symbols: &mut SymbolTable) -> AttrSet {
+    let mut globals: AttrSet = PRIMOPS
+        .iter()
+        .filter(|info| info.global)
+        .map(|info| (symbols.intern(info.name), primop(info)))
+        .collect();
+    let builtins = PRIMOPS
+        .iter()
+        .map(|info| (symbols.intern(info.name), primop(info)))
+        .collect();
+    globals.insert(symbols.intern("builtins"), Value::Attrs(builtins));
+    globals
+}
+
+/// Every name in the global builtin scope.
+pub fn global_names<'a>() -> impl Iterator<Item = &'a str> {
+    std::iter::once("builtins").chain(
+        PRIMOPS
+            .iter()
+            .filter(|info| info.global)
+            .map(|info| info.name),
+    )
+}
+
+/// Which arguments of the builtin `name` have to be forced deeply before calling it.
+pub(crate) fn deep_args(name: &str) -> &'static [usize] {
+    PRIMOPS
+        .iter()
+        .find(|info| info.name == name)
+        .map_or(&[], |info| info.deep_args)
+}
+
+/// Calls the builtin `name` with as many forced arguments as it takes.
+pub(crate) fn call(
+    name: &str,
+    mut args: Vec<Value>,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    match name {
+        "import" => import_file(&import_target(&args[0])?, ctx),
+        "scopedImport" => scoped_import(&args[0], &args[1], ctx),
+        "abort" => Err(EvaluationError::Abort(string_argument(&args[0])?)),
+        "throw" => Err(EvaluationError::Throw(string_argument(&args[0])?)),
+        "div" => div(&args[0], &args[1]),
+        "trace" => {
+            ctx.trace_sink.emit(&args[0]);
+            Ok(args.swap_remove(1))
+        }
+        "removeAttrs" => remove_attrs(&args[0], &args[1], ctx),
+        _ => unreachable!("every entry of PRIMOPS is dispatched above"),
+    }
+}
+
+fn import_target(target: &Value) -> Result<PathBuf, EvaluationError> {
+    match target {
+        Value::Path(path) => Ok(path.to_path_buf()),
+        Value::String(s) if Path::new(s.as_str()).is_absolute() => Ok(PathBuf::from(s.as_str())),
+        _ => Err(EvaluationError::type_mismatch("a path", target)),
+    }
+}
+
+fn string_argument(argument: &Value) -> Result<String, EvaluationError> {
+    match argument {
+        Value::String(s) => Ok(s.to_string()),
+        _ => Err(EvaluationError::type_mismatch("a string", argument)),
+    }
+}
+
+fn div(dividend: &Value, divisor: &Value) -> Result<Value, EvaluationError> {
+    let number = |value: &Value| match value {
+        Value::Int(_) | Value::Float(_) => Ok(()),
+        _ => Err(EvaluationError::type_mismatch("a number", value)),
+    };
+    number(dividend)?;
+    number(divisor)?;
+    match (dividend, divisor) {
+        (_, Value::Int(0)) => Err(EvaluationError::DivisionByZero),
+        (_, Value::Float(0.0)) => Err(EvaluationError::DivisionByZero),
+        (Value::Int(a), Value::Int(b)) => {
+            a.checked_div(*b)
+                .map(Value::Int)
+                .ok_or(EvaluationError::Overflow {
+                    operation: "division",
+                })
+        }
+        (Value::Int(a), Value::Float(b)) => Ok(Value::Float(*a as f64 / b)),
+        (Value::Float(a), Value::Int(b)) => Ok(Value::Float(a / *b as f64)),
+        (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a / b)),
+        _ => unreachable!("both operands are numbers"),
+    }
+}
+
+fn remove_attrs(attrs: &Value, names: &Value, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    let Value::Attrs(attrs) = attrs else {
+        return Err(EvaluationError::type_mismatch("a set", attrs));
+    };
+    let Value::List(names) = names else {
+        return Err(EvaluationError::type_mismatch("a list", names));
+    };
+    let mut result = attrs.clone();
+    for name in names.iter() {
+        let name = name.forced().expect("the names have been forced deeply");
+        // Names that were never interned can't be attributes of the set.
+        if let Some(name) = ctx.symbols.borrow().get(string_argument(name)?.as_str()) {
+            result.remove(&name);
+        }
+    }
+    Ok(Value::Attrs(result))
+}
+
+fn scoped_import(
+    attrs: &Value,
+    target: &Value,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let Value::Attrs(bindings) = attrs else {
+        return Err(EvaluationError::type_mismatch("a set", attrs));
+    };
+    let mut scope = ctx.import_scope.clone();
+    scope.extend(
+        bindings
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone())),
+    );
+    let canonical = canonical_import_path(&import_target(target)?)?;
+    evaluate_file(canonical, scope, ctx)
+}
+
+/// Parses and evaluates a file in `ctx.import_scope`, going through the import cache
+/// of `ctx`. Directories are resolved to their `default.nix`. The value is only forced
+/// as far as needed to know its type.
+pub fn import_file(path: &Path, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    let canonical = canonical_import_path(path)?;
+    if let Some(cached) = ctx.import_cache.borrow().get(&canonical) {
+        return Ok(cached.clone());
+    }
+    let value = evaluate_file(canonical.clone(), ctx.import_scope.clone(), ctx)?;
+    ctx.import_cache
+        .borrow_mut()
+        .insert(canonical, value.clone());
+    Ok(value)
+}
+
+fn canonical_import_path(path: &Path) -> Result<PathBuf, EvaluationError> {
+    let path = if path.is_dir() {
+        path.join("default.nix")
+    } else {
+        path.to_path_buf()
+    };
+    path.canonicalize()
+        .map_err(|error| EvaluationError::Io { path, error })
+}
+
+/// Parses and evaluates a file in `scope`, without consulting the import cache. Files
+/// are only parsed and lowered once per set of names in scope, though.
+fn evaluate_file(
+    canonical: PathBuf,
+    scope: Scope,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    if let Some(pos) = ctx
+        .import_stack
+        .borrow()
+        .iter()
+        .position(|p| *p == canonical)
+    {
+        let mut chain = ctx.import_stack.borrow()[pos..].to_vec();
+        chain.push(canonical);
+        return Err(EvaluationError::InfiniteRecursion {
+            cycle: chain.into_iter().map(CycleMember::File).collect(),
+        });
+    }
+
+    let key = (canonical.clone(), scope.keys().cloned().collect());
+    let cached = ctx.programs.borrow().get(&key).cloned();
+    let program = match cached {
+        Some(program) => program,
+        None => {
+            let program = Rc::new(prepare_file(&canonical, &scope, ctx)?);
+            ctx.programs.borrow_mut().insert(key, program.clone());
+            program
+        }
+    };
+
+    ctx.import_stack.borrow_mut().push(canonical.clone());
+    let result = eval_lowered(&program, Environment::root(scope), false, ctx);
+    ctx.import_stack.borrow_mut().pop();
+    let span = program.expr.span;
+    result.map_err(|error| error.in_frame(TraceFrameKind::Import(canonical), span))
+}
+
+/// Reads, parses and lowers a file to be evaluated in a scope with the names of `scope`.
+fn prepare_file(
+    canonical: &Path,
+    scope: &Scope,
+    ctx: &EvalContext,
+) -> Result<Program, EvaluationError> {
+    let content = std::fs::read_to_string(canonical).map_err(|error| EvaluationError::Io {
+        path: canonical.to_path_buf(),
+        error,
+    })?;
+    let root = canonical.parent().unwrap_or(Path::new("/"));
+    let file = ctx
+        .source_map
+        .borrow_mut()
+        .add(canonical.display().to_string(), content.as_str());
+    let ast = parser::parse_ast(&content, root, file).map_err(|error| {
+        EvaluationError::ImportParseFailed {
+            path: canonical.to_path_buf(),
+            file,
+            error,
+        }
+    })?;
+    let span = ast[ast.root()].span;
+    Program::lower(&ast, scope, ctx)
+        .map_err(|error| error.in_frame(TraceFrameKind::Import(canonical.to_path_buf()), span))
+}
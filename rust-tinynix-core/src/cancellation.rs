@@ -0,0 +1,25 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A flag that lets another thread stop an evaluation. Clones share the same flag.
+///
+/// The evaluator checks it before evaluating each expression, which includes every
+/// function call and import, and fails with [`crate::eval::EvaluationError::Cancelled`]
+/// once it is set. The flag can't be cleared again; see
+/// [`crate::evaluator::Evaluator::set_cancellation`] for evaluating further.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
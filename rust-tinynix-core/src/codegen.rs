@@ -1,113 +1,206 @@
-use crate::{NixBinaryOp, NixExpr, NixStringPart, NixUnaryOp, NixValue};
+use crate::span::{Location, Span};
+use crate::{NixBinaryOp, NixExpr, NixExprKind, NixStringPart, NixUnaryOp, NixValue};
 use proc_macro2::TokenStream;
 use quote::quote;
 
+/// Generates the tokens constructing `ast`, walking it with an explicit stack so deep
+/// expressions don't overflow the native one.
 pub fn generate_token_stream(ast: &NixExpr) -> TokenStream {
-    match ast {
-        NixExpr::Value(value) => match value {
+    enum Task<'a> {
+        Visit(&'a NixExpr),
+        Build(&'a NixExpr, usize),
+    }
+    let mut tasks = vec![Task::Visit(ast)];
+    let mut generated: Vec<TokenStream> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(expr) => {
+                let children = expr.children();
+                tasks.push(Task::Build(expr, children.len()));
+                tasks.extend(children.into_iter().rev().map(Task::Visit));
+            }
+            Task::Build(expr, len) => {
+                let children = generated.split_off(generated.len() - len);
+                let kind_ast = generate_kind_token_stream(&expr.kind, children);
+                let span_ast = generate_span_token_stream(&expr.span);
+                generated.push(quote! {
+                    ::rust_tinynix::NixExpr {
+                        kind: #kind_ast,
+                        span: #span_ast,
+                    }
+                });
+            }
+        }
+    }
+    generated.pop().expect("the root has been generated")
+}
+
+fn generate_location_token_stream(location: &Location) -> TokenStream {
+    let Location {
+        offset,
+        line,
+        column,
+    } = location;
+    quote! { ::rust_tinynix::Location::new(#offset, #line, #column) }
+}
+
+fn generate_span_token_stream(span: &Span) -> TokenStream {
+    let file = span.file.0;
+    let start_ast = generate_location_token_stream(&span.start);
+    let end_ast = generate_location_token_stream(&span.end);
+    quote! { ::rust_tinynix::Span::new(::rust_tinynix::FileId(#file), #start_ast, #end_ast) }
+}
+
+/// Generates the tokens constructing `kind`, given those of its nested expressions in
+/// the order of [`NixExpr::children`].
+fn generate_kind_token_stream(kind: &NixExprKind, children: Vec<TokenStream>) -> TokenStream {
+    let mut children = children.into_iter();
+    let mut next = || {
+        children
+            .next()
+            .expect("one token stream per nested expression")
+    };
+    match kind {
+        NixExprKind::Value(value) => match value {
             NixValue::Int(i) => {
-                quote! { ::rust_tinynix::NixExpr::Value(::rust_tinynix::NixValue::Int(#i)) }
+                quote! { ::rust_tinynix::NixExprKind::Value(::rust_tinynix::NixValue::Int(#i)) }
             }
             NixValue::Float(f) => {
-                quote! { ::rust_tinynix::NixExpr::Value(::rust_tinynix::NixValue::Float(#f)) }
+                quote! { ::rust_tinynix::NixExprKind::Value(::rust_tinynix::NixValue::Float(#f)) }
             }
             NixValue::Bool(b) => {
-                quote! { ::rust_tinynix::NixExpr::Value(::rust_tinynix::NixValue::Bool(#b)) }
+                quote! { ::rust_tinynix::NixExprKind::Value(::rust_tinynix::NixValue::Bool(#b)) }
             }
             NixValue::String(s) => {
-                quote! { ::rust_tinynix::NixExpr::Value(::rust_tinynix::NixValue::String(#s.to_string())) }
+                quote! { ::rust_tinynix::NixExprKind::Value(::rust_tinynix::NixValue::String(#s.to_string())) }
             }
             NixValue::Null => {
-                quote! { ::rust_tinynix::NixExpr::Value(::rust_tinynix::NixValue::Null) }
+                quote! { ::rust_tinynix::NixExprKind::Value(::rust_tinynix::NixValue::Null) }
             }
             NixValue::Path(p) => {
                 let path_str = p.to_str().expect("Path is not valid UTF-8");
-                quote! { ::rust_tinynix::NixExpr::Value(::rust_tinynix::NixValue::Path(::std::path::PathBuf::from(#path_str))) }
+                quote! { ::rust_tinynix::NixExprKind::Value(::rust_tinynix::NixValue::Path(::std::path::PathBuf::from(#path_str))) }
             }
         },
-        NixExpr::UnaryOp { op, expr } => {
-            let expr_ast = generate_token_stream(expr);
+        NixExprKind::UnaryOp { op, .. } => {
+            let expr_ast = next();
             let op_token = match op {
                 NixUnaryOp::Neg => quote! { ::rust_tinynix::NixUnaryOp::Neg },
                 NixUnaryOp::Not => quote! { ::rust_tinynix::NixUnaryOp::Not },
             };
             quote! {
-                ::rust_tinynix::NixExpr::UnaryOp {
+                ::rust_tinynix::NixExprKind::UnaryOp {
                     op: #op_token,
                     expr: Box::new(#expr_ast),
                 }
             }
         }
-        NixExpr::BinaryOp { op, left, right } => {
-            let left_ast = generate_token_stream(left);
-            let right_ast = generate_token_stream(right);
+        NixExprKind::BinaryOp { op, .. } => {
+            let left_ast = next();
+            let right_ast = next();
             let op_token = match op {
                 NixBinaryOp::Add => quote! { ::rust_tinynix::NixBinaryOp::Add },
                 NixBinaryOp::Sub => quote! { ::rust_tinynix::NixBinaryOp::Sub },
+                NixBinaryOp::Update => quote! { ::rust_tinynix::NixBinaryOp::Update },
             };
             quote! {
-                ::rust_tinynix::NixExpr::BinaryOp {
+                ::rust_tinynix::NixExprKind::BinaryOp {
                     op: #op_token,
                     left: Box::new(#left_ast),
                     right: Box::new(#right_ast),
                 }
             }
         }
-        NixExpr::InterpolatedString(parts) => {
+        NixExprKind::InterpolatedString(parts) | NixExprKind::InterpolatedPath(parts) => {
             let quoted_parts = parts.iter().map(|part| match part {
                 NixStringPart::Literal(s) => {
                     quote! { ::rust_tinynix::NixStringPart::Literal(#s.to_string()) }
                 }
-                NixStringPart::Interpolation(ast) => {
-                    let quoted_ast = generate_token_stream(ast);
+                NixStringPart::Interpolation(_) => {
+                    let quoted_ast = next();
                     quote! { ::rust_tinynix::NixStringPart::Interpolation(Box::new(#quoted_ast)) }
                 }
             });
-            quote! { ::rust_tinynix::NixExpr::InterpolatedString(vec![#(#quoted_parts),*]) }
+            let variant = match kind {
+                NixExprKind::InterpolatedPath(_) => quote! { InterpolatedPath },
+                _ => quote! { InterpolatedString },
+            };
+            quote! { ::rust_tinynix::NixExprKind::#variant(vec![#(#quoted_parts),*]) }
+        }
+        NixExprKind::SearchPath(s) => {
+            quote! { ::rust_tinynix::NixExprKind::SearchPath(#s.to_string()) }
         }
-        NixExpr::SearchPath(s) => {
-            quote! { ::rust_tinynix::NixExpr::SearchPath(#s.to_string()) }
+        NixExprKind::Ref(s) => quote! { ::rust_tinynix::NixExprKind::Ref(#s.to_string()) },
+        NixExprKind::Inherit(s) => {
+            quote! { ::rust_tinynix::NixExprKind::Inherit(#s.to_string()) }
         }
-        NixExpr::Ref(s) => quote! { ::rust_tinynix::NixExpr::Ref(#s.to_string()) },
-        NixExpr::List(items) => {
-            let quoted_items = items.iter().map(generate_token_stream);
-            quote! { ::rust_tinynix::NixExpr::List(vec![#(#quoted_items),*]) }
+        NixExprKind::List(_) => {
+            let quoted_items = children;
+            quote! { ::rust_tinynix::NixExprKind::List(vec![#(#quoted_items),*]) }
         }
-        NixExpr::With { environment, body } => {
-            let env_ast = generate_token_stream(environment);
-            let body_ast = generate_token_stream(body);
+        NixExprKind::With { .. } => {
+            let env_ast = next();
+            let body_ast = next();
             quote! {
-                ::rust_tinynix::NixExpr::With {
+                ::rust_tinynix::NixExprKind::With {
                     environment: Box::new(#env_ast),
                     body: Box::new(#body_ast),
                 }
             }
         }
-        NixExpr::LetIn { bindings, body } => {
-            let quoted_bindings = bindings.iter().map(|(k, v)| {
+        NixExprKind::Assert { .. } => {
+            let condition_ast = next();
+            let body_ast = next();
+            quote! {
+                ::rust_tinynix::NixExprKind::Assert {
+                    condition: Box::new(#condition_ast),
+                    body: Box::new(#body_ast),
+                }
+            }
+        }
+        NixExprKind::Apply { .. } => {
+            let function_ast = next();
+            let argument_ast = next();
+            quote! {
+                ::rust_tinynix::NixExprKind::Apply {
+                    function: Box::new(#function_ast),
+                    argument: Box::new(#argument_ast),
+                }
+            }
+        }
+        NixExprKind::Lambda { param, .. } => {
+            let body_ast = next();
+            quote! {
+                ::rust_tinynix::NixExprKind::Lambda {
+                    param: #param.to_string(),
+                    body: Box::new(#body_ast),
+                }
+            }
+        }
+        NixExprKind::Error => quote! { ::rust_tinynix::NixExprKind::Error },
+        NixExprKind::LetIn { bindings, .. } => {
+            let body_ast = children.next_back().expect("a let has a body");
+            let quoted_bindings = bindings.keys().zip(children).map(|(k, val_ast)| {
                 let key_str = k;
-                let val_ast = generate_token_stream(v);
                 quote! { (#key_str.to_string(), #val_ast) }
             });
-            let body_ast = generate_token_stream(body);
             quote! {
-                ::rust_tinynix::NixExpr::LetIn {
+                ::rust_tinynix::NixExprKind::LetIn {
                     bindings: vec![#(#quoted_bindings),*].into_iter().collect(),
                     body: Box::new(#body_ast),
                 }
             }
         }
-        NixExpr::AttrSet {
+        NixExprKind::AttrSet {
             recursive,
             bindings,
         } => {
-            let quoted_bindings = bindings.iter().map(|(k, v)| {
+            let quoted_bindings = bindings.keys().zip(children).map(|(k, val_ast)| {
                 let key_str = k;
-                let val_ast = generate_token_stream(v);
                 quote! { (#key_str.to_string(), #val_ast) }
             });
             quote! {
-                ::rust_tinynix::NixExpr::AttrSet {
+                ::rust_tinynix::NixExprKind::AttrSet {
                     recursive: #recursive,
                     bindings: vec![#(#quoted_bindings),*].into_iter().collect()
                 }
@@ -0,0 +1,434 @@
+use crate::span::{SourceMap, Span};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        }
+    }
+}
+
+/// A source region annotated with a message. Primary labels mark the offending code
+/// (`^^^`), secondary ones provide context (`---`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+/// A user-facing report of a parse or evaluation problem, rendered rustc-style.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+/// Whether [`Diagnostic::render`] should emit ANSI escape codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    #[default]
+    Plain,
+    Ansi,
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+
+struct Painter(ColorMode);
+
+impl Painter {
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.0 {
+            ColorMode::Plain => text.to_string(),
+            ColorMode::Ansi => format!("{}{}{}", color, text, RESET),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            help: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, message)
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn with_secondary_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    /// The span of the first primary label, which is where the diagnostic "points".
+    pub fn primary_span(&self) -> Option<Span> {
+        self.labels
+            .iter()
+            .find(|label| label.primary)
+            .or(self.labels.first())
+            .map(|label| label.span)
+    }
+
+    /// Renders the diagnostic with source snippets for every label whose file is
+    /// known to `sources`.
+    pub fn render(&self, sources: &SourceMap, color: ColorMode) -> String {
+        let painter = Painter(color);
+        let mut out = String::new();
+
+        let header = painter.paint(self.severity.color(), self.severity.as_str());
+        let _ = writeln!(
+            out,
+            "{}{}",
+            header,
+            painter.paint(BOLD, &format!(": {}", self.message))
+        );
+
+        // Primary labels first, the rest in the order they were added, which matters
+        // for evaluation traces.
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|label| !label.primary);
+
+        let gutter_width = labels
+            .iter()
+            .map(|label| label.span.start.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(gutter_width);
+        let pipe = painter.paint(BLUE, "|");
+
+        for label in &labels {
+            let span = label.span;
+            let file = sources.get(span.file);
+            let arrow = painter.paint(BLUE, if label.primary { "-->" } else { ":::" });
+            let _ = writeln!(out, "{}{} {}", gutter, arrow, sources.locate(span));
+            let Some(file) = file else {
+                if !label.message.is_empty() {
+                    let _ = writeln!(out, "{} {} {}", gutter, pipe, label.message);
+                }
+                continue;
+            };
+            let Some(range) = file.lines.line_range(&file.source, span.start.line) else {
+                continue;
+            };
+            let line_text = file.source[range.clone()].trim_end_matches('\r');
+
+            let underline_start = span.start.column.saturating_sub(1);
+            let line_len = line_text.chars().count();
+            let underline_end = if span.end.line == span.start.line {
+                span.end.column.saturating_sub(1)
+            } else {
+                line_len
+            };
+            let underline_len = underline_end.saturating_sub(underline_start).max(1);
+            let marker = if label.primary { "^" } else { "-" };
+            let marker_color = if label.primary {
+                self.severity.color()
+            } else {
+                BLUE
+            };
+            let underline = painter.paint(
+                marker_color,
+                &format!(
+                    "{}{}",
+                    marker.repeat(underline_len),
+                    label_suffix(&label.message)
+                ),
+            );
+
+            let line_number = painter.paint(
+                BLUE,
+                &format!("{:>width$}", span.start.line, width = gutter_width),
+            );
+            let _ = writeln!(out, "{} {}", gutter, pipe);
+            let _ = writeln!(out, "{} {} {}", line_number, pipe, line_text);
+            // Keep tabs so the underline lines up with the source line above it.
+            let padding: String = line_text
+                .chars()
+                .take(underline_start)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let _ = writeln!(out, "{} {} {}{}", gutter, pipe, padding, underline);
+        }
+
+        if !self.notes.is_empty() || !self.help.is_empty() {
+            if !labels.is_empty() {
+                let _ = writeln!(out, "{} {}", gutter, pipe);
+            }
+            let eq = painter.paint(BLUE, "=");
+            for note in &self.notes {
+                let _ = writeln!(
+                    out,
+                    "{} {} {}: {}",
+                    gutter,
+                    eq,
+                    painter.paint(BOLD, "note"),
+                    note
+                );
+            }
+            for help in &self.help {
+                let _ = writeln!(
+                    out,
+                    "{} {} {}: {}",
+                    gutter,
+                    eq,
+                    painter.paint(CYAN, "help"),
+                    help
+                );
+            }
+        }
+        out
+    }
+
+    /// A machine-readable rendering of the diagnostic, as a single JSON object.
+    pub fn to_json(&self, sources: &SourceMap) -> String {
+        let labels = self
+            .labels
+            .iter()
+            .map(|label| {
+                let span = label.span;
+                let file = sources
+                    .get(span.file)
+                    .map_or("null".to_string(), |file| json_string(&file.name));
+                format!(
+                    "{{\"file\":{},\"start\":{{\"offset\":{},\"line\":{},\"column\":{}}},\"end\":{{\"offset\":{},\"line\":{},\"column\":{}}},\"message\":{},\"primary\":{}}}",
+                    file,
+                    span.start.offset,
+                    span.start.line,
+                    span.start.column,
+                    span.end.offset,
+                    span.end.line,
+                    span.end.column,
+                    json_string(&label.message),
+                    label.primary
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"severity\":{},\"message\":{},\"labels\":[{}],\"notes\":[{}],\"help\":[{}]}}",
+            json_string(self.severity.as_str()),
+            json_string(&self.message),
+            labels.join(","),
+            json_string_list(&self.notes),
+            json_string_list(&self.help)
+        )
+    }
+}
+
+/// Up to three candidates that are within a small edit distance of `name`, closest first.
+pub fn suggest_similar<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Vec<String> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut scored: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .filter_map(|candidate| {
+            let distance = if candidate.eq_ignore_ascii_case(name) {
+                0
+            } else {
+                edit_distance(name, candidate)
+            };
+            (distance <= max_distance).then_some((distance, candidate))
+        })
+        .collect();
+    scored.sort();
+    scored.dedup_by_key(|(_, candidate)| *candidate);
+    scored
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+/// Formats the output of [`suggest_similar`] as a help message.
+pub fn did_you_mean(suggestions: &[String]) -> Option<String> {
+    match suggestions {
+        [] => None,
+        [only] => Some(format!("did you mean '{}'?", only)),
+        many => {
+            let quoted = many.iter().map(|s| format!("'{}'", s)).collect::<Vec<_>>();
+            Some(format!("did you mean one of {}?", quoted.join(", ")))
+        }
+    }
+}
+
+/// Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+fn label_suffix(message: &str) -> String {
+    if message.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", message)
+    }
+}
+
+fn json_string_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| json_string(item))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::{FileId, Location};
+
+    fn span(file: FileId, start: (usize, usize, usize), end: (usize, usize, usize)) -> Span {
+        Span::new(
+            file,
+            Location::new(start.0, start.1, start.2),
+            Location::new(end.0, end.1, end.2),
+        )
+    }
+
+    #[test]
+    fn labels_are_rendered_under_their_source() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("x.nix", "let\n  a = 1 + \"s\";\nin a\n");
+        let diagnostic = Diagnostic::error("cannot add a string to an integer")
+            .with_label(span(file, (10, 2, 7), (17, 2, 14)), "in this sum")
+            .with_secondary_label(span(file, (22, 3, 4), (23, 3, 5)), "used here")
+            .with_note("only numbers can be added")
+            .with_help("use string interpolation");
+        assert_eq!(
+            diagnostic.render(&sources, ColorMode::Plain),
+            "\
+error: cannot add a string to an integer
+ --> x.nix:2:7
+  |
+2 |   a = 1 + \"s\";
+  |       ^^^^^^^ in this sum
+ ::: x.nix:3:4
+  |
+3 | in a
+  |    - used here
+  |
+  = note: only numbers can be added
+  = help: use string interpolation
+"
+        );
+    }
+
+    #[test]
+    fn labels_in_unknown_files_are_rendered_without_source() {
+        let diagnostic =
+            Diagnostic::warning("odd").with_label(span(FileId(7), (0, 1, 1), (1, 1, 2)), "here");
+        assert_eq!(
+            diagnostic.render(&SourceMap::new(), ColorMode::Plain),
+            "warning: odd\n --> <unknown>:1:1\n  | here\n"
+        );
+    }
+
+    #[test]
+    fn json_has_every_field_escaped() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("dir/\"q\".nix", "x\n");
+        let diagnostic = Diagnostic::error("bad \"x\"\n\tnext \\ \u{1}")
+            .with_label(span(file, (0, 1, 1), (1, 1, 2)), "")
+            .with_secondary_label(span(FileId(9), (0, 1, 1), (0, 1, 1)), "elsewhere")
+            .with_note("n");
+        assert_eq!(
+            diagnostic.to_json(&sources),
+            concat!(
+                r#"{"severity":"error","message":"bad \"x\"\n\tnext \\ \u0001","#,
+                r#""labels":[{"file":"dir/\"q\".nix","start":{"offset":0,"line":1,"column":1},"#,
+                r#""end":{"offset":1,"line":1,"column":2},"message":"","primary":true},"#,
+                r#"{"file":null,"start":{"offset":0,"line":1,"column":1},"#,
+                r#""end":{"offset":0,"line":1,"column":1},"message":"elsewhere","primary":false}],"#,
+                r#""notes":["n"],"help":[]}"#
+            )
+        );
+    }
+}
@@ -1,175 +1,755 @@
-use crate::{NixBinaryOp, NixExpr, NixStringPart, NixUnaryOp, NixValue};
+use crate::ast::Ast;
+use crate::cancellation::CancellationToken;
+use crate::diagnostics::{Diagnostic, did_you_mean, suggest_similar};
+use crate::limits::{Limits, ResourceLimit};
+use crate::parser::ParseError;
+use crate::search_path::{SearchPath, SearchPathEntry};
+use crate::span::{FileId, SourceMap, Span};
+use crate::symbol::{Symbol, SymbolTable};
+use crate::value::{AttrSet, NixString, Value};
+use crate::{NixBinaryOp, NixExpr, NixUnaryOp, builtins};
+use env::{Env, Environment};
 use indexmap::IndexMap;
+use ir::{Address, Binder};
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
+
+pub(crate) mod bytecode;
+pub(crate) mod cycles;
+pub(crate) mod env;
+pub(crate) mod ir;
+pub(crate) mod machine;
+pub(crate) mod vm;
 
 // Scope now uses owned Strings for keys to allow for dynamic extension.
-pub type Scope = IndexMap<String, NixExpr>;
+pub type Scope = IndexMap<String, Value>;
+
+/// The canonical path of a file and the names in scope where it is lowered.
+pub(crate) type ProgramKey = (PathBuf, Vec<String>);
 
-#[derive(Debug, PartialEq)]
+/// An error raised during evaluation.
+///
+/// Errors leave [`nix_eval_with`] wrapped in [`EvaluationError::Traced`], which records
+/// the span of the failing expression and the chain of constructs it was evaluated in;
+/// see [`EvaluationError::span`] and [`EvaluationError::trace`].
+#[derive(Debug)]
 pub enum EvaluationError {
-    UndefinedVariable(String),
-    TypeMismatch(String),
+    UndefinedVariable {
+        name: String,
+        /// Similarly named variables that are in scope.
+        suggestions: Vec<String>,
+    },
+    MissingAttribute {
+        attribute: String,
+        /// The expression the attribute was selected from, e.g. `config.services`.
+        path: String,
+        /// Similarly named attributes of the selected set.
+        suggestions: Vec<String>,
+    },
+    /// A value of the wrong type was used, e.g. a string where a set was expected. Both
+    /// fields are type descriptions such as "an integer" or "a set".
+    TypeMismatch {
+        expected: &'static str,
+        actual: &'static str,
+    },
     UnsupportedOperation(String),
+    DivisionByZero,
+    /// Integer arithmetic overflowed; `operation` is e.g. "addition".
+    Overflow {
+        operation: &'static str,
+    },
+    /// The condition of an `assert` evaluated to `false`.
+    AssertionFailed,
+    /// Raised by `throw`.
+    Throw(String),
+    /// Raised by `abort`.
+    Abort(String),
+    /// A value depends on itself; `cycle` leads from it back to itself.
+    InfiniteRecursion {
+        cycle: Vec<CycleMember>,
+    },
+    /// The evaluation's [`CancellationToken`] was triggered.
+    Cancelled,
+    /// One of the configured [`Limits`] was exceeded.
+    ResourceLimitExceeded(ResourceLimit),
+    SearchPathNotFound {
+        lookup: String,
+        searched: Vec<SearchPathEntry>,
+    },
+    /// `operation`, e.g. "look up '<nixpkgs>'", was attempted in pure evaluation mode.
+    Impure {
+        operation: String,
+    },
+    /// A file could not be read.
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// An imported file has a syntax error; `file` identifies it in the source map.
+    ImportParseFailed {
+        path: PathBuf,
+        file: FileId,
+        error: ParseError,
+    },
+    /// Source text passed to [`crate::evaluator::Evaluator::eval_str`] has a syntax
+    /// error; `file` identifies it in the source map.
+    ParseFailed {
+        file: FileId,
+        error: ParseError,
+    },
+    /// `error` was raised by the expression at `span`, while evaluating the constructs
+    /// in `trace`, innermost first.
+    Traced {
+        error: Box<EvaluationError>,
+        span: Span,
+        trace: Vec<TraceFrame>,
+    },
 }
 
-pub fn nix_eval(expr: &NixExpr, scope: &Scope) -> Result<NixExpr, EvaluationError> {
-    match expr {
-        NixExpr::Value(_) => Ok(expr.clone()),
+/// A link in the cycle of an [`EvaluationError::InfiniteRecursion`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum CycleMember {
+    /// A `let` binding or attribute of a `rec` set, located at its value.
+    Binding { name: String, span: Span },
+    /// A file importing itself, possibly through other files.
+    File(PathBuf),
+}
 
-        NixExpr::Ref(name) => scope
-            .get(name)
-            .cloned()
-            .ok_or_else(|| EvaluationError::UndefinedVariable(name.clone())),
-
-        NixExpr::UnaryOp { op, expr } => {
-            let val = nix_eval(expr, scope)?;
-            if let NixExpr::Value(v) = val {
-                match op {
-                    NixUnaryOp::Neg => match v {
-                        NixValue::Int(i) => Ok(NixExpr::Value(NixValue::Int(-i))),
-                        NixValue::Float(f) => Ok(NixExpr::Value(NixValue::Float(-f))),
-                        _ => Err(EvaluationError::TypeMismatch(
-                            "Cannot negate a non-numeric value.".to_string(),
-                        )),
-                    },
-                    NixUnaryOp::Not => match v {
-                        NixValue::Bool(b) => Ok(NixExpr::Value(NixValue::Bool(!b))),
-                        _ => Err(EvaluationError::TypeMismatch(
-                            "Cannot perform logical negation on a non-boolean value.".to_string(),
-                        )),
-                    },
+impl fmt::Display for CycleMember {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CycleMember::Binding { name, .. } => write!(f, "'{}'", name),
+            CycleMember::File(path) => write!(f, "'{}'", path.display()),
+        }
+    }
+}
+
+/// One level of context an evaluation error passed through on its way up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub kind: TraceFrameKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceFrameKind {
+    /// A (possibly dotted) attribute of an attribute set.
+    Attribute(String),
+    /// A binding of a `let` expression.
+    LetBinding(String),
+    /// A call of the named builtin.
+    Call(String),
+    /// A call of the lambda defined at `span`, which `location` names along with its
+    /// file, e.g. `x.nix:3:5`.
+    Lambda { span: Span, location: String },
+    /// The top-level expression of an imported file.
+    Import(PathBuf),
+}
+
+impl TraceFrameKind {
+    /// The call of the lambda defined at `span`, located in the source map of `ctx`.
+    pub(crate) fn lambda(span: Span, ctx: &EvalContext) -> Self {
+        TraceFrameKind::Lambda {
+            span,
+            location: ctx.source_map.borrow().locate(span),
+        }
+    }
+}
+
+impl fmt::Display for TraceFrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceFrameKind::Attribute(path) => {
+                write!(f, "while evaluating the attribute '{}'", path)
+            }
+            TraceFrameKind::LetBinding(name) => {
+                write!(f, "while evaluating the let binding '{}'", name)
+            }
+            TraceFrameKind::Call(name) => write!(f, "while calling the '{}' builtin", name),
+            TraceFrameKind::Lambda { location, .. } => {
+                write!(f, "while calling the function at {}", location)
+            }
+            TraceFrameKind::Import(path) => {
+                write!(f, "while evaluating the file '{}'", path.display())
+            }
+        }
+    }
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvaluationError::Traced { error, trace, .. } => {
+                write!(f, "{}", error)?;
+                for frames in trace.chunk_by(PartialEq::eq) {
+                    write!(f, "\n… {}", frames[0].kind)?;
+                    if frames.len() > 1 {
+                        write!(f, "\n… ({} duplicate frames omitted)", frames.len() - 1)?;
+                    }
                 }
-            } else {
-                Err(EvaluationError::TypeMismatch(
-                    "Cannot perform unary operation on a non-value.".to_string(),
-                ))
+                Ok(())
+            }
+            EvaluationError::UndefinedVariable { name, .. } => {
+                write!(f, "undefined variable '{}'", name)
+            }
+            EvaluationError::MissingAttribute {
+                attribute, path, ..
+            } if path.is_empty() => write!(f, "attribute '{}' missing", attribute),
+            EvaluationError::MissingAttribute {
+                attribute, path, ..
+            } => write!(f, "attribute '{}' missing in '{}'", attribute, path),
+            EvaluationError::TypeMismatch { expected, actual } => {
+                write!(f, "value is {} while {} was expected", actual, expected)
             }
+            EvaluationError::UnsupportedOperation(message) => write!(f, "{}", message),
+            EvaluationError::DivisionByZero => write!(f, "division by zero"),
+            EvaluationError::Overflow { operation } => {
+                write!(f, "integer overflow in {}", operation)
+            }
+            EvaluationError::AssertionFailed => write!(f, "assertion failed"),
+            EvaluationError::Throw(message) => write!(f, "{}", message),
+            EvaluationError::Abort(message) => write!(
+                f,
+                "evaluation aborted with the following error message: '{}'",
+                message
+            ),
+            EvaluationError::InfiniteRecursion { cycle } => {
+                write!(f, "infinite recursion encountered")?;
+                if !cycle.is_empty() {
+                    let cycle = cycle.iter().map(ToString::to_string).collect::<Vec<_>>();
+                    write!(f, ": {}", cycle.join(" -> "))?;
+                }
+                Ok(())
+            }
+            EvaluationError::Cancelled => write!(f, "evaluation was cancelled"),
+            EvaluationError::ResourceLimitExceeded(limit) => {
+                write!(f, "evaluation exceeded the {}", limit)
+            }
+            EvaluationError::SearchPathNotFound { lookup, .. } => {
+                write!(f, "file '{}' was not found in the Nix search path", lookup)
+            }
+            EvaluationError::Impure { operation } => {
+                write!(f, "cannot {} in pure evaluation mode", operation)
+            }
+            // The underlying errors are exposed through `source()`.
+            EvaluationError::Io { path, .. } => write!(f, "cannot read '{}'", path.display()),
+            EvaluationError::ImportParseFailed { path, .. } => {
+                write!(f, "cannot parse '{}'", path.display())
+            }
+            EvaluationError::ParseFailed { error, .. } => write!(f, "{}", error),
         }
+    }
+}
 
-        NixExpr::BinaryOp { op, left, right } => {
-            let l_val = nix_eval(left, scope)?;
-            let r_val = nix_eval(right, scope)?;
+impl std::error::Error for EvaluationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // `Traced` displays its inner error, so it is transparent here as well.
+            EvaluationError::Traced { error, .. } => error.source(),
+            EvaluationError::Io { error, .. } => Some(error),
+            EvaluationError::ImportParseFailed { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
 
-            if let (NixExpr::Value(l), NixExpr::Value(r)) = (l_val, r_val) {
-                match op {
-                    NixBinaryOp::Add => match (l, r) {
-                        // Arithmetic
-                        (NixValue::Int(a), NixValue::Int(b)) => {
-                            Ok(NixExpr::Value(NixValue::Int(a + b)))
-                        }
-                        (NixValue::Float(a), NixValue::Float(b)) => {
-                            Ok(NixExpr::Value(NixValue::Float(a + b)))
-                        }
-                        (NixValue::Int(a), NixValue::Float(b)) => {
-                            Ok(NixExpr::Value(NixValue::Float(a as f64 + b)))
-                        }
-                        (NixValue::Float(a), NixValue::Int(b)) => {
-                            Ok(NixExpr::Value(NixValue::Float(a + b as f64)))
-                        }
-                        // String concatenation
-                        (NixValue::String(a), NixValue::String(b)) => {
-                            Ok(NixExpr::Value(NixValue::String(format!("{}{}", a, b))))
-                        }
-                        // TODO: Add other + operations (lists, paths, etc.)
-                        _ => Err(EvaluationError::UnsupportedOperation(
-                            "Addition is not supported for these types.".to_string(),
-                        )),
-                    },
-                    NixBinaryOp::Sub => match (l, r) {
-                        (NixValue::Int(a), NixValue::Int(b)) => {
-                            Ok(NixExpr::Value(NixValue::Int(a - b)))
-                        }
-                        (NixValue::Float(a), NixValue::Float(b)) => {
-                            Ok(NixExpr::Value(NixValue::Float(a - b)))
+impl EvaluationError {
+    pub(crate) fn type_mismatch(expected: &'static str, value: &Value) -> Self {
+        EvaluationError::TypeMismatch {
+            expected,
+            actual: value.type_name(),
+        }
+    }
+
+    /// The underlying error, without any location or trace information.
+    pub fn root_cause(&self) -> &EvaluationError {
+        match self {
+            EvaluationError::Traced { error, .. } => error.root_cause(),
+            error => error,
+        }
+    }
+
+    /// Where the error was raised, if it has been located yet.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            EvaluationError::Traced { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// The constructs the error surfaced through, innermost first.
+    pub fn trace(&self) -> &[TraceFrame] {
+        match self {
+            EvaluationError::Traced { trace, .. } => trace,
+            _ => &[],
+        }
+    }
+
+    /// Locates an untraced error at `span`; already located errors are left alone.
+    pub(crate) fn at(self, span: Span) -> Self {
+        match self {
+            EvaluationError::Traced { .. } => self,
+            error => EvaluationError::Traced {
+                error: Box::new(error),
+                span,
+                trace: Vec::new(),
+            },
+        }
+    }
+
+    /// Records that the error surfaced while evaluating `kind` at `span`. Consecutive
+    /// attribute frames are merged into a single dotted path.
+    pub(crate) fn in_frame(self, kind: TraceFrameKind, span: Span) -> Self {
+        let EvaluationError::Traced {
+            error,
+            span: error_span,
+            mut trace,
+        } = self
+        else {
+            return self.at(span);
+        };
+        match (trace.last_mut(), kind) {
+            (
+                Some(TraceFrame {
+                    kind: TraceFrameKind::Attribute(inner),
+                    ..
+                }),
+                TraceFrameKind::Attribute(outer),
+            ) => *inner = format!("{}.{}", outer, inner),
+            (_, kind) => trace.push(TraceFrame { kind, span }),
+        }
+        EvaluationError::Traced {
+            error,
+            span: error_span,
+            trace,
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diagnostic = Diagnostic::error(self.to_string());
+        match self {
+            EvaluationError::Traced { error, span, trace } => {
+                let mut diagnostic = error.to_diagnostic();
+                if !diagnostic.labels.iter().any(|label| label.primary) {
+                    diagnostic = diagnostic.with_label(*span, "");
+                }
+                // Recursion repeats the same frames, which are shown only once.
+                trace
+                    .chunk_by(PartialEq::eq)
+                    .fold(diagnostic, |diagnostic, frames| {
+                        let mut message = format!("… {}", frames[0].kind);
+                        if frames.len() > 1 {
+                            message = format!(
+                                "{} ({} duplicate frames omitted)",
+                                message,
+                                frames.len() - 1
+                            );
                         }
-                        (NixValue::Int(a), NixValue::Float(b)) => {
-                            Ok(NixExpr::Value(NixValue::Float(a as f64 - b)))
+                        diagnostic.with_secondary_label(frames[0].span, message)
+                    })
+            }
+            EvaluationError::ImportParseFailed { path, file, error } => {
+                let mut diagnostic = error.to_diagnostic(*file);
+                diagnostic.message = format!("{} (in '{}')", diagnostic.message, path.display());
+                diagnostic
+            }
+            EvaluationError::ParseFailed { file, error } => error.to_diagnostic(*file),
+            EvaluationError::Io { error, .. } => diagnostic.with_note(error.to_string()),
+            // The error is located at the first binding, so it gets the primary label.
+            EvaluationError::InfiniteRecursion { cycle } => {
+                cycle
+                    .windows(2)
+                    .enumerate()
+                    .fold(diagnostic, |diagnostic, (i, pair)| match pair {
+                        [CycleMember::Binding { span, .. }, next] if i == 0 => {
+                            diagnostic.with_label(*span, format!("depends on {}", next))
                         }
-                        (NixValue::Float(a), NixValue::Int(b)) => {
-                            Ok(NixExpr::Value(NixValue::Float(a - b as f64)))
+                        [CycleMember::Binding { span, .. }, next] => {
+                            diagnostic.with_secondary_label(*span, format!("depends on {}", next))
                         }
-                        _ => Err(EvaluationError::UnsupportedOperation(
-                            "Subtraction is not supported for these types.".to_string(),
-                        )),
-                    },
+                        _ => diagnostic,
+                    })
+            }
+            EvaluationError::UndefinedVariable { suggestions, .. }
+            | EvaluationError::MissingAttribute { suggestions, .. } => {
+                match did_you_mean(suggestions) {
+                    Some(help) => diagnostic.with_help(help),
+                    None => diagnostic,
                 }
-            } else {
-                Err(EvaluationError::TypeMismatch(
-                    "Cannot perform binary operation on non-values.".to_string(),
-                ))
             }
+            EvaluationError::SearchPathNotFound { searched, .. } if searched.is_empty() => {
+                diagnostic.with_help("add it using $NIX_PATH or -I")
+            }
+            EvaluationError::SearchPathNotFound { searched, .. } => searched
+                .iter()
+                .fold(diagnostic, |diagnostic, entry| {
+                    diagnostic.with_note(format!("searched '{}'", entry))
+                })
+                .with_help("add it using $NIX_PATH or -I"),
+            EvaluationError::Impure { .. } => {
+                diagnostic.with_help("disable pure evaluation mode to allow it")
+            }
+            _ => diagnostic,
         }
+    }
+}
 
-        NixExpr::With { environment, body } => {
-            let evaluated_env = nix_eval(environment, scope)?;
+/// How expressions are evaluated. Both backends accept the same programs and produce
+/// the same values and errors, except for [`Limits::max_steps`], which counts different
+/// things.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Walks the expression tree directly.
+    #[default]
+    TreeWalking,
+    /// Compiles each file and each [`crate::evaluator::Evaluator::eval_str`] source to
+    /// bytecode once and runs it on a virtual machine. Compiling makes the first
+    /// evaluation slower, which pays off for programs that call the same functions many
+    /// times or are evaluated repeatedly. Its steps are instructions rather than
+    /// expressions.
+    Bytecode,
+}
 
-            if let NixExpr::AttrSet { bindings, .. } = evaluated_env {
-                let mut extended_scope = scope.clone();
-                for (key, value) in bindings {
-                    extended_scope.insert(key, value);
-                }
-                nix_eval(body, &extended_scope)
-            } else {
-                Err(EvaluationError::TypeMismatch(
-                    "Expression in 'with' must evaluate to an attribute set.".to_string(),
-                ))
-            }
-        }
+/// Evaluation-wide settings that are independent of the lexical scope.
+#[derive(Debug, Clone, Default)]
+pub struct EvalContext {
+    pub backend: Backend,
+    pub search_path: SearchPath,
+    /// Variables visible to every imported file, in addition to the builtins.
+    pub import_scope: Scope,
+    /// Evaluated files, keyed by their canonical path.
+    pub(crate) import_cache: RefCell<HashMap<PathBuf, Value>>,
+    /// Files whose evaluation is currently in progress, outermost first.
+    pub(crate) import_stack: RefCell<Vec<PathBuf>>,
+    /// Files parsed and lowered so far, so that `scopedImport` prepares each of them
+    /// once per set of names in scope.
+    pub(crate) programs: RefCell<HashMap<ProgramKey, Rc<Program>>>,
+    /// The text of every file loaded during evaluation, for resolving spans.
+    pub source_map: RefCell<SourceMap>,
+    pub limits: Limits,
+    pub cancellation: CancellationToken,
+    /// Forbids evaluation from depending on the environment, such as `<...>` lookups.
+    pub pure: bool,
+    pub trace_sink: TraceSink,
+    /// How deeply nested the expression currently being evaluated is.
+    pub(crate) depth: Cell<usize>,
+    /// How many expressions the current evaluation has evaluated so far.
+    pub(crate) steps: Cell<u64>,
+    /// When the current evaluation runs out of time, see [`Limits::timeout`].
+    pub(crate) deadline: Cell<Option<Instant>>,
+    /// The identifiers and attribute names of every file evaluated so far.
+    pub(crate) symbols: RefCell<SymbolTable>,
+    /// The global builtin scope, built with `symbols` the first time it is needed.
+    globals: OnceCell<AttrSet>,
+    /// The thunks of recursive bindings, which may be part of reference cycles. Declared
+    /// last, so that the caches are gone by the time it collects them on drop.
+    pub(crate) knots: RefCell<cycles::Knots>,
+}
 
-        NixExpr::LetIn { bindings, body } => {
-            let mut extended_scope = scope.clone();
-            for (key, value_expr) in bindings {
-                let evaluated_value = nix_eval(value_expr, &extended_scope)?;
-                extended_scope.insert(key.clone(), evaluated_value);
-            }
-            nix_eval(body, &extended_scope)
+impl EvalContext {
+    /// Starts a new evaluation, which gets its own step count and time limit.
+    pub(crate) fn begin(&self) {
+        self.steps.set(0);
+        let deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.deadline.set(deadline);
+    }
+
+    /// Finishes an evaluation, reclaiming the cycles it left behind now and then.
+    pub(crate) fn end(&self) {
+        self.knots.borrow_mut().maybe_collect();
+    }
+
+    /// The values of the names that resolve to [`Binder::Global`].
+    pub(crate) fn globals(&self) -> &AttrSet {
+        self.globals
+            .get_or_init(|| builtins::globals(&mut self.symbols.borrow_mut()))
+    }
+}
+
+/// A lowered program, along with its bytecode once [`Backend::Bytecode`] has compiled it.
+pub(crate) struct Program {
+    pub expr: Rc<ir::Expr>,
+    chunk: OnceCell<Rc<bytecode::Chunk>>,
+}
+
+impl Program {
+    pub fn new(expr: Rc<ir::Expr>) -> Self {
+        Program {
+            expr,
+            chunk: OnceCell::new(),
         }
+    }
 
-        NixExpr::List(items) => {
-            let evaluated_items = items
-                .iter()
-                .map(|item| nix_eval(item, scope))
-                .collect::<Result<Vec<_>, _>>()?;
-            Ok(NixExpr::List(evaluated_items))
+    /// Lowers `ast` to be evaluated in a scope with the names of `scope`.
+    pub fn lower(ast: &Ast, scope: &Scope, ctx: &EvalContext) -> Result<Self, EvaluationError> {
+        // The symbol table is released before evaluating, which imports other files.
+        ir::lower(ast, scope, &mut ctx.symbols.borrow_mut()).map(Program::new)
+    }
+
+    /// The bytecode of the program, compiled the first time it is run.
+    fn chunk(&self, ctx: &EvalContext) -> Rc<bytecode::Chunk> {
+        self.chunk
+            .get_or_init(|| bytecode::compile(&self.expr, ctx.globals()))
+            .clone()
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Program")
+    }
+}
+
+/// Receives the messages of `builtins.trace`. The default sink prints them to stderr.
+#[derive(Clone)]
+pub struct TraceSink(Rc<dyn Fn(&Value)>);
+
+impl TraceSink {
+    pub fn new(sink: impl Fn(&Value) + 'static) -> Self {
+        TraceSink(Rc::new(sink))
+    }
+
+    pub(crate) fn emit(&self, value: &Value) {
+        (self.0)(value)
+    }
+}
+
+impl Default for TraceSink {
+    fn default() -> Self {
+        TraceSink::new(|value| eprintln!("trace: {}", value))
+    }
+}
+
+impl fmt::Debug for TraceSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TraceSink")
+    }
+}
+
+/// Evaluates `expr` with a fresh [`EvalContext`]. Hosts evaluating more than once should
+/// prefer an [`crate::evaluator::Evaluator`], which keeps its caches between calls.
+pub fn nix_eval(expr: &NixExpr, scope: &Scope) -> Result<Value, EvaluationError> {
+    nix_eval_with(expr, scope, &EvalContext::default())
+}
+
+/// Evaluates `expr` in `scope` and forces the result deeply.
+pub fn nix_eval_with(
+    expr: &NixExpr,
+    scope: &Scope,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    nix_eval_ast(&Ast::from_nix_expr(expr), scope, ctx)
+}
+
+/// Like [`nix_eval_with`], for a tree from [`crate::parser::parse_ast`].
+pub fn nix_eval_ast(ast: &Ast, scope: &Scope, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    Program::lower(ast, scope, ctx).and_then(|program| eval_program(&program, scope, ctx))
+}
+
+/// Evaluates a lowered program in `scope` as an evaluation of its own and forces the
+/// result deeply.
+pub(crate) fn eval_program(
+    program: &Program,
+    scope: &Scope,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    ctx.begin();
+    let result = eval_lowered(program, Environment::root(scope.clone()), true, ctx);
+    ctx.end();
+    result
+}
+
+/// Evaluates a program with the backend of `ctx`, to weak head normal form or, if
+/// `deep` is set, completely.
+pub(crate) fn eval_lowered(
+    program: &Program,
+    env: Env,
+    deep: bool,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let expr = program.expr.clone();
+    match ctx.backend {
+        Backend::TreeWalking if deep => machine::eval_deep(expr, env, ctx),
+        Backend::TreeWalking => machine::eval_shallow(expr, env, ctx),
+        Backend::Bytecode => vm::eval(program.chunk(ctx), env, deep, ctx),
+    }
+}
+
+/// Forces everything an already evaluated value contains.
+pub(crate) fn force_deep(value: Value, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    match ctx.backend {
+        Backend::TreeWalking => machine::force_deep(value, ctx),
+        Backend::Bytecode => vm::force_deep(value, ctx),
+    }
+}
+
+/// Applies `function` to `args` one at a time and forces the result deeply.
+pub(crate) fn call(
+    function: Value,
+    args: Vec<Value>,
+    span: Span,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    match ctx.backend {
+        Backend::TreeWalking => machine::call(function, args, span, ctx),
+        Backend::Bytecode => vm::call(function, args, span, ctx),
+    }
+}
+
+/// Evaluates `+`, `-` and `//` on two forced operands.
+fn binary_op(op: NixBinaryOp, left: &Value, right: &Value) -> Result<Value, EvaluationError> {
+    if op == NixBinaryOp::Update {
+        return match (left, right) {
+            (Value::Attrs(a), Value::Attrs(b)) => Ok(Value::Attrs(a.update(b))),
+            (Value::Attrs(_), _) => Err(EvaluationError::type_mismatch("a set", right)),
+            _ => Err(EvaluationError::type_mismatch("a set", left)),
+        };
+    }
+    let float_op = |a: f64, b: f64| match op {
+        NixBinaryOp::Add => a + b,
+        NixBinaryOp::Sub => a - b,
+        NixBinaryOp::Update => unreachable!("`//` is not arithmetic"),
+    };
+    match (op, left, right) {
+        (NixBinaryOp::Add, Value::Int(a), Value::Int(b)) => a
+            .checked_add(*b)
+            .map(Value::Int)
+            .ok_or(EvaluationError::Overflow {
+                operation: "addition",
+            }),
+        (NixBinaryOp::Sub, Value::Int(a), Value::Int(b)) => a
+            .checked_sub(*b)
+            .map(Value::Int)
+            .ok_or(EvaluationError::Overflow {
+                operation: "subtraction",
+            }),
+        (_, Value::Float(a), Value::Float(b)) => Ok(Value::Float(float_op(*a, *b))),
+        (_, Value::Int(a), Value::Float(b)) => Ok(Value::Float(float_op(*a as f64, *b))),
+        (_, Value::Float(a), Value::Int(b)) => Ok(Value::Float(float_op(*a, *b as f64))),
+        // String concatenation, which carries the context of both operands along
+        (NixBinaryOp::Add, Value::String(a), Value::String(b)) => {
+            let mut context = a.context().clone();
+            context.extend(b.context().iter().cloned());
+            Ok(Value::String(NixString::with_context(
+                format!("{}{}", a, b),
+                context,
+            )))
+        }
+        // TODO: Add other + operations (lists, paths, etc.)
+        (NixBinaryOp::Add, Value::String(_), _) => {
+            Err(EvaluationError::type_mismatch("a string", right))
+        }
+        (_, Value::Int(_) | Value::Float(_), _) => {
+            Err(EvaluationError::type_mismatch("a number", right))
         }
+        _ => Err(EvaluationError::type_mismatch("a number", left)),
+    }
+}
 
-        NixExpr::AttrSet {
-            recursive,
-            bindings,
-        } => {
-            // TODO: The `recursive` flag is not yet handled.
-            let evaluated_bindings = bindings
-                .iter()
-                .map(|(key, value)| {
-                    let evaluated_value = nix_eval(value, scope)?;
-                    Ok((key.clone(), evaluated_value))
+/// Evaluates `-` and `!` on a forced operand.
+fn unary_op(op: NixUnaryOp, value: &Value) -> Result<Value, EvaluationError> {
+    match (op, value) {
+        (NixUnaryOp::Neg, Value::Int(i)) => {
+            i.checked_neg()
+                .map(Value::Int)
+                .ok_or(EvaluationError::Overflow {
+                    operation: "negation",
                 })
-                .collect::<Result<IndexMap<_, _>, _>>()?;
-            Ok(NixExpr::AttrSet {
-                recursive: *recursive,
-                bindings: evaluated_bindings,
-            })
-        }
-        NixExpr::SearchPath(path) => Ok(NixExpr::SearchPath(path.clone())),
-
-        NixExpr::InterpolatedString(parts) => {
-            let mut result = String::new();
-            for part in parts {
-                match part {
-                    NixStringPart::Literal(s) => result.push_str(s),
-                    NixStringPart::Interpolation(expr_to_interpolate) => {
-                        let evaluated_expr = nix_eval(expr_to_interpolate, scope)?;
-                        // This logic should be expanded to handle auto-coercion to string
-                        if let NixExpr::Value(NixValue::String(s)) = evaluated_expr {
-                            result.push_str(&s);
-                        } else {
-                            return Err(EvaluationError::TypeMismatch(
-                                "Expected a string for interpolation.".to_string(),
-                            ));
-                        }
-                    }
-                }
+        }
+        (NixUnaryOp::Neg, Value::Float(f)) => Ok(Value::Float(-f)),
+        (NixUnaryOp::Neg, _) => Err(EvaluationError::type_mismatch("a number", value)),
+        (NixUnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (NixUnaryOp::Not, _) => Err(EvaluationError::type_mismatch("a Boolean", value)),
+    }
+}
+
+/// Fetches the value of a variable from where it was resolved to be bound. Only names
+/// under `with` need to be looked up by name.
+fn lookup(
+    name: &Symbol,
+    address: &Address,
+    env: &Environment,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let bound = |binder: &Binder| match *binder {
+        Binder::Local { depth, slot } => env.get(depth, slot).clone(),
+        Binder::Global => ctx
+            .globals()
+            .get(name)
+            .expect("resolved to a builtin")
+            .clone(),
+    };
+    match address {
+        Address::Static(binder) => Ok(bound(binder)),
+        Address::With(binder) => {
+            // A lexical binding inside the innermost `with` shadows the outer ones.
+            let depth = match binder {
+                Some(Binder::Local { depth, .. }) => Some(*depth),
+                _ => None,
+            };
+            if let Some(value) = env.get_dynamic(name, depth) {
+                return Ok(value.clone());
             }
-            Ok(NixExpr::Value(NixValue::String(result)))
+            binder
+                .as_ref()
+                .map(bound)
+                .ok_or_else(|| EvaluationError::UndefinedVariable {
+                    name: name.to_string(),
+                    suggestions: suggest_similar(
+                        name.as_str(),
+                        env.with_names()
+                            .map(Symbol::as_str)
+                            .chain(builtins::global_names()),
+                    ),
+                })
         }
     }
 }
+
+/// Resolves a `<lookup>` path against the search path.
+fn search_path(lookup: &str, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    if ctx.pure {
+        return Err(EvaluationError::Impure {
+            operation: format!("look up '<{}>'", lookup),
+        });
+    }
+    let path =
+        ctx.search_path
+            .resolve(lookup)
+            .ok_or_else(|| EvaluationError::SearchPathNotFound {
+                lookup: lookup.to_string(),
+                searched: ctx.search_path.entries().cloned().collect(),
+            })?;
+    Ok(Value::Path(path.into()))
+}
+
+/// Selects the dotted attribute path `attrs` from a deeply forced `value`.
+pub(crate) fn select(
+    value: &Value,
+    attrs: &[Cow<str>],
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let mut current = value;
+    for (depth, attr) in attrs.iter().enumerate() {
+        let Value::Attrs(bindings) = current else {
+            return Err(EvaluationError::type_mismatch("a set", current));
+        };
+        let symbol = ctx.symbols.borrow().get(attr);
+        let Some(selected) = symbol.and_then(|symbol| bindings.get(&symbol)) else {
+            return Err(EvaluationError::MissingAttribute {
+                attribute: attr.to_string(),
+                path: attrs[..depth].join("."),
+                suggestions: suggest_similar(attr, bindings.keys().map(Symbol::as_str)),
+            });
+        };
+        current = selected
+            .forced()
+            .expect("deeply forced values contain no unforced thunks");
+    }
+    Ok(current.clone())
+}
@@ -0,0 +1,509 @@
+use super::ir::{Address, Binder, Binding, Expr, ExprKind, Part};
+use crate::span::Span;
+use crate::symbol::Symbol;
+use crate::value::{AttrSet, NixString, Value};
+use crate::{NixBinaryOp, NixUnaryOp};
+use std::rc::Rc;
+
+/// The compiled body of a program, thunk or function.
+///
+/// Instructions are kept small by referring to everything else by index: values to the
+/// constant pool, the bodies of nested thunks and functions to `chunks`, and names and
+/// spans to the other tables.
+pub(crate) struct Chunk {
+    pub code: Vec<Op>,
+    /// The node of the expression each instruction belongs to.
+    pub owners: Vec<u32>,
+    /// The expressions compiled into the chunk, the whole expression first.
+    pub nodes: Vec<Node>,
+    pub constants: Vec<Value>,
+    /// The bodies of the thunks and functions this chunk creates.
+    pub chunks: Vec<Rc<Chunk>>,
+    /// The parameters of the functions this chunk creates.
+    pub params: Vec<Symbol>,
+    /// Variables under `with`, which are looked up by name.
+    pub dynamic: Vec<(Symbol, Address)>,
+    /// Variables followed by the attributes selected from them.
+    pub paths: Vec<Box<[Symbol]>>,
+    /// The attribute names of non-recursive sets.
+    pub names: Vec<Box<[Symbol]>>,
+    /// The bindings of `let` expressions and recursive sets.
+    pub recursive: Vec<Vec<RecBinding>>,
+    /// The conditions of assertions.
+    pub assertions: Vec<Span>,
+    pub search_paths: Vec<String>,
+    /// Where the compiled expression is.
+    pub span: Span,
+}
+
+/// An expression compiled into a chunk, which locates errors and tells how deeply the
+/// instructions it owns are nested.
+pub(crate) struct Node {
+    pub span: Span,
+    /// How many expressions of the chunk it is nested in.
+    pub depth: u32,
+    /// The node of the expression it is nested in, or its own for the whole expression.
+    pub parent: u32,
+}
+
+/// An instruction of the virtual machine. Operands are taken from and results left on
+/// its value stack.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    /// Pushes `constants[index]`.
+    Constant(u32),
+    /// Pushes the variable in `slot` of the frame `depth` frames out.
+    Local {
+        depth: u32,
+        slot: u32,
+    },
+    /// Pushes the variable `dynamic[index]`.
+    Dynamic(u32),
+    /// Forces the value on top of the stack.
+    Force,
+    /// Replaces the set on top of the stack by the attribute `depth + 1` of
+    /// `paths[path]`.
+    Select {
+        path: u32,
+        depth: u32,
+    },
+    /// Pushes a thunk of `chunks[index]` in the current environment.
+    Thunk(u32),
+    /// Pushes a function with the parameter `params[param]` and the body `chunks[body]`.
+    Closure {
+        param: u32,
+        body: u32,
+    },
+    /// Applies the function below the top of the stack to the argument on top.
+    Call,
+    Unary(NixUnaryOp),
+    Binary(NixBinaryOp),
+    /// Enters a `with` frame for the set on top of the stack.
+    PushWith,
+    /// Enters a frame for the bindings `recursive[index]` of a `let`.
+    PushLet(u32),
+    /// Leaves the innermost frame.
+    PopEnv,
+    /// Pushes a recursive set with the bindings `recursive[index]`.
+    RecAttrs(u32),
+    /// Collects the topmost `len` values into a list.
+    List(u32),
+    /// Collects the topmost values into a set with the attributes `names[index]`.
+    Attrs(u32),
+    /// Fails unless the value on top of the stack is true. The condition is at
+    /// `assertions[index]`.
+    Assert(u32),
+    /// Checks that the value on top of the stack can be interpolated into a string.
+    CheckString,
+    /// Concatenates the topmost `len` strings.
+    Interpolate(u32),
+    /// Concatenates the topmost `len` strings into a path.
+    InterpolatePath(u32),
+    SearchPath(u32),
+    /// An expression that failed to parse.
+    Invalid,
+    /// Ends the chunk, whose value is on top of the stack.
+    Return,
+}
+
+/// A binding of a `let` or recursive set.
+pub(crate) struct RecBinding {
+    pub name: Symbol,
+    pub value: Delayed,
+    pub inherited: bool,
+}
+
+/// How the value of a binding is created.
+#[derive(Clone, Copy)]
+pub(crate) enum Delayed {
+    /// `constants[index]`.
+    Constant(u32),
+    /// A thunk of `chunks[index]`.
+    Thunk(u32),
+}
+
+impl Chunk {
+    fn new(span: Span) -> Self {
+        Chunk {
+            code: Vec::new(),
+            owners: Vec::new(),
+            nodes: vec![Node {
+                span,
+                depth: 0,
+                parent: 0,
+            }],
+            constants: Vec::new(),
+            chunks: Vec::new(),
+            params: Vec::new(),
+            dynamic: Vec::new(),
+            paths: Vec::new(),
+            names: Vec::new(),
+            recursive: Vec::new(),
+            assertions: Vec::new(),
+            search_paths: Vec::new(),
+            span,
+        }
+    }
+
+    fn emit(&mut self, op: Op, node: u32) {
+        self.code.push(op);
+        self.owners.push(node);
+    }
+
+    /// The node of the instruction at `pc`.
+    pub fn node(&self, pc: usize) -> &Node {
+        &self.nodes[self.owners[pc] as usize]
+    }
+
+    /// The span of the expression `depth` levels deep that the instruction at `pc`
+    /// is nested in.
+    pub fn enclosing(&self, pc: usize, depth: u32) -> Span {
+        let mut node = self.node(pc);
+        while node.depth > depth {
+            node = &self.nodes[node.parent as usize];
+        }
+        node.span
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        // Deeply nested thunks would overflow the stack with the derived drop glue.
+        let mut pending = std::mem::take(&mut self.chunks);
+        while let Some(chunk) = pending.pop() {
+            if let Some(mut chunk) = Rc::into_inner(chunk) {
+                pending.append(&mut chunk.chunks);
+            }
+        }
+    }
+}
+
+/// Compiles a lowered expression. Variables have already been resolved, so the
+/// compiler only has to flatten the tree into instructions, and to take the builtins
+/// they refer to from `globals`.
+pub(crate) fn compile(expr: &Expr, globals: &AttrSet) -> Rc<Chunk> {
+    let mut compiler = Compiler {
+        globals,
+        chunks: Vec::new(),
+        tasks: vec![
+            Task::Finish(Target::Root),
+            Task::Compile(expr, None),
+            Task::Begin(expr.span),
+        ],
+        compiled: None,
+    };
+    // The expression tree is walked with an explicit stack, like it is evaluated.
+    while let Some(task) = compiler.tasks.pop() {
+        compiler.run(task);
+    }
+    compiler.compiled.expect("the root chunk has been compiled")
+}
+
+struct Compiler<'a> {
+    globals: &'a AttrSet,
+    /// The chunks being compiled, innermost last.
+    chunks: Vec<Chunk>,
+    tasks: Vec<Task<'a>>,
+    compiled: Option<Rc<Chunk>>,
+}
+
+enum Task<'a> {
+    /// Starts a chunk for the expression at the span.
+    Begin(Span),
+    /// Emits the code that pushes the forced value of the expression, which is nested
+    /// in the node, or is the whole expression of the chunk.
+    Compile(&'a Expr, Option<u32>),
+    /// Emits the code that pushes the value of the expression without evaluating it,
+    /// on behalf of the node.
+    Delay(&'a Rc<Expr>, u32),
+    Emit(Op, u32),
+    /// Ends the innermost chunk and hands it to `target`.
+    Finish(Target),
+}
+
+enum Target {
+    Root,
+    /// A thunk created by the node of the enclosing chunk.
+    Thunk(u32),
+    /// A function with the parameter, created by the node of the enclosing chunk.
+    Closure(Symbol, u32),
+    /// The value of binding `index` of `recursive[table]` in the enclosing chunk.
+    Binding {
+        table: u32,
+        index: usize,
+    },
+}
+
+impl<'a> Compiler<'a> {
+    fn chunk(&mut self) -> &mut Chunk {
+        self.chunks.last_mut().expect("a chunk is being compiled")
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let constants = &mut self.chunk().constants;
+        constants.push(value);
+        index(constants.len() - 1)
+    }
+
+    fn run(&mut self, task: Task<'a>) {
+        match task {
+            Task::Begin(span) => self.chunks.push(Chunk::new(span)),
+            Task::Compile(expr, parent) => self.compile(expr, parent),
+            Task::Delay(expr, node) => self.delay(expr, node),
+            Task::Emit(op, node) => self.chunk().emit(op, node),
+            Task::Finish(target) => {
+                let mut chunk = self.chunks.pop().expect("a chunk is being compiled");
+                chunk.emit(Op::Return, 0);
+                let chunk = Rc::new(chunk);
+                if let Target::Root = target {
+                    self.compiled = Some(chunk);
+                    return;
+                }
+                let parent = self.chunk();
+                parent.chunks.push(chunk);
+                let body = index(parent.chunks.len() - 1);
+                match target {
+                    Target::Root => unreachable!("the root chunk has no parent"),
+                    Target::Thunk(node) => parent.emit(Op::Thunk(body), node),
+                    Target::Closure(param, node) => {
+                        parent.params.push(param);
+                        let param = index(parent.params.len() - 1);
+                        parent.emit(Op::Closure { param, body }, node);
+                    }
+                    Target::Binding { table, index } => {
+                        parent.recursive[table as usize][index].value = Delayed::Thunk(body);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Schedules compiling `expr` as a chunk of its own, for `target`.
+    fn nested(&mut self, expr: &'a Expr, target: Target) {
+        self.tasks.push(Task::Finish(target));
+        self.tasks.push(Task::Compile(expr, None));
+        self.tasks.push(Task::Begin(expr.span));
+    }
+
+    fn delay(&mut self, expr: &'a Rc<Expr>, node: u32) {
+        match &expr.kind {
+            ExprKind::Literal(value) => {
+                let constant = self.constant(value.clone());
+                self.chunk().emit(Op::Constant(constant), node);
+            }
+            ExprKind::Var {
+                attrs,
+                address: Address::Static(Binder::Local { depth, slot }),
+                ..
+            } if attrs.is_empty() => {
+                let op = Op::Local {
+                    depth: index(*depth),
+                    slot: index(*slot),
+                };
+                self.chunk().emit(op, node);
+            }
+            _ => self.nested(expr, Target::Thunk(node)),
+        }
+    }
+
+    fn compile(&mut self, expr: &'a Expr, parent: Option<u32>) {
+        let node = match parent {
+            Some(parent) => {
+                let nodes = &mut self.chunk().nodes;
+                nodes.push(Node {
+                    span: expr.span,
+                    depth: nodes[parent as usize].depth + 1,
+                    parent,
+                });
+                index(nodes.len() - 1)
+            }
+            None => 0,
+        };
+        match &expr.kind {
+            ExprKind::Literal(value) => {
+                let constant = self.constant(value.clone());
+                self.chunk().emit(Op::Constant(constant), node);
+            }
+
+            ExprKind::Var {
+                name,
+                attrs,
+                address,
+            } => {
+                let load = match address {
+                    Address::Static(Binder::Local { depth, slot }) => Op::Local {
+                        depth: index(*depth),
+                        slot: index(*slot),
+                    },
+                    Address::Static(Binder::Global) => {
+                        let builtin = self.globals.get(name).expect("resolved to a builtin");
+                        Op::Constant(self.constant(builtin.clone()))
+                    }
+                    Address::With(_) => {
+                        let dynamic = &mut self.chunk().dynamic;
+                        dynamic.push((name.clone(), *address));
+                        Op::Dynamic(index(dynamic.len() - 1))
+                    }
+                };
+                let chunk = self.chunk();
+                chunk.emit(load, node);
+                chunk.emit(Op::Force, node);
+                if attrs.is_empty() {
+                    return;
+                }
+                let path = std::iter::once(name).chain(attrs).cloned();
+                chunk.paths.push(path.collect());
+                let path = index(chunk.paths.len() - 1);
+                for depth in 0..attrs.len() {
+                    let depth = index(depth);
+                    chunk.emit(Op::Select { path, depth }, node);
+                    chunk.emit(Op::Force, node);
+                }
+            }
+
+            ExprKind::Lambda { param, body } => {
+                self.nested(body, Target::Closure(param.clone(), node))
+            }
+
+            ExprKind::Error => self.chunk().emit(Op::Invalid, node),
+
+            ExprKind::SearchPath(lookup) => {
+                let chunk = self.chunk();
+                chunk.search_paths.push(lookup.clone());
+                let lookup = index(chunk.search_paths.len() - 1);
+                chunk.emit(Op::SearchPath(lookup), node);
+            }
+
+            ExprKind::Unary { op, operand } => {
+                self.tasks.push(Task::Emit(Op::Unary(*op), node));
+                self.tasks.push(Task::Compile(operand, Some(node)));
+            }
+
+            ExprKind::Binary { op, left, right } => {
+                self.tasks.push(Task::Emit(Op::Binary(*op), node));
+                self.tasks.push(Task::Compile(right, Some(node)));
+                self.tasks.push(Task::Compile(left, Some(node)));
+            }
+
+            ExprKind::Apply { function, argument } => {
+                self.tasks.push(Task::Emit(Op::Call, node));
+                self.tasks.push(Task::Delay(argument, node));
+                self.tasks.push(Task::Compile(function, Some(node)));
+            }
+
+            ExprKind::With { environment, body } => {
+                self.tasks.push(Task::Emit(Op::PopEnv, node));
+                self.tasks.push(Task::Compile(body, Some(node)));
+                self.tasks.push(Task::Emit(Op::PushWith, node));
+                self.tasks.push(Task::Compile(environment, Some(node)));
+            }
+
+            ExprKind::Assert { condition, body } => {
+                let assertions = &mut self.chunk().assertions;
+                assertions.push(condition.span);
+                let assertion = index(assertions.len() - 1);
+                self.tasks.push(Task::Compile(body, Some(node)));
+                self.tasks.push(Task::Emit(Op::Assert(assertion), node));
+                self.tasks.push(Task::Compile(condition, Some(node)));
+            }
+
+            ExprKind::List(items) => {
+                self.tasks
+                    .push(Task::Emit(Op::List(index(items.len())), node));
+                self.tasks
+                    .extend(items.iter().rev().map(|item| Task::Delay(item, node)));
+            }
+
+            ExprKind::Attrs {
+                recursive: false,
+                bindings,
+            } => {
+                let names = &mut self.chunk().names;
+                names.push(
+                    bindings
+                        .iter()
+                        .map(|binding| binding.name.clone())
+                        .collect(),
+                );
+                let names = index(names.len() - 1);
+                self.tasks.push(Task::Emit(Op::Attrs(names), node));
+                self.tasks.extend(
+                    bindings
+                        .iter()
+                        .rev()
+                        .map(|binding| Task::Delay(&binding.value, node)),
+                );
+            }
+
+            ExprKind::Attrs {
+                recursive: true,
+                bindings,
+            } => {
+                let table = self.recursive(bindings);
+                self.chunk().emit(Op::RecAttrs(table), node);
+            }
+
+            ExprKind::Let { bindings, body } => {
+                let table = self.recursive(bindings);
+                self.chunk().emit(Op::PushLet(table), node);
+                self.tasks.push(Task::Emit(Op::PopEnv, node));
+                self.tasks.push(Task::Compile(body, Some(node)));
+            }
+
+            ExprKind::Interpolated { parts, path } => {
+                let len = index(parts.len());
+                let op = if *path {
+                    Op::InterpolatePath(len)
+                } else {
+                    Op::Interpolate(len)
+                };
+                self.tasks.push(Task::Emit(op, node));
+                for part in parts.iter().rev() {
+                    match part {
+                        Part::Literal(s) => {
+                            let constant = self.constant(Value::String(NixString::new(s.as_str())));
+                            self.tasks.push(Task::Emit(Op::Constant(constant), node));
+                        }
+                        Part::Interpolation(expr) => {
+                            self.tasks.push(Task::Emit(Op::CheckString, node));
+                            self.tasks.push(Task::Compile(expr, Some(node)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the table of a `let` or recursive set, scheduling the compilation of the
+    /// bindings that aren't constants.
+    fn recursive(&mut self, bindings: &'a [Binding]) -> u32 {
+        let table = index(self.chunk().recursive.len());
+        let mut entries = Vec::with_capacity(bindings.len());
+        for (position, binding) in bindings.iter().enumerate() {
+            let value = match &binding.value.kind {
+                ExprKind::Literal(value) => Delayed::Constant(self.constant(value.clone())),
+                _ => {
+                    let target = Target::Binding {
+                        table,
+                        index: position,
+                    };
+                    self.nested(&binding.value, target);
+                    // Filled in once the chunk has been compiled.
+                    Delayed::Thunk(u32::MAX)
+                }
+            };
+            entries.push(RecBinding {
+                name: binding.name.clone(),
+                value,
+                inherited: binding.inherited,
+            });
+        }
+        self.chunk().recursive.push(entries);
+        table
+    }
+}
+
+/// Narrows an index to the width instructions store it in.
+fn index(index: usize) -> u32 {
+    u32::try_from(index).expect("chunks have fewer than 2^32 entries")
+}
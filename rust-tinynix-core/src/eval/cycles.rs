@@ -0,0 +1,251 @@
+//! Reclaims the reference cycles recursive bindings leave behind.
+//!
+//! Values are reference counted, and the only way for them to refer back to themselves
+//! is through the thunks of `let` bindings and `rec` sets, which live in the very
+//! environment they are evaluated in. A binding that is never forced keeps that
+//! environment alive from within, and so does a function bound by a `let`. Such thunks
+//! are remembered as candidates, and once enough of them have piled up, everything
+//! reachable from them is checked by trial deletion: whatever is referenced only from
+//! within that graph can't be reached anymore, and its cycles are cut at the
+//! environments of unforced thunks and functions.
+//!
+//! Values that contain themselves without an environment in between, like the list in
+//! `let xs = [ xs ]; in xs`, can't be cut and are not reclaimed.
+
+use super::env::{Bindings, Env};
+use crate::value::attrs::NodeRef;
+use crate::value::{Lambda, PrimOp, Thunk, Value, WeakThunk};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// How many candidates may pile up before the first collection.
+const MIN_COLLECTION: usize = 1024;
+
+/// The thunks of recursive bindings, which may be part of reference cycles.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Knots {
+    thunks: Vec<WeakThunk>,
+    /// How many candidates were still alive after the last collection.
+    survivors: usize,
+}
+
+impl Knots {
+    pub fn add(&mut self, thunk: &Thunk) {
+        self.thunks.push(thunk.downgrade());
+    }
+
+    /// Collects once the candidates have doubled since the last collection, so that
+    /// the candidates surviving many collections don't make each of them slow.
+    pub fn maybe_collect(&mut self) {
+        if self.thunks.len() >= MIN_COLLECTION.max(2 * self.survivors) {
+            self.collect();
+        }
+    }
+
+    /// Frees the cycles that nothing outside of them refers to anymore.
+    pub fn collect(&mut self) {
+        let roots = self.thunks.iter().filter_map(WeakThunk::upgrade);
+        cut_unreachable(roots.map(Object::Thunk));
+        self.thunks.retain(|thunk| thunk.upgrade().is_some());
+        self.survivors = self.thunks.len();
+    }
+}
+
+impl Drop for Knots {
+    fn drop(&mut self) {
+        self.collect();
+    }
+}
+
+/// A reference counted allocation that values can refer to.
+enum Object {
+    Thunk(Thunk),
+    Env(Env),
+    Lambda(Rc<Lambda>),
+    List(Rc<Vec<Value>>),
+    Attrs(NodeRef),
+    PrimOp(Rc<PrimOp>),
+}
+
+impl Object {
+    /// The allocation `value` refers to, if any.
+    fn of(value: &Value) -> Option<Object> {
+        match value {
+            Value::Thunk(thunk) => Some(Object::Thunk(thunk.clone())),
+            Value::List(items) => Some(Object::List(items.clone())),
+            Value::Attrs(attrs) => attrs.root().map(Object::Attrs),
+            Value::Lambda(lambda) => Some(Object::Lambda(lambda.clone())),
+            Value::Primop(primop) => Some(Object::PrimOp(primop.clone())),
+            _ => None,
+        }
+    }
+
+    fn as_ptr(&self) -> *const () {
+        match self {
+            Object::Thunk(thunk) => thunk.as_ptr(),
+            Object::Env(env) => Rc::as_ptr(env).cast(),
+            Object::Lambda(lambda) => Rc::as_ptr(lambda).cast(),
+            Object::List(items) => Rc::as_ptr(items).cast(),
+            Object::Attrs(node) => node.as_ptr(),
+            Object::PrimOp(primop) => Rc::as_ptr(primop).cast(),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Thunk(thunk) => thunk.strong_count(),
+            Object::Env(env) => Rc::strong_count(env),
+            Object::Lambda(lambda) => Rc::strong_count(lambda),
+            Object::List(items) => Rc::strong_count(items),
+            Object::Attrs(node) => node.strong_count(),
+            Object::PrimOp(primop) => Rc::strong_count(primop),
+        }
+    }
+
+    /// The allocations this one holds a reference to.
+    fn references(&self) -> Vec<Object> {
+        match self {
+            Object::Thunk(thunk) => {
+                let value = thunk.value().and_then(Object::of);
+                value
+                    .into_iter()
+                    .chain(thunk.suspended_env().map(Object::Env))
+                    .collect()
+            }
+            Object::Env(env) => {
+                let mut references: Vec<_> = match env.bindings() {
+                    Bindings::Slots(slots) => slots.iter().filter_map(Object::of).collect(),
+                    Bindings::With(attrs) => attrs.root().map(Object::Attrs).into_iter().collect(),
+                };
+                references.extend(env.outer().cloned().map(Object::Env));
+                references
+            }
+            Object::Lambda(lambda) => {
+                let env = lambda.env.borrow().clone();
+                env.map(Object::Env).into_iter().collect()
+            }
+            Object::List(items) => items.iter().filter_map(Object::of).collect(),
+            Object::Attrs(node) => Object::of(node.value())
+                .into_iter()
+                .chain(node.children().map(Object::Attrs))
+                .collect(),
+            Object::PrimOp(primop) => primop.args.iter().filter_map(Object::of).collect(),
+        }
+    }
+
+    /// Drops the environment of an unreachable thunk or function, which breaks every
+    /// cycle through it.
+    fn cut(&self) {
+        match self {
+            Object::Thunk(thunk) => drop(thunk.take_suspended()),
+            Object::Lambda(lambda) => drop(lambda.env.borrow_mut().take()),
+            _ => {}
+        }
+    }
+}
+
+/// Everything reachable from the candidates, each object held exactly once.
+#[derive(Default)]
+struct Graph {
+    objects: Vec<Object>,
+    indices: HashMap<*const (), usize>,
+    /// The objects each object refers to.
+    edges: Vec<Vec<usize>>,
+    /// How many references to each object come from within the graph.
+    internal: Vec<usize>,
+}
+
+impl Graph {
+    /// The index of `object`, adding it to `pending` when it is new.
+    fn index(&mut self, object: Object, pending: &mut Vec<usize>) -> usize {
+        *self.indices.entry(object.as_ptr()).or_insert_with(|| {
+            self.objects.push(object);
+            self.edges.push(Vec::new());
+            self.internal.push(0);
+            pending.push(self.objects.len() - 1);
+            self.objects.len() - 1
+        })
+    }
+}
+
+/// Cuts the cycles among everything reachable from `roots` that is not referenced from
+/// anywhere else.
+fn cut_unreachable(roots: impl Iterator<Item = Object>) {
+    let mut graph = Graph::default();
+    let mut pending = Vec::new();
+    for root in roots {
+        graph.index(root, &mut pending);
+    }
+    while let Some(source) = pending.pop() {
+        for reference in graph.objects[source].references() {
+            let target = graph.index(reference, &mut pending);
+            graph.edges[source].push(target);
+            graph.internal[target] += 1;
+        }
+    }
+
+    // Besides the references from within, the graph holds one to each object itself.
+    // Anything referenced more often is reachable from outside, and so is everything
+    // it refers to.
+    let Graph {
+        objects,
+        edges,
+        internal,
+        ..
+    } = graph;
+    let mut reachable = vec![false; objects.len()];
+    let mut pending: Vec<usize> = (0..objects.len())
+        .filter(|&index| objects[index].strong_count() > internal[index] + 1)
+        .collect();
+    while let Some(index) = pending.pop() {
+        if !std::mem::replace(&mut reachable[index], true) {
+            pending.extend(&edges[index]);
+        }
+    }
+    for (object, reachable) in objects.iter().zip(reachable) {
+        if !reachable {
+            object.cut();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval::{Backend, EvalContext, Scope, nix_eval_ast};
+    use crate::parser;
+    use crate::span::FileId;
+    use crate::value::Value;
+    use std::path::Path;
+
+    fn eval(source: &str, ctx: &EvalContext) -> Value {
+        let ast = parser::parse_ast(source, Path::new("."), FileId::UNKNOWN).unwrap();
+        nix_eval_ast(&ast, &Scope::new(), ctx).unwrap()
+    }
+
+    #[test]
+    fn unreachable_knots_are_freed() {
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let ctx = EvalContext {
+                backend,
+                ..Default::default()
+            };
+            eval("let a = 1 + 1; b = a + 1; f = x: x; in f a", &ctx);
+            ctx.knots.borrow_mut().collect();
+            assert!(ctx.knots.borrow().thunks.is_empty());
+        }
+    }
+
+    #[test]
+    fn reachable_functions_keep_their_scope() {
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let ctx = EvalContext {
+                backend,
+                ..Default::default()
+            };
+            let function = eval("let n = 40; f = x: x + n + 2; in f", &ctx);
+            ctx.knots.borrow_mut().collect();
+            let result = crate::eval::call(function, vec![Value::Int(0)], Default::default(), &ctx);
+            assert!(matches!(result, Ok(Value::Int(42))));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use super::Scope;
+use crate::symbol::Symbol;
+use crate::value::{AttrSet, Value};
+use std::rc::Rc;
+
+/// A shared, immutable scope. Entering a `let`, `rec` set, lambda or `with` links a new
+/// frame to the one around it instead of copying it.
+pub(crate) type Env = Rc<Environment>;
+
+/// The bindings introduced by one construct, together with the environment it appears
+/// in.
+pub(crate) struct Environment {
+    bindings: Bindings,
+    parent: Option<Env>,
+}
+
+pub(crate) enum Bindings {
+    /// The variables of a `let`, `rec` set, lambda or the root scope, in the order they
+    /// are declared. Variables are resolved to their slot before evaluation.
+    Slots(Vec<Value>),
+    /// The attributes brought into scope by a `with`, which can only be looked up by
+    /// name.
+    With(AttrSet),
+}
+
+impl Environment {
+    /// The outermost environment of an evaluation, with the variables of `scope` in its
+    /// slots.
+    pub fn root(scope: Scope) -> Env {
+        Rc::new(Environment {
+            bindings: Bindings::Slots(scope.into_values().collect()),
+            parent: None,
+        })
+    }
+
+    /// A frame with the variables `slots` on top of `parent`.
+    pub fn extend(parent: &Env, slots: Vec<Value>) -> Env {
+        Rc::new(Environment {
+            bindings: Bindings::Slots(slots),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    /// A frame for the body of a `with`, on top of `parent`.
+    pub fn extend_with(parent: &Env, attrs: AttrSet) -> Env {
+        Rc::new(Environment {
+            bindings: Bindings::With(attrs),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    /// What the innermost frame binds.
+    pub fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+
+    /// The environment around the innermost frame, unless it is the root.
+    pub fn outer(&self) -> Option<&Env> {
+        self.parent.as_ref()
+    }
+
+    /// The environment around the innermost frame.
+    pub fn parent(&self) -> &Env {
+        self.parent.as_ref().expect("the root frame is never left")
+    }
+
+    /// The variables of the innermost frame, which must not be a `with`.
+    pub fn slots(&self) -> &[Value] {
+        match &self.bindings {
+            Bindings::Slots(slots) => slots,
+            Bindings::With(_) => unreachable!("`with` frames have no slots"),
+        }
+    }
+
+    /// The variable in `slot` of the frame `depth` frames out.
+    pub fn get(&self, depth: usize, slot: usize) -> &Value {
+        let mut env = self;
+        for _ in 0..depth {
+            env = env
+                .parent
+                .as_deref()
+                .expect("variables resolve to enclosing frames");
+        }
+        &env.slots()[slot]
+    }
+
+    /// Looks `name` up in the `with` frames, innermost first, stopping before the frame
+    /// `depth` frames out if given.
+    pub fn get_dynamic(&self, name: &Symbol, depth: Option<usize>) -> Option<&Value> {
+        std::iter::successors(Some(self), |env| env.parent.as_deref())
+            .take(depth.unwrap_or(usize::MAX))
+            .find_map(|env| match &env.bindings {
+                Bindings::With(attrs) => attrs.get(name),
+                Bindings::Slots(_) => None,
+            })
+    }
+
+    /// Every name brought into scope by a `with`, for suggestions.
+    pub fn with_names(&self) -> impl Iterator<Item = &Symbol> {
+        std::iter::successors(Some(self), |env| env.parent.as_deref())
+            .filter_map(|env| match &env.bindings {
+                Bindings::With(attrs) => Some(attrs.keys()),
+                Bindings::Slots(_) => None,
+            })
+            .flatten()
+    }
+
+    /// Moves the values out of the frames of `env` that nothing else shares, so they
+    /// can be dropped without recursion.
+    pub fn take_values(env: &mut Env) -> Vec<Value> {
+        let mut values = Vec::new();
+        let mut current = Rc::get_mut(env);
+        while let Some(env) = current {
+            match &mut env.bindings {
+                Bindings::Slots(slots) => values.append(slots),
+                Bindings::With(attrs) => values.extend(attrs.take_values()),
+            }
+            current = env.parent.as_mut().and_then(Rc::get_mut);
+        }
+        values
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        // Long chains of frames would overflow the stack with the derived drop glue.
+        let mut parent = self.parent.take();
+        while let Some(env) = parent {
+            parent = Rc::into_inner(env).and_then(|mut env| env.parent.take());
+        }
+    }
+}
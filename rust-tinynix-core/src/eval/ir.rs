@@ -0,0 +1,445 @@
+use super::{EvaluationError, Scope};
+use crate::ast::{Ast, Binding as AstBinding, ExprId, ExprKind as AstKind, StringPart};
+use crate::builtins;
+use crate::diagnostics::suggest_similar;
+use crate::parser::split_attr_path;
+use crate::span::Span;
+use crate::symbol::{Symbol, SymbolTable};
+use crate::value::Value;
+use crate::{NixBinaryOp, NixUnaryOp};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The form expressions are evaluated in. Unlike in an [`Ast`], subexpressions are shared
+/// through reference counting, so thunks and closures can hold on to them.
+pub(crate) struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+pub(crate) enum ExprKind {
+    Literal(Value),
+    /// A variable, with the attributes selected from it, e.g. `config.services`.
+    Var {
+        name: Symbol,
+        attrs: Vec<Symbol>,
+        address: Address,
+    },
+    /// A string, or with `path` a path, put together from its parts.
+    Interpolated {
+        parts: Vec<Part>,
+        path: bool,
+    },
+    List(Vec<Rc<Expr>>),
+    Attrs {
+        recursive: bool,
+        bindings: Vec<Binding>,
+    },
+    Let {
+        bindings: Vec<Binding>,
+        body: Rc<Expr>,
+    },
+    Lambda {
+        param: Symbol,
+        body: Rc<Expr>,
+    },
+    Unary {
+        op: NixUnaryOp,
+        operand: Rc<Expr>,
+    },
+    Binary {
+        op: NixBinaryOp,
+        left: Rc<Expr>,
+        right: Rc<Expr>,
+    },
+    SearchPath(String),
+    With {
+        environment: Rc<Expr>,
+        body: Rc<Expr>,
+    },
+    Assert {
+        condition: Rc<Expr>,
+        body: Rc<Expr>,
+    },
+    Apply {
+        function: Rc<Expr>,
+        argument: Rc<Expr>,
+    },
+    Error,
+}
+
+pub(crate) enum Part {
+    Literal(String),
+    Interpolation(Rc<Expr>),
+}
+
+pub(crate) struct Binding {
+    pub name: Symbol,
+    pub value: Rc<Expr>,
+    /// Whether the binding is an `inherit`, which is resolved in the scope around the
+    /// bindings rather than among them.
+    pub inherited: bool,
+}
+
+/// Where a variable is bound, as far as it can be told before evaluation.
+#[derive(Clone, Copy)]
+pub(crate) enum Address {
+    Static(Binder),
+    /// Under `with`, whose sets are searched first, innermost first. The binder is what
+    /// the name resolves to if none of them has it.
+    With(Option<Binder>),
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Binder {
+    /// In `slot` of the frame `depth` frames out.
+    Local { depth: usize, slot: usize },
+    /// A builtin that is reachable without the `builtins.` prefix.
+    Global,
+}
+
+/// The variables bound by an enclosing construct, for resolving references before
+/// evaluation. Mirrors the frames of [`super::env::Environment`] one to one.
+struct StaticScope {
+    kind: ScopeKind,
+    parent: Option<Rc<StaticScope>>,
+}
+
+enum ScopeKind {
+    Slots(HashMap<Symbol, usize>),
+    With,
+}
+
+impl StaticScope {
+    fn new(names: impl IntoIterator<Item = Symbol>, parent: Option<&Rc<StaticScope>>) -> Rc<Self> {
+        let slots = names
+            .into_iter()
+            .enumerate()
+            .map(|(slot, name)| (name, slot))
+            .collect();
+        Rc::new(StaticScope {
+            kind: ScopeKind::Slots(slots),
+            parent: parent.cloned(),
+        })
+    }
+
+    fn with(parent: &Rc<StaticScope>) -> Rc<Self> {
+        Rc::new(StaticScope {
+            kind: ScopeKind::With,
+            parent: Some(parent.clone()),
+        })
+    }
+
+    fn resolve(&self, name: &Symbol) -> Option<Address> {
+        let mut under_with = false;
+        let scopes = std::iter::successors(Some(self), |scope| scope.parent.as_deref());
+        for (depth, scope) in scopes.enumerate() {
+            match &scope.kind {
+                ScopeKind::Slots(slots) => {
+                    if let Some(&slot) = slots.get(name) {
+                        let binder = Binder::Local { depth, slot };
+                        return Some(if under_with {
+                            Address::With(Some(binder))
+                        } else {
+                            Address::Static(binder)
+                        });
+                    }
+                }
+                ScopeKind::With => under_with = true,
+            }
+        }
+        let global = builtins::global_names().any(|global| global == name.as_str());
+        match (global, under_with) {
+            (true, false) => Some(Address::Static(Binder::Global)),
+            (true, true) => Some(Address::With(Some(Binder::Global))),
+            (false, true) => Some(Address::With(None)),
+            (false, false) => None,
+        }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &Symbol> {
+        std::iter::successors(Some(self), |scope| scope.parent.as_deref())
+            .filter_map(|scope| match &scope.kind {
+                ScopeKind::Slots(slots) => Some(slots.keys()),
+                ScopeKind::With => None,
+            })
+            .flatten()
+    }
+}
+
+/// Converts a syntax tree into the evaluated form, without recursing on the native stack.
+/// Identifiers and attribute names are interned in `symbols` on the way, and every
+/// variable is resolved against the enclosing bindings and the variables of `root`, so
+/// references to undefined variables are reported before anything is evaluated.
+pub(crate) fn lower(
+    ast: &Ast,
+    root: &Scope,
+    symbols: &mut SymbolTable,
+) -> Result<Rc<Expr>, EvaluationError> {
+    enum Task {
+        Visit(ExprId, Rc<StaticScope>),
+        Build(ExprId, usize, Rc<StaticScope>),
+    }
+    let root = StaticScope::new(root.keys().map(|name| symbols.intern(name)), None);
+    let mut tasks = vec![Task::Visit(ast.root(), root)];
+    let mut lowered: Vec<Rc<Expr>> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(id, scope) => {
+                let children = ast.children(id);
+                let scopes = child_scopes(ast, id, &scope, symbols);
+                tasks.push(Task::Build(id, children.len(), scope));
+                tasks.extend(
+                    children
+                        .into_iter()
+                        .zip(scopes)
+                        .rev()
+                        .map(|(child, scope)| Task::Visit(child, scope)),
+                );
+            }
+            Task::Build(id, len, scope) => {
+                let children = lowered.split_off(lowered.len() - len);
+                let expr = &ast[id];
+                let kind = lower_kind(ast, &expr.kind, children, &scope, symbols)
+                    .map_err(|error| error.at(expr.span))?;
+                lowered.push(Rc::new(Expr {
+                    kind,
+                    span: expr.span,
+                }));
+            }
+        }
+    }
+    Ok(lowered.pop().expect("the root has been lowered"))
+}
+
+/// The scopes the children of `id` are resolved in, in the order of [`Ast::children`].
+fn child_scopes(
+    ast: &Ast,
+    id: ExprId,
+    scope: &Rc<StaticScope>,
+    symbols: &mut SymbolTable,
+) -> Vec<Rc<StaticScope>> {
+    let bindings_scopes = |bindings: &[AstBinding], inner: &Rc<StaticScope>| {
+        bindings
+            .iter()
+            .map(|binding| match ast[binding.value].kind {
+                // Inherited bindings are looked up around the bindings.
+                AstKind::Inherit(_) => scope.clone(),
+                _ => inner.clone(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut binding_names = |bindings: &[AstBinding]| {
+        StaticScope::new(
+            bindings.iter().map(|binding| symbols.intern(&binding.name)),
+            Some(scope),
+        )
+    };
+    match &ast[id].kind {
+        AstKind::LetIn { bindings, .. } => {
+            let bindings = ast.bindings(*bindings);
+            let inner = binding_names(bindings);
+            let mut scopes = bindings_scopes(bindings, &inner);
+            scopes.push(inner);
+            scopes
+        }
+        AstKind::AttrSet {
+            recursive: true,
+            bindings,
+        } => {
+            let bindings = ast.bindings(*bindings);
+            bindings_scopes(bindings, &binding_names(bindings))
+        }
+        AstKind::Lambda { param, .. } => {
+            vec![StaticScope::new([symbols.intern(param)], Some(scope))]
+        }
+        AstKind::With { .. } => vec![scope.clone(), StaticScope::with(scope)],
+        _ => vec![scope.clone(); ast.children(id).len()],
+    }
+}
+
+/// Lowers a single node, given its already lowered children in the order of
+/// [`Ast::children`].
+fn lower_kind(
+    ast: &Ast,
+    kind: &AstKind,
+    children: Vec<Rc<Expr>>,
+    scope: &StaticScope,
+    symbols: &mut SymbolTable,
+) -> Result<ExprKind, EvaluationError> {
+    let mut children = children.into_iter();
+    let mut next = || children.next().expect("one child per nested expression");
+    Ok(match kind {
+        AstKind::Value(literal) => ExprKind::Literal(Value::from(literal)),
+        AstKind::Ref(name) => lower_var(name, scope, symbols)?,
+        AstKind::Inherit(name) => lower_var(name, scope, symbols)?,
+        AstKind::InterpolatedString(parts) | AstKind::InterpolatedPath(parts) => {
+            ExprKind::Interpolated {
+                parts: ast
+                    .parts(*parts)
+                    .iter()
+                    .map(|part| match part {
+                        StringPart::Literal(s) => Part::Literal(s.to_string()),
+                        StringPart::Interpolation(_) => Part::Interpolation(next()),
+                    })
+                    .collect(),
+                path: matches!(kind, AstKind::InterpolatedPath(_)),
+            }
+        }
+        AstKind::List(_) => ExprKind::List(children.collect()),
+        AstKind::AttrSet {
+            recursive,
+            bindings,
+        } => ExprKind::Attrs {
+            recursive: *recursive,
+            bindings: lower_bindings(ast, ast.bindings(*bindings), &mut children, symbols),
+        },
+        AstKind::LetIn { bindings, .. } => ExprKind::Let {
+            bindings: lower_bindings(ast, ast.bindings(*bindings), &mut children, symbols),
+            body: children.next().expect("a let has a body"),
+        },
+        AstKind::Lambda { param, .. } => ExprKind::Lambda {
+            param: symbols.intern(param),
+            body: next(),
+        },
+        AstKind::UnaryOp { op, .. } => ExprKind::Unary {
+            op: *op,
+            operand: next(),
+        },
+        AstKind::BinaryOp { op, .. } => ExprKind::Binary {
+            op: *op,
+            left: next(),
+            right: next(),
+        },
+        AstKind::SearchPath(lookup) => ExprKind::SearchPath(lookup.to_string()),
+        AstKind::With { .. } => ExprKind::With {
+            environment: next(),
+            body: next(),
+        },
+        AstKind::Assert { .. } => ExprKind::Assert {
+            condition: next(),
+            body: next(),
+        },
+        AstKind::Apply { .. } => ExprKind::Apply {
+            function: next(),
+            argument: next(),
+        },
+        AstKind::Error => ExprKind::Error,
+    })
+}
+
+/// Resolves a reference such as `config.services`, which selects attributes from the
+/// variable `config`.
+fn lower_var(
+    name: &str,
+    scope: &StaticScope,
+    symbols: &mut SymbolTable,
+) -> Result<ExprKind, EvaluationError> {
+    let mut path: Vec<Symbol> = split_attr_path(name)
+        .iter()
+        .map(|part| symbols.intern(part))
+        .collect();
+    let name = path.remove(0);
+    let address = scope
+        .resolve(&name)
+        .ok_or_else(|| EvaluationError::UndefinedVariable {
+            name: name.to_string(),
+            suggestions: suggest_similar(
+                name.as_str(),
+                scope
+                    .names()
+                    .map(Symbol::as_str)
+                    .chain(builtins::global_names()),
+            ),
+        })?;
+    Ok(ExprKind::Var {
+        name,
+        attrs: path,
+        address,
+    })
+}
+
+fn lower_bindings(
+    ast: &Ast,
+    bindings: &[AstBinding],
+    children: &mut impl Iterator<Item = Rc<Expr>>,
+    symbols: &mut SymbolTable,
+) -> Vec<Binding> {
+    bindings
+        .iter()
+        .zip(children)
+        .map(|(binding, lowered)| Binding {
+            name: symbols.intern(&binding.name),
+            value: lowered,
+            inherited: matches!(ast[binding.value].kind, AstKind::Inherit(_)),
+        })
+        .collect()
+}
+
+impl Expr {
+    /// Moves the subexpressions out of this one, as far as nothing else shares them.
+    fn take_children(&mut self) -> Vec<Expr> {
+        let placeholder = || {
+            Rc::new(Expr {
+                kind: ExprKind::Error,
+                span: Span::default(),
+            })
+        };
+        let children: Vec<Rc<Expr>> = match &mut self.kind {
+            ExprKind::List(items) => std::mem::take(items),
+            ExprKind::Interpolated { parts, .. } => std::mem::take(parts)
+                .into_iter()
+                .filter_map(|part| match part {
+                    Part::Interpolation(expr) => Some(expr),
+                    Part::Literal(_) => None,
+                })
+                .collect(),
+            ExprKind::Attrs { bindings, .. } => std::mem::take(bindings)
+                .into_iter()
+                .map(|binding| binding.value)
+                .collect(),
+            ExprKind::Let { bindings, body } => std::mem::take(bindings)
+                .into_iter()
+                .map(|binding| binding.value)
+                .chain([std::mem::replace(body, placeholder())])
+                .collect(),
+            ExprKind::Lambda { body: expr, .. } | ExprKind::Unary { operand: expr, .. } => {
+                vec![std::mem::replace(expr, placeholder())]
+            }
+            ExprKind::Binary {
+                left: first,
+                right: second,
+                ..
+            }
+            | ExprKind::With {
+                environment: first,
+                body: second,
+            }
+            | ExprKind::Assert {
+                condition: first,
+                body: second,
+            }
+            | ExprKind::Apply {
+                function: first,
+                argument: second,
+            } => vec![
+                std::mem::replace(first, placeholder()),
+                std::mem::replace(second, placeholder()),
+            ],
+            ExprKind::Literal(_)
+            | ExprKind::Var { .. }
+            | ExprKind::SearchPath(_)
+            | ExprKind::Error => Vec::new(),
+        };
+        children.into_iter().filter_map(Rc::into_inner).collect()
+    }
+}
+
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut pending = self.take_children();
+        while let Some(mut expr) = pending.pop() {
+            pending.append(&mut expr.take_children());
+        }
+    }
+}
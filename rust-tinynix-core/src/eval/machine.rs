@@ -0,0 +1,661 @@
+use super::env::{Env, Environment};
+use super::ir::{Address, Binder, Binding, Expr, ExprKind, Part};
+use super::{
+    CycleMember, EvalContext, EvaluationError, TraceFrameKind, binary_op, lookup, search_path,
+    unary_op, vm,
+};
+use crate::builtins;
+use crate::diagnostics::suggest_similar;
+use crate::span::Span;
+use crate::symbol::Symbol;
+use crate::value::{Code, Lambda, NixString, Origin, PrimOp, Suspended, Thunk, Value};
+use crate::{NixBinaryOp, NixUnaryOp};
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Evaluates `expr` to weak head normal form: the outermost constructor of the result
+/// is known, but its elements and attributes may still be unforced thunks.
+pub(crate) fn eval_shallow(
+    expr: Rc<Expr>,
+    env: Env,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    run(vec![Frame::Eval(expr, env)], ctx)
+}
+
+/// Evaluates `expr` and forces everything it contains.
+pub(crate) fn eval_deep(
+    expr: Rc<Expr>,
+    env: Env,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    run(vec![Frame::DeepForceResult, Frame::Eval(expr, env)], ctx)
+}
+
+/// Forces everything an already evaluated value contains.
+pub(crate) fn force_deep(value: Value, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    run(vec![Frame::DeepForceResult, Frame::Force(value)], ctx)
+}
+
+/// Forces a thunk to weak head normal form.
+pub(crate) fn force(value: Value, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    run(vec![Frame::Force(value)], ctx)
+}
+
+/// Applies a function to a single argument, without forcing the result.
+pub(crate) fn apply(
+    function: Value,
+    argument: Value,
+    span: Span,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    run(
+        vec![Frame::Apply { argument, span }, Frame::Force(function)],
+        ctx,
+    )
+}
+
+/// Applies `function` to `args` one at a time and forces the result deeply.
+pub(crate) fn call(
+    function: Value,
+    args: Vec<Value>,
+    span: Span,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let mut frames = vec![Frame::DeepForceResult];
+    frames.extend(
+        args.into_iter()
+            .rev()
+            .map(|argument| Frame::Apply { argument, span }),
+    );
+    frames.push(Frame::Force(function));
+    run(frames, ctx).map_err(|error| error.at(span))
+}
+
+/// Runs the frames on an explicit stack rather than the native one, so nesting is
+/// bounded by the heap instead of the thread's stack size.
+fn run(frames: Vec<Frame>, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    let mut machine = Machine {
+        ctx,
+        frames,
+        values: Vec::new(),
+        deeply_forced: HashSet::new(),
+    };
+    while let Some(frame) = machine.frames.pop() {
+        if let Err(error) = machine.step(frame) {
+            return Err(machine.unwind(error));
+        }
+    }
+    Ok(machine.values.pop().expect("evaluation produced a value"))
+}
+
+struct Machine<'c> {
+    ctx: &'c EvalContext,
+    /// The work still to be done, innermost last.
+    frames: Vec<Frame>,
+    /// Forced values of subexpressions, waiting for their parents.
+    values: Vec<Value>,
+    /// The lists and sets deep forcing has already visited, so cyclic values end.
+    deeply_forced: HashSet<*const ()>,
+}
+
+enum Frame {
+    /// Evaluates an expression and pushes its value.
+    Eval(Rc<Expr>, Env),
+    /// Pushes the value, forcing it first if it is a thunk.
+    Force(Value),
+    /// Stores the value on top of the stack in a thunk being forced.
+    Update(Thunk, Suspended),
+    /// Leaves the expression at `span`, whose value is on top of the value stack.
+    Exit(Span),
+    /// Adds `kind` at `span` to the trace of errors raised by the frames above.
+    Context(TraceFrameKind, Span),
+    /// Adds the call at `span` of the lambda defined at `lambda` to the trace of errors
+    /// raised by the frames above.
+    Call {
+        lambda: Span,
+        span: Span,
+    },
+    Unary(NixUnaryOp),
+    Binary(NixBinaryOp),
+    /// Applies the function on top of the stack to `argument`.
+    Apply {
+        argument: Value,
+        span: Span,
+    },
+    /// Calls a saturated builtin with the arguments on top of the stack.
+    CallPrimop {
+        name: &'static str,
+        arity: usize,
+        span: Span,
+    },
+    /// Selects the attributes of a variable from `depth` on from the value on top of
+    /// the stack.
+    Select {
+        var: Rc<Expr>,
+        depth: usize,
+    },
+    With {
+        body: Rc<Expr>,
+        env: Env,
+    },
+    Assert {
+        condition: Span,
+        body: Rc<Expr>,
+        env: Env,
+    },
+    /// Appends the interpolation on top of the stack and goes on with part `next`.
+    Interpolate {
+        string: Rc<Expr>,
+        next: usize,
+        result: String,
+        context: BTreeSet<String>,
+        env: Env,
+    },
+    /// Forces the value and everything it contains, leaving the stack as it is.
+    DeepForce(Value),
+    /// Like [`Frame::DeepForce`] for the attribute `name` of a set, tracing failures
+    /// to it while it is being forced.
+    DeepForceAttribute(Symbol, Value),
+    /// Deep forces the value on top of the stack, which has just been forced.
+    DeepForceForced,
+    /// Deep forces the result, leaving it on the stack.
+    DeepForceResult,
+}
+
+impl Machine<'_> {
+    fn step(&mut self, frame: Frame) -> Result<(), EvaluationError> {
+        match frame {
+            Frame::Eval(expr, env) => self.eval(expr, env)?,
+            Frame::Force(value) => self.force(value)?,
+            Frame::Update(thunk, _) => {
+                let value = self.values.last().expect("a forced thunk has a value");
+                thunk.set_value(value.clone());
+            }
+            Frame::Exit(span) => {
+                self.ctx.depth.set(self.ctx.depth.get() - 1);
+                let value = self
+                    .values
+                    .last()
+                    .expect("an exited expression has a value");
+                self.ctx
+                    .limits
+                    .check_value(value)
+                    .map_err(|error| error.at(span))?;
+            }
+            Frame::Context(..) | Frame::Call { .. } => {}
+            Frame::Unary(op) => {
+                let value = self.pop();
+                let result = unary_op(op, &value)?;
+                self.values.push(result);
+            }
+            Frame::Binary(op) => {
+                let right = self.pop();
+                let left = self.pop();
+                let result = binary_op(op, &left, &right)?;
+                self.values.push(result);
+            }
+            Frame::Apply { argument, span } => self.apply(argument, span)?,
+            Frame::CallPrimop { name, arity, span } => {
+                let args = self.values.split_off(self.values.len() - arity);
+                let result = builtins::call(name, args, self.ctx).map_err(|error| {
+                    error.in_frame(TraceFrameKind::Call(name.to_string()), span)
+                })?;
+                self.values.push(result);
+            }
+            Frame::Select { var, depth } => self.select(var, depth)?,
+            Frame::With { body, env } => {
+                let environment = self.pop();
+                let Value::Attrs(attrs) = &environment else {
+                    return Err(EvaluationError::type_mismatch("a set", &environment));
+                };
+                let env = Environment::extend_with(&env, attrs.clone());
+                self.frames.push(Frame::Eval(body, env));
+            }
+            Frame::Assert {
+                condition,
+                body,
+                env,
+            } => {
+                let value = self.pop();
+                match &value {
+                    Value::Bool(true) => self.frames.push(Frame::Eval(body, env)),
+                    Value::Bool(false) => {
+                        return Err(EvaluationError::AssertionFailed.at(condition));
+                    }
+                    _ => return Err(EvaluationError::type_mismatch("a Boolean", &value)),
+                }
+            }
+            Frame::Interpolate {
+                string,
+                next,
+                mut result,
+                mut context,
+                env,
+            } => {
+                let value = self.pop();
+                // This logic should be expanded to handle auto-coercion to string
+                let Value::String(s) = &value else {
+                    return Err(EvaluationError::type_mismatch("a string", &value));
+                };
+                result.push_str(s.as_str());
+                context.extend(s.context().iter().cloned());
+                self.interpolate(string, next, result, context, env);
+            }
+            Frame::DeepForce(value) => self.deep_force(value),
+            Frame::DeepForceAttribute(name, value) => {
+                if let Value::Thunk(thunk) = &value
+                    && thunk.value().is_none()
+                {
+                    let frame = TraceFrameKind::Attribute(name.to_string());
+                    self.frames.push(Frame::Context(frame, thunk.span()));
+                }
+                self.deep_force(value);
+            }
+            Frame::DeepForceForced => {
+                let value = self.pop();
+                self.deep_force(value);
+            }
+            Frame::DeepForceResult => {
+                let value = self.values.last().expect("a result to force").clone();
+                self.deep_force(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops frames down to the bottom of the stack, adding the context of each to `error`.
+    fn unwind(&mut self, mut error: EvaluationError) -> EvaluationError {
+        while let Some(frame) = self.frames.pop() {
+            match frame {
+                Frame::Exit(span) => {
+                    self.ctx.depth.set(self.ctx.depth.get() - 1);
+                    error = error.at(span);
+                }
+                Frame::Context(kind, span) => error = error.in_frame(kind, span),
+                Frame::Call { lambda, span } => {
+                    error = error.in_frame(TraceFrameKind::lambda(lambda, self.ctx), span)
+                }
+                // Forcing the thunk again raises the error again.
+                Frame::Update(thunk, suspended) => thunk.restore(suspended),
+                _ => {}
+            }
+        }
+        error
+    }
+
+    fn eval(&mut self, expr: Rc<Expr>, env: Env) -> Result<(), EvaluationError> {
+        let ctx = self.ctx;
+        let span = expr.span;
+        if ctx.cancellation.is_cancelled() {
+            return Err(EvaluationError::Cancelled.at(span));
+        }
+        let depth = ctx.depth.get() + 1;
+        let steps = ctx.steps.get() + 1;
+        ctx.limits
+            .check_step(depth, steps, ctx.deadline.get())
+            .map_err(|error| error.at(span))?;
+        ctx.depth.set(depth);
+        ctx.steps.set(steps);
+        self.frames.push(Frame::Exit(span));
+
+        match &expr.kind {
+            ExprKind::Literal(value) => self.values.push(value.clone()),
+
+            ExprKind::Var { name, address, .. } => {
+                let value = lookup(name, address, &env, ctx)?;
+                self.frames.push(Frame::Select {
+                    var: expr.clone(),
+                    depth: 0,
+                });
+                self.frames.push(Frame::Force(value));
+            }
+
+            ExprKind::Lambda { param, body } => {
+                let lambda = Lambda::new(param.clone(), Code::Expr(body.clone()), env, span);
+                self.values.push(Value::Lambda(Rc::new(lambda)));
+            }
+
+            ExprKind::Error => {
+                return Err(EvaluationError::UnsupportedOperation(
+                    "Cannot evaluate an expression that failed to parse.".to_string(),
+                ));
+            }
+
+            ExprKind::SearchPath(lookup) => self.values.push(search_path(lookup, ctx)?),
+
+            ExprKind::Unary { op, operand } => {
+                self.frames.push(Frame::Unary(*op));
+                self.frames.push(Frame::Eval(operand.clone(), env));
+            }
+
+            ExprKind::Binary { op, left, right } => {
+                self.frames.push(Frame::Binary(*op));
+                self.frames.push(Frame::Eval(right.clone(), env.clone()));
+                self.frames.push(Frame::Eval(left.clone(), env));
+            }
+
+            ExprKind::Apply { function, argument } => {
+                self.frames.push(Frame::Apply {
+                    argument: delay(argument, &env),
+                    span,
+                });
+                self.frames.push(Frame::Eval(function.clone(), env));
+            }
+
+            ExprKind::With { environment, body } => {
+                self.frames.push(Frame::With {
+                    body: body.clone(),
+                    env: env.clone(),
+                });
+                self.frames.push(Frame::Eval(environment.clone(), env));
+            }
+
+            ExprKind::Assert { condition, body } => {
+                self.frames.push(Frame::Assert {
+                    condition: condition.span,
+                    body: body.clone(),
+                    env: env.clone(),
+                });
+                self.frames.push(Frame::Eval(condition.clone(), env));
+            }
+
+            ExprKind::List(items) => {
+                let items = items.iter().map(|item| delay(item, &env)).collect();
+                self.values.push(Value::List(Rc::new(items)));
+            }
+
+            ExprKind::Attrs {
+                recursive: false,
+                bindings,
+            } => {
+                let attrs = bindings
+                    .iter()
+                    .map(|binding| (binding.name.clone(), delay(&binding.value, &env)))
+                    .collect();
+                self.values.push(Value::Attrs(attrs));
+            }
+
+            ExprKind::Attrs {
+                recursive: true,
+                bindings,
+            } => {
+                let env = recursive_env(bindings, env, Origin::Attribute, self.ctx);
+                let attrs = bindings
+                    .iter()
+                    .zip(env.slots())
+                    .map(|(binding, value)| (binding.name.clone(), value.clone()))
+                    .collect();
+                self.values.push(Value::Attrs(attrs));
+            }
+
+            ExprKind::Let { bindings, body } => {
+                let env = recursive_env(bindings, env, Origin::LetBinding, self.ctx);
+                self.frames.push(Frame::Eval(body.clone(), env));
+            }
+
+            ExprKind::Interpolated { .. } => {
+                self.interpolate(expr.clone(), 0, String::new(), BTreeSet::new(), env);
+            }
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Value {
+        self.values.pop().expect("an operand has been evaluated")
+    }
+
+    fn force(&mut self, value: Value) -> Result<(), EvaluationError> {
+        let Value::Thunk(thunk) = &value else {
+            self.values.push(value);
+            return Ok(());
+        };
+        if let Some(forced) = thunk.value() {
+            self.values.push(forced.clone());
+            return Ok(());
+        }
+        let Some(suspended) = thunk.take_suspended() else {
+            let in_progress = self.frames.iter().filter_map(|frame| match frame {
+                Frame::Update(thunk, _) => Some(thunk),
+                _ => None,
+            });
+            return Err(infinite_recursion(in_progress, thunk));
+        };
+        let Code::Expr(expr) = suspended.code.clone() else {
+            // Thunks of the virtual machine are forced by it.
+            thunk.restore(suspended);
+            self.values.push(vm::force(value.clone(), self.ctx)?);
+            return Ok(());
+        };
+        let env = suspended.env.clone();
+        let span = thunk.span();
+        let frame = match thunk.origin() {
+            Some(Origin::LetBinding(name)) => Some(TraceFrameKind::LetBinding(name.to_string())),
+            _ => None,
+        };
+        self.frames.push(Frame::Update(thunk.clone(), suspended));
+        if let Some(frame) = frame {
+            self.frames.push(Frame::Context(frame, span));
+        }
+        self.frames.push(Frame::Eval(expr, env));
+        Ok(())
+    }
+
+    fn apply(&mut self, argument: Value, span: Span) -> Result<(), EvaluationError> {
+        let function = self.pop();
+        match &function {
+            Value::Lambda(lambda) => match &lambda.body {
+                Code::Expr(body) => {
+                    let env = Environment::extend(&lambda.env(), vec![argument]);
+                    self.frames.push(Frame::Call {
+                        lambda: lambda.span(),
+                        span,
+                    });
+                    self.frames.push(Frame::Eval(body.clone(), env));
+                }
+                Code::Chunk(_) => {
+                    let result = vm::apply(function.clone(), argument, span, self.ctx)?;
+                    self.values.push(result);
+                }
+            },
+            Value::Primop(primop) => {
+                let mut args = primop.args.clone();
+                args.push(argument);
+                if args.len() < primop.arity {
+                    self.values.push(Value::Primop(Rc::new(PrimOp {
+                        name: primop.name,
+                        arity: primop.arity,
+                        args,
+                    })));
+                    return Ok(());
+                }
+                self.frames.push(Frame::CallPrimop {
+                    name: primop.name,
+                    arity: primop.arity,
+                    span,
+                });
+                let deep_args = builtins::deep_args(primop.name);
+                for (index, argument) in args.into_iter().enumerate().rev() {
+                    if deep_args.contains(&index) {
+                        self.frames.push(Frame::DeepForceResult);
+                    }
+                    self.frames.push(Frame::Force(argument));
+                }
+            }
+            _ => return Err(EvaluationError::type_mismatch("a function", &function)),
+        }
+        Ok(())
+    }
+
+    fn select(&mut self, var: Rc<Expr>, depth: usize) -> Result<(), EvaluationError> {
+        let ExprKind::Var { name, attrs, .. } = &var.kind else {
+            unreachable!("only variables select attributes");
+        };
+        let Some(attr) = attrs.get(depth) else {
+            return Ok(());
+        };
+        let value = self.pop();
+        let Value::Attrs(bindings) = &value else {
+            return Err(EvaluationError::type_mismatch("a set", &value));
+        };
+        let Some(selected) = bindings.get(attr) else {
+            let path: Vec<_> = std::iter::once(name)
+                .chain(&attrs[..depth])
+                .map(|part| part.as_str())
+                .collect();
+            return Err(EvaluationError::MissingAttribute {
+                attribute: attr.to_string(),
+                path: path.join("."),
+                suggestions: suggest_similar(attr.as_str(), bindings.keys().map(Symbol::as_str)),
+            });
+        };
+        let selected = selected.clone();
+        self.frames.push(Frame::Select {
+            var: var.clone(),
+            depth: depth + 1,
+        });
+        self.frames.push(Frame::Force(selected));
+        Ok(())
+    }
+
+    /// Appends the literal parts of a string from `next` on to `result`, up to the next
+    /// interpolation, which is scheduled for evaluation.
+    fn interpolate(
+        &mut self,
+        string: Rc<Expr>,
+        mut next: usize,
+        mut result: String,
+        context: BTreeSet<String>,
+        env: Env,
+    ) {
+        let ExprKind::Interpolated { parts, path } = &string.kind else {
+            unreachable!("only strings are interpolated");
+        };
+        while let Some(part) = parts.get(next) {
+            next += 1;
+            match part {
+                Part::Literal(s) => result.push_str(s),
+                Part::Interpolation(expr) => {
+                    let expr = expr.clone();
+                    self.frames.push(Frame::Interpolate {
+                        string,
+                        next,
+                        result,
+                        context,
+                        env: env.clone(),
+                    });
+                    self.frames.push(Frame::Eval(expr, env));
+                    return;
+                }
+            }
+        }
+        let value = if *path {
+            Value::Path(Rc::from(Path::new(&result)))
+        } else {
+            Value::String(NixString::with_context(result, context))
+        };
+        self.values.push(value);
+    }
+
+    /// Schedules forcing `value` and, recursively, the elements of lists and attributes
+    /// of sets. Failures inside sets are traced to the attribute they happened in.
+    fn deep_force(&mut self, value: Value) {
+        let Some(forced) = value.forced() else {
+            self.frames.push(Frame::DeepForceForced);
+            self.frames.push(Frame::Force(value));
+            return;
+        };
+        if let Some(identity) = forced.identity()
+            && !self.deeply_forced.insert(identity)
+        {
+            return;
+        }
+        match forced {
+            Value::List(items) => {
+                for item in items.iter().rev() {
+                    self.frames.push(Frame::DeepForce(item.clone()));
+                }
+            }
+            Value::Attrs(attrs) => {
+                for (name, value) in attrs.iter().rev() {
+                    self.frames
+                        .push(Frame::DeepForceAttribute(name.clone(), value.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The value of `expr` in `env`, deferred unless that is cheaper than a thunk.
+fn delay(expr: &Rc<Expr>, env: &Env) -> Value {
+    match &expr.kind {
+        ExprKind::Literal(value) => value.clone(),
+        ExprKind::Var {
+            attrs,
+            address: Address::Static(Binder::Local { depth, slot }),
+            ..
+        } if attrs.is_empty() => env.get(*depth, *slot).clone(),
+        _ => Value::Thunk(Thunk::new(Code::Expr(expr.clone()), env.clone(), None)),
+    }
+}
+
+/// Extends `env` with the mutually visible bindings of a `let` or `rec` set, each one
+/// deferred in the extended environment. Inherited bindings see `env` instead.
+fn recursive_env(
+    bindings: &[Binding],
+    env: Env,
+    origin: fn(Symbol) -> Origin,
+    ctx: &EvalContext,
+) -> Env {
+    let mut slots = Vec::with_capacity(bindings.len());
+    let mut knots = Vec::new();
+    for binding in bindings {
+        let value = match &binding.value.kind {
+            ExprKind::Literal(value) => value.clone(),
+            _ => {
+                let thunk = Thunk::new(
+                    Code::Expr(binding.value.clone()),
+                    env.clone(),
+                    Some(origin(binding.name.clone())),
+                );
+                if !binding.inherited {
+                    knots.push(thunk.clone());
+                }
+                Value::Thunk(thunk)
+            }
+        };
+        slots.push(value);
+    }
+    let env = Environment::extend(&env, slots);
+    let mut candidates = ctx.knots.borrow_mut();
+    for thunk in knots {
+        thunk.set_env(env.clone());
+        candidates.add(&thunk);
+    }
+    env
+}
+
+/// The error for forcing `thunk` while it is already being forced, naming the bindings
+/// that led back to it. `in_progress` are the thunks being forced, outermost first.
+pub(super) fn infinite_recursion<'a>(
+    in_progress: impl Iterator<Item = &'a Thunk>,
+    thunk: &'a Thunk,
+) -> EvaluationError {
+    let mut cycle: Vec<_> = in_progress
+        .skip_while(|other| !other.ptr_eq(thunk))
+        .chain([thunk])
+        .filter_map(|thunk| {
+            let (Origin::LetBinding(name) | Origin::Attribute(name)) = thunk.origin()?;
+            Some(CycleMember::Binding {
+                name: name.to_string(),
+                span: thunk.span(),
+            })
+        })
+        .collect();
+    if cycle.len() < 2 {
+        cycle.clear();
+    }
+    EvaluationError::InfiniteRecursion { cycle }
+}
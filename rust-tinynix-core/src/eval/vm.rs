@@ -0,0 +1,626 @@
+use super::bytecode::{Chunk, Delayed, Op, RecBinding};
+use super::env::{Env, Environment};
+use super::machine::{self, infinite_recursion};
+use super::{
+    EvalContext, EvaluationError, TraceFrameKind, binary_op, lookup, search_path, unary_op,
+};
+use crate::builtins;
+use crate::diagnostics::suggest_similar;
+use crate::span::Span;
+use crate::symbol::Symbol;
+use crate::value::{Code, Lambda, NixString, Origin, PrimOp, Suspended, Thunk, Value};
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Runs a compiled program, forcing the result deeply if `deep` is set.
+pub(crate) fn eval(
+    chunk: Rc<Chunk>,
+    env: Env,
+    deep: bool,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let frames = if deep {
+        vec![Frame::DeepForceResult]
+    } else {
+        Vec::new()
+    };
+    let mut vm = Vm::new(frames, ctx);
+    vm.enter(chunk, env);
+    vm.run()
+}
+
+/// Forces a thunk to weak head normal form.
+pub(crate) fn force(value: Value, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    Vm::new(vec![Frame::Force(value)], ctx).run()
+}
+
+/// Forces everything an already evaluated value contains.
+pub(crate) fn force_deep(value: Value, ctx: &EvalContext) -> Result<Value, EvaluationError> {
+    Vm::new(vec![Frame::DeepForceResult, Frame::Force(value)], ctx).run()
+}
+
+/// Applies a function to a single argument, without forcing the result.
+pub(crate) fn apply(
+    function: Value,
+    argument: Value,
+    span: Span,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let frames = vec![Frame::Apply { argument, span }, Frame::Force(function)];
+    Vm::new(frames, ctx).run()
+}
+
+/// Applies `function` to `args` one at a time and forces the result deeply.
+pub(crate) fn call(
+    function: Value,
+    args: Vec<Value>,
+    span: Span,
+    ctx: &EvalContext,
+) -> Result<Value, EvaluationError> {
+    let mut frames = vec![Frame::DeepForceResult];
+    frames.extend(
+        args.into_iter()
+            .rev()
+            .map(|argument| Frame::Apply { argument, span }),
+    );
+    frames.push(Frame::Force(function));
+    Vm::new(frames, ctx).run().map_err(|error| error.at(span))
+}
+
+/// The virtual machine. Like the tree-walking machine, it keeps its call stack on the
+/// heap, so nesting is bounded by memory instead of the thread's stack size.
+struct Vm<'c> {
+    ctx: &'c EvalContext,
+    /// The work still to be done, innermost last.
+    frames: Vec<Frame>,
+    /// The operands and results of instructions.
+    values: Vec<Value>,
+    /// The lists and sets deep forcing has already visited, so cyclic values end.
+    deeply_forced: HashSet<*const ()>,
+}
+
+enum Frame {
+    /// Runs `chunk` in `env` from instruction `pc` on. The whole expression of the
+    /// chunk is `base` levels deep.
+    Code {
+        chunk: Rc<Chunk>,
+        pc: usize,
+        env: Env,
+        base: usize,
+    },
+    /// Pushes the value, forcing it first if it is a thunk.
+    Force(Value),
+    /// Stores the value on top of the stack in a thunk being forced.
+    Update(Thunk, Suspended),
+    /// Adds `kind` at `span` to the trace of errors raised by the frames above.
+    Context(TraceFrameKind, Span),
+    /// Adds the call at `span` of the lambda defined at `lambda` to the trace of errors
+    /// raised by the frames above.
+    Call { lambda: Span, span: Span },
+    /// Applies the function on top of the stack to `argument`.
+    Apply { argument: Value, span: Span },
+    /// Calls a saturated builtin with the arguments on top of the stack.
+    CallPrimop {
+        name: &'static str,
+        arity: usize,
+        span: Span,
+    },
+    /// Forces the value and everything it contains, leaving the stack as it is.
+    DeepForce(Value),
+    /// Like [`Frame::DeepForce`] for the attribute `name` of a set, tracing failures
+    /// to it while it is being forced.
+    DeepForceAttribute(Symbol, Value),
+    /// Deep forces the value on top of the stack, which has just been forced.
+    DeepForceForced,
+    /// Deep forces the result, leaving it on the stack.
+    DeepForceResult,
+}
+
+/// What the running chunk needs after an instruction.
+enum Next {
+    Continue,
+    /// Suspends the chunk until the value is forced.
+    Force(Value),
+    /// Suspends the chunk until the function has been applied.
+    Apply(Value, Value),
+    Return,
+}
+
+impl<'c> Vm<'c> {
+    fn new(frames: Vec<Frame>, ctx: &'c EvalContext) -> Self {
+        Vm {
+            ctx,
+            frames,
+            values: Vec::new(),
+            deeply_forced: HashSet::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<Value, EvaluationError> {
+        while let Some(frame) = self.frames.pop() {
+            if let Err(error) = self.step(frame) {
+                return Err(self.unwind(error));
+            }
+        }
+        Ok(self.values.pop().expect("evaluation produced a value"))
+    }
+
+    fn step(&mut self, frame: Frame) -> Result<(), EvaluationError> {
+        match frame {
+            Frame::Code {
+                chunk,
+                pc,
+                env,
+                base,
+            } => self.execute(chunk, pc, env, base)?,
+            Frame::Force(value) => self.force(value)?,
+            Frame::Update(thunk, _) => {
+                let value = self.values.last().expect("a forced thunk has a value");
+                thunk.set_value(value.clone());
+            }
+            Frame::Context(..) | Frame::Call { .. } => {}
+            Frame::Apply { argument, span } => {
+                let function = self.pop();
+                self.apply(function, argument, span)?;
+            }
+            Frame::CallPrimop { name, arity, span } => {
+                let args = self.values.split_off(self.values.len() - arity);
+                let result = builtins::call(name, args, self.ctx).map_err(|error| {
+                    error.in_frame(TraceFrameKind::Call(name.to_string()), span)
+                })?;
+                self.checked_push(result)?;
+            }
+            Frame::DeepForce(value) => self.deep_force(value),
+            Frame::DeepForceAttribute(name, value) => {
+                if let Value::Thunk(thunk) = &value
+                    && thunk.value().is_none()
+                {
+                    let frame = TraceFrameKind::Attribute(name.to_string());
+                    self.frames.push(Frame::Context(frame, thunk.span()));
+                }
+                self.deep_force(value);
+            }
+            Frame::DeepForceForced => {
+                let value = self.pop();
+                self.deep_force(value);
+            }
+            Frame::DeepForceResult => {
+                let value = self.values.last().expect("a result to force").clone();
+                self.deep_force(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops frames down to the bottom of the stack, adding the context of each to `error`.
+    fn unwind(&mut self, mut error: EvaluationError) -> EvaluationError {
+        while let Some(frame) = self.frames.pop() {
+            match frame {
+                Frame::Code {
+                    chunk, pc, base, ..
+                } => {
+                    self.ctx.depth.set(base - 1);
+                    error = error.at(chunk.node(pc.saturating_sub(1)).span);
+                }
+                Frame::Context(kind, span) => error = error.in_frame(kind, span),
+                Frame::Call { lambda, span } => {
+                    error = error.in_frame(TraceFrameKind::lambda(lambda, self.ctx), span)
+                }
+                // Forcing the thunk again raises the error again.
+                Frame::Update(thunk, suspended) => thunk.restore(suspended),
+                _ => {}
+            }
+        }
+        error
+    }
+
+    /// Starts running `chunk` in `env`, one level deeper than the instruction that
+    /// entered it, like the tree-walking machine evaluates the expression.
+    fn enter(&mut self, chunk: Rc<Chunk>, env: Env) {
+        let base = self.ctx.depth.get() + 1;
+        self.frames.push(Frame::Code {
+            chunk,
+            pc: 0,
+            env,
+            base,
+        });
+    }
+
+    /// Runs instructions of `chunk` until it returns or has to wait for a thunk or
+    /// function call.
+    fn execute(
+        &mut self,
+        chunk: Rc<Chunk>,
+        mut pc: usize,
+        mut env: Env,
+        base: usize,
+    ) -> Result<(), EvaluationError> {
+        loop {
+            let result = self
+                .check_step(&chunk, pc, base)
+                .and_then(|()| self.instruction(chunk.code[pc], &chunk, pc, &mut env));
+            pc += 1;
+            let next = match result {
+                Ok(next) => next,
+                Err(error) => {
+                    // Leaves the chunk on the stack, where unwinding locates the error.
+                    self.frames.push(Frame::Code {
+                        chunk,
+                        pc,
+                        env,
+                        base,
+                    });
+                    return Err(error);
+                }
+            };
+            match next {
+                Next::Continue => {}
+                Next::Return => {
+                    self.ctx.depth.set(base - 1);
+                    return Ok(());
+                }
+                Next::Force(value) => {
+                    self.frames.push(Frame::Code {
+                        chunk,
+                        pc,
+                        env,
+                        base,
+                    });
+                    return self.force(value);
+                }
+                Next::Apply(function, argument) => {
+                    let span = chunk.node(pc - 1).span;
+                    self.frames.push(Frame::Code {
+                        chunk,
+                        pc,
+                        env,
+                        base,
+                    });
+                    return self.apply(function, argument, span);
+                }
+            }
+        }
+    }
+
+    /// Checks the limits before the instruction at `pc`, which is as deeply nested as
+    /// the expression it belongs to.
+    fn check_step(&self, chunk: &Chunk, pc: usize, base: usize) -> Result<(), EvaluationError> {
+        let ctx = self.ctx;
+        if ctx.cancellation.is_cancelled() {
+            return Err(EvaluationError::Cancelled);
+        }
+        let depth = base + chunk.node(pc).depth as usize;
+        let steps = ctx.steps.get() + 1;
+        ctx.limits
+            .check_step(depth, steps, ctx.deadline.get())
+            .map_err(|error| {
+                // The tree-walking machine stops at the first expression that is too
+                // deep, which encloses this one.
+                let max = ctx.limits.max_depth.map_or(depth, |max| depth.min(max + 1));
+                error.at(chunk.enclosing(pc, (max - base) as u32))
+            })?;
+        ctx.depth.set(depth);
+        ctx.steps.set(steps);
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        op: Op,
+        chunk: &Chunk,
+        pc: usize,
+        env: &mut Env,
+    ) -> Result<Next, EvaluationError> {
+        match op {
+            Op::Constant(index) => self.values.push(chunk.constants[index as usize].clone()),
+            Op::Local { depth, slot } => {
+                let value = env.get(depth as usize, slot as usize).clone();
+                self.values.push(value);
+            }
+            Op::Dynamic(index) => {
+                let (name, address) = &chunk.dynamic[index as usize];
+                self.values.push(lookup(name, address, env, self.ctx)?);
+            }
+            Op::Force => {
+                let value = self.pop();
+                match &value {
+                    Value::Thunk(thunk) => match thunk.value() {
+                        Some(forced) => self.values.push(forced.clone()),
+                        None => return Ok(Next::Force(value)),
+                    },
+                    _ => self.values.push(value),
+                }
+            }
+            Op::Select { path, depth } => {
+                let path = &chunk.paths[path as usize];
+                let depth = depth as usize + 1;
+                let value = self.pop();
+                let Value::Attrs(bindings) = &value else {
+                    return Err(EvaluationError::type_mismatch("a set", &value));
+                };
+                let attr = &path[depth];
+                let Some(selected) = bindings.get(attr) else {
+                    let path: Vec<_> = path[..depth].iter().map(|part| part.as_str()).collect();
+                    return Err(EvaluationError::MissingAttribute {
+                        attribute: attr.to_string(),
+                        path: path.join("."),
+                        suggestions: suggest_similar(
+                            attr.as_str(),
+                            bindings.keys().map(Symbol::as_str),
+                        ),
+                    });
+                };
+                let selected = selected.clone();
+                self.values.push(selected);
+            }
+            Op::Thunk(index) => {
+                let body = Code::Chunk(chunk.chunks[index as usize].clone());
+                self.values
+                    .push(Value::Thunk(Thunk::new(body, env.clone(), None)));
+            }
+            Op::Closure { param, body } => {
+                let body = Code::Chunk(chunk.chunks[body as usize].clone());
+                let param = chunk.params[param as usize].clone();
+                let lambda = Lambda::new(param, body, env.clone(), chunk.node(pc).span);
+                self.values.push(Value::Lambda(Rc::new(lambda)));
+            }
+            Op::Call => {
+                let argument = self.pop();
+                let function = self.pop();
+                return Ok(Next::Apply(function, argument));
+            }
+            Op::Unary(op) => {
+                let value = self.pop();
+                self.values.push(unary_op(op, &value)?);
+            }
+            Op::Binary(op) => {
+                let right = self.pop();
+                let left = self.pop();
+                let result = binary_op(op, &left, &right)?;
+                self.checked_push(result)?;
+            }
+            Op::PushWith => {
+                let environment = self.pop();
+                let Value::Attrs(attrs) = &environment else {
+                    return Err(EvaluationError::type_mismatch("a set", &environment));
+                };
+                *env = Environment::extend_with(env, attrs.clone());
+            }
+            Op::PushLet(index) => {
+                let bindings = &chunk.recursive[index as usize];
+                *env = recursive_env(chunk, bindings, env, Origin::LetBinding, self.ctx);
+            }
+            Op::PopEnv => *env = env.parent().clone(),
+            Op::RecAttrs(index) => {
+                let bindings = &chunk.recursive[index as usize];
+                let env = recursive_env(chunk, bindings, env, Origin::Attribute, self.ctx);
+                let attrs = bindings
+                    .iter()
+                    .zip(env.slots())
+                    .map(|(binding, value)| (binding.name.clone(), value.clone()))
+                    .collect();
+                self.checked_push(Value::Attrs(attrs))?;
+            }
+            Op::List(len) => {
+                let items = self.values.split_off(self.values.len() - len as usize);
+                self.checked_push(Value::List(Rc::new(items)))?;
+            }
+            Op::Attrs(index) => {
+                let names = &chunk.names[index as usize];
+                let values = self.values.split_off(self.values.len() - names.len());
+                let attrs = names.iter().cloned().zip(values).collect();
+                self.checked_push(Value::Attrs(attrs))?;
+            }
+            Op::Assert(index) => {
+                let value = self.pop();
+                match &value {
+                    Value::Bool(true) => {}
+                    Value::Bool(false) => {
+                        let condition = chunk.assertions[index as usize];
+                        return Err(EvaluationError::AssertionFailed.at(condition));
+                    }
+                    _ => return Err(EvaluationError::type_mismatch("a Boolean", &value)),
+                }
+            }
+            Op::CheckString => {
+                let value = self.values.last().expect("an interpolation has a value");
+                // This logic should be expanded to handle auto-coercion to string
+                if !matches!(value, Value::String(_)) {
+                    return Err(EvaluationError::type_mismatch("a string", value));
+                }
+            }
+            Op::Interpolate(len) | Op::InterpolatePath(len) => {
+                let parts = self.values.split_off(self.values.len() - len as usize);
+                let mut result = String::new();
+                let mut context = BTreeSet::new();
+                for part in &parts {
+                    let Value::String(s) = part else {
+                        unreachable!("interpolations are checked to be strings");
+                    };
+                    result.push_str(s.as_str());
+                    context.extend(s.context().iter().cloned());
+                }
+                let value = match op {
+                    Op::InterpolatePath(_) => Value::Path(Rc::from(Path::new(&result))),
+                    _ => Value::String(NixString::with_context(result, context)),
+                };
+                self.checked_push(value)?;
+            }
+            Op::SearchPath(index) => {
+                let value = search_path(&chunk.search_paths[index as usize], self.ctx)?;
+                self.values.push(value);
+            }
+            Op::Invalid => {
+                return Err(EvaluationError::UnsupportedOperation(
+                    "Cannot evaluate an expression that failed to parse.".to_string(),
+                ));
+            }
+            Op::Return => return Ok(Next::Return),
+        }
+        Ok(Next::Continue)
+    }
+
+    fn pop(&mut self) -> Value {
+        self.values.pop().expect("an operand has been evaluated")
+    }
+
+    /// Pushes a newly built value, checking it against the limits first.
+    fn checked_push(&mut self, value: Value) -> Result<(), EvaluationError> {
+        self.ctx.limits.check_value(&value)?;
+        self.values.push(value);
+        Ok(())
+    }
+
+    fn force(&mut self, value: Value) -> Result<(), EvaluationError> {
+        let Value::Thunk(thunk) = &value else {
+            self.values.push(value);
+            return Ok(());
+        };
+        if let Some(forced) = thunk.value() {
+            self.values.push(forced.clone());
+            return Ok(());
+        }
+        let Some(suspended) = thunk.take_suspended() else {
+            let in_progress = self.frames.iter().filter_map(|frame| match frame {
+                Frame::Update(thunk, _) => Some(thunk),
+                _ => None,
+            });
+            return Err(infinite_recursion(in_progress, thunk));
+        };
+        let Code::Chunk(chunk) = suspended.code.clone() else {
+            // Thunks of the tree-walking machine are forced by it.
+            thunk.restore(suspended);
+            self.values.push(machine::force(value.clone(), self.ctx)?);
+            return Ok(());
+        };
+        let env = suspended.env.clone();
+        let span = thunk.span();
+        let frame = match thunk.origin() {
+            Some(Origin::LetBinding(name)) => Some(TraceFrameKind::LetBinding(name.to_string())),
+            _ => None,
+        };
+        self.frames.push(Frame::Update(thunk.clone(), suspended));
+        if let Some(frame) = frame {
+            self.frames.push(Frame::Context(frame, span));
+        }
+        self.enter(chunk, env);
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        function: Value,
+        argument: Value,
+        span: Span,
+    ) -> Result<(), EvaluationError> {
+        match &function {
+            Value::Lambda(lambda) => match &lambda.body {
+                Code::Chunk(body) => {
+                    let env = Environment::extend(&lambda.env(), vec![argument]);
+                    self.frames.push(Frame::Call {
+                        lambda: lambda.span(),
+                        span,
+                    });
+                    self.enter(body.clone(), env);
+                }
+                Code::Expr(_) => {
+                    let result = machine::apply(function.clone(), argument, span, self.ctx)?;
+                    self.values.push(result);
+                }
+            },
+            Value::Primop(primop) => {
+                let mut args = primop.args.clone();
+                args.push(argument);
+                if args.len() < primop.arity {
+                    self.values.push(Value::Primop(Rc::new(PrimOp {
+                        name: primop.name,
+                        arity: primop.arity,
+                        args,
+                    })));
+                    return Ok(());
+                }
+                self.frames.push(Frame::CallPrimop {
+                    name: primop.name,
+                    arity: primop.arity,
+                    span,
+                });
+                let deep_args = builtins::deep_args(primop.name);
+                for (index, argument) in args.into_iter().enumerate().rev() {
+                    if deep_args.contains(&index) {
+                        self.frames.push(Frame::DeepForceResult);
+                    }
+                    self.frames.push(Frame::Force(argument));
+                }
+            }
+            _ => return Err(EvaluationError::type_mismatch("a function", &function)),
+        }
+        Ok(())
+    }
+
+    /// Schedules forcing `value` and, recursively, the elements of lists and attributes
+    /// of sets. Failures inside sets are traced to the attribute they happened in.
+    fn deep_force(&mut self, value: Value) {
+        let Some(forced) = value.forced() else {
+            self.frames.push(Frame::DeepForceForced);
+            self.frames.push(Frame::Force(value));
+            return;
+        };
+        if let Some(identity) = forced.identity()
+            && !self.deeply_forced.insert(identity)
+        {
+            return;
+        }
+        match forced {
+            Value::List(items) => {
+                for item in items.iter().rev() {
+                    self.frames.push(Frame::DeepForce(item.clone()));
+                }
+            }
+            Value::Attrs(attrs) => {
+                for (name, value) in attrs.iter().rev() {
+                    self.frames
+                        .push(Frame::DeepForceAttribute(name.clone(), value.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Extends `env` with the mutually visible bindings of a `let` or `rec` set. Inherited
+/// bindings see `env` instead.
+fn recursive_env(
+    chunk: &Chunk,
+    bindings: &[RecBinding],
+    env: &Env,
+    origin: fn(Symbol) -> Origin,
+    ctx: &EvalContext,
+) -> Env {
+    let mut slots = Vec::with_capacity(bindings.len());
+    let mut knots = Vec::new();
+    for binding in bindings {
+        let value = match binding.value {
+            Delayed::Constant(index) => chunk.constants[index as usize].clone(),
+            Delayed::Thunk(index) => {
+                let thunk = Thunk::new(
+                    Code::Chunk(chunk.chunks[index as usize].clone()),
+                    env.clone(),
+                    Some(origin(binding.name.clone())),
+                );
+                if !binding.inherited {
+                    knots.push(thunk.clone());
+                }
+                Value::Thunk(thunk)
+            }
+        };
+        slots.push(value);
+    }
+    let env = Environment::extend(env, slots);
+    let mut candidates = ctx.knots.borrow_mut();
+    for thunk in knots {
+        thunk.set_env(env.clone());
+        candidates.add(&thunk);
+    }
+    env
+}
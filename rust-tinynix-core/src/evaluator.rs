@@ -0,0 +1,605 @@
+use crate::builtins;
+use crate::cancellation::CancellationToken;
+use crate::eval::{self, Backend, EvalContext, EvaluationError, Program, TraceSink, select};
+use crate::limits::Limits;
+use crate::parser::{self, split_attr_path};
+use crate::search_path::SearchPath;
+use crate::span::{FileId, SourceMap, Span};
+use crate::value::Value;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A long-lived evaluation session. It keeps its configuration and the import cache
+/// across calls, so hosts evaluating many expressions only pay for each file once.
+#[derive(Debug)]
+pub struct Evaluator {
+    ctx: EvalContext,
+    base_dir: PathBuf,
+    /// The source map entry [`Evaluator::eval_str`] reuses for its source text.
+    string_file: Cell<Option<FileId>>,
+    /// The programs [`Evaluator::eval_str`] has lowered, keyed by their source text.
+    strings: RefCell<HashMap<String, Rc<Program>>>,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Evaluator::builder().build()
+    }
+}
+
+impl Evaluator {
+    /// An evaluator with the default configuration, see [`EvaluatorBuilder`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> EvaluatorBuilder {
+        EvaluatorBuilder::default()
+    }
+
+    /// Parses and evaluates `source`, resolving relative paths against the base
+    /// directory.
+    ///
+    /// Every call stores its source in the same source map entry, so spans into an
+    /// earlier source can only be rendered until the next call. Sources evaluated before
+    /// are not parsed, lowered or compiled again.
+    pub fn eval_str(&self, source: &str) -> Result<Value, EvaluationError> {
+        let file = {
+            let mut source_map = self.ctx.source_map.borrow_mut();
+            match self.string_file.get() {
+                Some(file) => {
+                    source_map.replace(file, source);
+                    file
+                }
+                None => source_map.add("«string»", source),
+            }
+        };
+        self.string_file.set(Some(file));
+        let cached = self.strings.borrow().get(source).cloned();
+        let program = match cached {
+            Some(program) => program,
+            None => {
+                let ast = parser::parse_ast(source, &self.base_dir, file)
+                    .map_err(|error| EvaluationError::ParseFailed { file, error })?;
+                let program = Rc::new(Program::lower(&ast, &self.ctx.import_scope, &self.ctx)?);
+                self.strings
+                    .borrow_mut()
+                    .insert(source.to_owned(), program.clone());
+                program
+            }
+        };
+        eval::eval_program(&program, &self.ctx.import_scope, &self.ctx)
+    }
+
+    /// Evaluates a file, relative to the base directory, like `import` would, and then
+    /// forces the result completely.
+    pub fn eval_file(&self, path: impl AsRef<Path>) -> Result<Value, EvaluationError> {
+        self.ctx.begin();
+        let result = builtins::import_file(&self.base_dir.join(path), &self.ctx)
+            .and_then(|value| eval::force_deep(value, &self.ctx));
+        self.ctx.end();
+        result
+    }
+
+    /// Evaluates a file and selects the dotted attribute path `attr_path`, e.g.
+    /// `"packages.hello"`, from the resulting set.
+    pub fn eval_attr(
+        &self,
+        path: impl AsRef<Path>,
+        attr_path: &str,
+    ) -> Result<Value, EvaluationError> {
+        let value = self.eval_file(path)?;
+        select(&value, &split_attr_path(attr_path), &self.ctx)
+    }
+
+    /// Applies a function to arguments, one at a time, and forces the result
+    /// completely.
+    pub fn call(
+        &self,
+        function: Value,
+        args: impl IntoIterator<Item = Value>,
+    ) -> Result<Value, EvaluationError> {
+        self.ctx.begin();
+        let result = eval::call(
+            function,
+            args.into_iter().collect(),
+            Span::default(),
+            &self.ctx,
+        );
+        self.ctx.end();
+        result
+    }
+
+    /// Replaces the cancellation token for the evaluations that follow. A cancelled
+    /// token stays cancelled, so hosts that abandon stale evaluations give each new one
+    /// a fresh token, while the caches are kept.
+    pub fn set_cancellation(&mut self, cancellation: CancellationToken) {
+        self.ctx.cancellation = cancellation;
+    }
+
+    /// The text of everything evaluated so far, for rendering diagnostics.
+    pub fn source_map(&self) -> Ref<'_, SourceMap> {
+        self.ctx.source_map.borrow()
+    }
+
+    pub fn context(&self) -> &EvalContext {
+        &self.ctx
+    }
+}
+
+/// Configures an [`Evaluator`]. Everything is optional: by default there is no search
+/// path, relative paths are resolved against the current directory, evaluation is
+/// impure and unlimited, and traces go to stderr.
+#[derive(Debug, Default)]
+pub struct EvaluatorBuilder {
+    ctx: EvalContext,
+    base_dir: Option<PathBuf>,
+}
+
+impl EvaluatorBuilder {
+    /// How to evaluate expressions, see [`Backend`].
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.ctx.backend = backend;
+        self
+    }
+
+    pub fn search_path(mut self, search_path: SearchPath) -> Self {
+        self.ctx.search_path = search_path;
+        self
+    }
+
+    /// The directory relative paths in [`Evaluator::eval_str`] and the files passed to
+    /// [`Evaluator::eval_file`] are resolved against.
+    pub fn base_dir(mut self, base_dir: impl Into<PathBuf>) -> Self {
+        self.base_dir = Some(base_dir.into());
+        self
+    }
+
+    /// Makes `value` visible as `name` everywhere, including in imported files.
+    pub fn builtin(mut self, name: impl Into<String>, value: Value) -> Self {
+        self.ctx.import_scope.insert(name.into(), value);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.ctx.limits = limits;
+        self
+    }
+
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.ctx.cancellation = cancellation;
+        self
+    }
+
+    pub fn trace_sink(mut self, sink: impl Fn(&Value) + 'static) -> Self {
+        self.ctx.trace_sink = TraceSink::new(sink);
+        self
+    }
+
+    /// Whether to forbid evaluation from depending on the environment.
+    pub fn pure(mut self, pure: bool) -> Self {
+        self.ctx.pure = pure;
+        self
+    }
+
+    pub fn build(self) -> Evaluator {
+        let base_dir = self
+            .base_dir
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_default();
+        Evaluator {
+            ctx: self.ctx,
+            base_dir,
+            string_file: Cell::new(None),
+            strings: RefCell::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::ColorMode;
+    use crate::eval::TraceFrameKind;
+    use crate::limits::ResourceLimit;
+    use crate::parser::ParseError;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Evaluates `source` on both backends, checks that they agree, and returns the
+    /// value or the rendered error.
+    fn on_both_backends(source: &str, limits: Limits) -> String {
+        let [tree_walking, bytecode] = [Backend::TreeWalking, Backend::Bytecode].map(|backend| {
+            let evaluator = Evaluator::builder()
+                .backend(backend)
+                .limits(limits.clone())
+                .build();
+            match evaluator.eval_str(source) {
+                Ok(value) => value.to_string(),
+                Err(error) => error
+                    .to_diagnostic()
+                    .render(&evaluator.source_map(), ColorMode::Plain),
+            }
+        });
+        assert_eq!(tree_walking, bytecode, "{}", source);
+        tree_walking
+    }
+
+    #[test]
+    fn backends_agree_on_values_and_errors() {
+        let sources = [
+            "let x = 1; y = x + 1; in [ x y (x - y) ]",
+            "rec { a = 1; b = a + 1; c = { d = b; }; }",
+            "let f = a: b: a - b; in f 3 1",
+            "let s = { x = 1; }; in with s; x + 1",
+            "let n = \"two\"; in \"n is ${n}\"",
+            "\"n is ${1}\"",
+            "let s = removeAttrs { a = 1; b = 7; } [ \"a\" ]; in [ s (builtins.div s.b 2) ]",
+            "{ a = { b = 1 + \"x\"; }; }",
+            "let xs = [ 1 (throw \"item\") ]; in xs",
+            "assert false; 3",
+            "let f = x: x.missing; in f { present = 1; }",
+            "let x = x + 1; in x",
+            "let f = a: b: a; in f 1 2 3",
+            "{ a = 1; } // { b = builtins.div 1 0; }",
+        ];
+        for source in sources {
+            on_both_backends(source, Limits::unlimited());
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_the_depth_limit() {
+        let sum = vec!["1"; 14].join(" + ");
+        let thunks = "let a = 1; b = a + 1; c = b + 1; d = c + 1; in [ d ]";
+        let calls = "let inc = x: x + 1; in inc (inc (inc (inc 1)))";
+        for source in [sum.as_str(), thunks, calls] {
+            let results: Vec<_> = (1..40)
+                .map(|max_depth| {
+                    let limits = Limits {
+                        max_depth: Some(max_depth),
+                        ..Limits::unlimited()
+                    };
+                    on_both_backends(source, limits)
+                })
+                .collect();
+            assert!(results[0].contains("recursion"), "{}", results[0]);
+            assert!(!results[38].contains("recursion"), "{}", results[38]);
+        }
+        let limits = Limits {
+            max_depth: Some(14),
+            ..Limits::unlimited()
+        };
+        assert_eq!(on_both_backends(&sum, limits), "14");
+    }
+
+    /// The limit `source` runs into on each backend, if any.
+    fn limit_exceeded(source: &str, limits: Limits) -> [Option<ResourceLimit>; 2] {
+        [Backend::TreeWalking, Backend::Bytecode].map(|backend| {
+            let evaluator = Evaluator::builder()
+                .backend(backend)
+                .limits(limits.clone())
+                .build();
+            match evaluator.eval_str(source) {
+                Ok(_) => None,
+                Err(error) => match error.root_cause() {
+                    EvaluationError::ResourceLimitExceeded(limit) => Some(*limit),
+                    _ => panic!("{}: {}", source, error),
+                },
+            }
+        })
+    }
+
+    #[test]
+    fn every_limit_is_enforced_on_both_backends() {
+        let unlimited = Limits::unlimited();
+        let cases = [
+            (
+                "1 + 1 + 1 + 1 + 1 + 1 + 1 + 1",
+                Limits {
+                    max_steps: Some(5),
+                    ..unlimited.clone()
+                },
+                ResourceLimit::Steps(5),
+            ),
+            (
+                "let inc = x: x + 1; in inc (inc (inc 1))",
+                Limits {
+                    max_depth: Some(3),
+                    ..unlimited.clone()
+                },
+                ResourceLimit::RecursionDepth(3),
+            ),
+            (
+                "\"ab\" + \"cd\"",
+                Limits {
+                    max_string_length: Some(3),
+                    ..unlimited.clone()
+                },
+                ResourceLimit::StringLength(3),
+            ),
+            (
+                "[ 1 2 3 ]",
+                Limits {
+                    max_list_length: Some(2),
+                    ..unlimited.clone()
+                },
+                ResourceLimit::ListLength(2),
+            ),
+            (
+                "{ a = 1; } // { b = 2; }",
+                Limits {
+                    max_attrset_size: Some(1),
+                    ..unlimited.clone()
+                },
+                ResourceLimit::AttrsetSize(1),
+            ),
+            (
+                // Calls `inc` 2^16 times, which takes far longer than the limit.
+                &format!(
+                    "let twice = f: x: f (f x); inc = x: x + 1; in {}inc{} 0",
+                    "twice (".repeat(16),
+                    ")".repeat(16)
+                ),
+                Limits {
+                    timeout: Some(Duration::from_millis(10)),
+                    ..unlimited.clone()
+                },
+                ResourceLimit::Timeout(Duration::from_millis(10)),
+            ),
+        ];
+        for (source, limits, limit) in cases {
+            assert_eq!(
+                limit_exceeded(source, limits),
+                [Some(limit); 2],
+                "{}",
+                source
+            );
+        }
+        for source in ["\"abc\"", "[ 1 2 ]", "{ a = 1; } // { a = 2; }"] {
+            let limits = Limits {
+                max_string_length: Some(3),
+                max_list_length: Some(2),
+                max_attrset_size: Some(1),
+                ..unlimited.clone()
+            };
+            assert_eq!(limit_exceeded(source, limits), [None; 2], "{}", source);
+        }
+    }
+
+    #[test]
+    fn cancelled_evaluations_fail_on_both_backends() {
+        let token = CancellationToken::new();
+        token.cancel();
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let evaluator = Evaluator::builder()
+                .backend(backend)
+                .cancellation(token.clone())
+                .build();
+            let error = evaluator.eval_str("1 + 2").unwrap_err();
+            assert!(
+                matches!(error.root_cause(), EvaluationError::Cancelled),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn sets_are_listed_alphabetically() {
+        let source = "let s = { zeta = 1; alpha = 2; mid = 3; }; in [ s (removeAttrs s [ \"mid\" \"unknown\" ]) ]";
+        assert_eq!(
+            on_both_backends(source, Limits::unlimited()),
+            "[ { alpha = 2; mid = 3; zeta = 1; } { alpha = 2; zeta = 1; } ]"
+        );
+    }
+
+    #[test]
+    fn repeated_sources_are_evaluated_again() {
+        let failing = "let f = x: x + \"s\"; in f 1";
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let evaluator = Evaluator::builder().backend(backend).build();
+            let render = |source| match evaluator.eval_str(source) {
+                Ok(value) => value.to_string(),
+                Err(error) => error
+                    .to_diagnostic()
+                    .render(&evaluator.source_map(), ColorMode::Plain),
+            };
+            let first = render(failing);
+            assert_eq!(render("[ 1 2 ]"), "[ 1 2 ]");
+            assert_eq!(render(failing), first);
+            assert_eq!(render("[ 1 2 ]"), "[ 1 2 ]");
+        }
+    }
+
+    #[test]
+    fn calls_are_traced_to_where_the_function_is_defined() {
+        let source = "let f = x: x + \"s\"; in f 1";
+        let rendered = on_both_backends(source, Limits::unlimited());
+        assert!(
+            rendered.contains("while calling the function at «string»:1:9"),
+            "{}",
+            rendered
+        );
+
+        let dir = std::env::temp_dir().join(format!("tinynix-lambda-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("x.nix"), "let\n  f =\n    x: x + \"s\";\nin f 1").unwrap();
+        let path = dir.join("x.nix").canonicalize().unwrap();
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let evaluator = Evaluator::builder().backend(backend).build();
+            let error = evaluator.eval_file(&path).unwrap_err();
+            let TraceFrameKind::Lambda { span, location } = &error.trace()[0].kind else {
+                panic!("{:?}", error.trace());
+            };
+            assert_eq!((span.start.line, span.start.column), (3, 5));
+            assert_eq!(*location, format!("{}:3:5", path.display()));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn import_failures_expose_their_cause() {
+        use std::error::Error;
+
+        let dir = std::env::temp_dir().join(format!("tinynix-causes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("broken.nix"), "{ a = ; }").unwrap();
+        std::fs::write(dir.join("outer.nix"), "import ./broken.nix").unwrap();
+        let evaluator = Evaluator::builder().base_dir(&dir).build();
+        let chain = |error: &EvaluationError| {
+            std::iter::successors(Some(error as &dyn Error), |&error| error.source())
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let error = evaluator.eval_str("import ./outer.nix").unwrap_err();
+        let cause = error.source().unwrap();
+        assert!(matches!(
+            cause.downcast_ref::<ParseError>(),
+            Some(ParseError::Syntax { .. })
+        ));
+        assert!(cause.source().is_none());
+        assert_eq!(
+            chain(&error)[1..],
+            ["1:7: syntax error: expected an expression, found ';'"]
+        );
+
+        let error = evaluator.eval_str("import ./missing.nix").unwrap_err();
+        let cause = error.source().unwrap();
+        let io = cause.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(chain(&error).len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_builder_configures_every_evaluation() {
+        let dir = std::env::temp_dir().join(format!("tinynix-builder-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("pkgs")).unwrap();
+        std::fs::write(dir.join("uses-host.nix"), "host + 1").unwrap();
+        std::fs::write(dir.join("pkgs/default.nix"), "{ version = 3; }").unwrap();
+        std::fs::write(dir.join("pkgs/lib.nix"), "{ answer = 42; }").unwrap();
+        let mut search_path = SearchPath::new();
+        search_path.add_prefix("pkgs", dir.join("pkgs"));
+        let traced = Rc::new(RefCell::new(Vec::new()));
+        let sink = traced.clone();
+        let evaluator = Evaluator::builder()
+            .base_dir(&dir)
+            .builtin("host", Value::Int(41))
+            .search_path(search_path.clone())
+            .trace_sink(move |value| sink.borrow_mut().push(value.to_string()))
+            .build();
+
+        let value = evaluator.eval_str("import ./uses-host.nix").unwrap();
+        assert!(matches!(value, Value::Int(42)), "{:?}", value);
+        let value = evaluator
+            .eval_str("let pkgs = import <pkgs>; lib = import <pkgs/lib.nix>; in [ pkgs.version lib.answer ]")
+            .unwrap();
+        assert_eq!(value.to_string(), "[ 3 42 ]");
+        let value = evaluator
+            .eval_str("builtins.trace { a = 1; } (builtins.trace \"b\" 2)")
+            .unwrap();
+        assert!(matches!(value, Value::Int(2)), "{:?}", value);
+        assert_eq!(*traced.borrow(), ["\"b\"", "{ a = 1; }"]);
+
+        let pure = Evaluator::builder()
+            .search_path(search_path)
+            .pure(true)
+            .build();
+        let error = pure.eval_str("import <pkgs>").unwrap_err();
+        assert!(
+            matches!(error.root_cause(), EvaluationError::Impure { .. }),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn attributes_are_selected_from_files() {
+        let dir = std::env::temp_dir().join(format!("tinynix-attrs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("set.nix"), "{ a = { b = 1; c = 2; }; }").unwrap();
+        let evaluator = Evaluator::builder().base_dir(&dir).build();
+        let value = evaluator.eval_attr("set.nix", "a.b").unwrap();
+        assert!(matches!(value, Value::Int(1)), "{:?}", value);
+        let error = evaluator.eval_attr("set.nix", "a.d").unwrap_err();
+        assert_eq!(error.to_string(), "attribute 'd' missing in 'a'");
+        let error = evaluator.eval_attr("set.nix", "a.never-seen").unwrap_err();
+        assert_eq!(error.to_string(), "attribute 'never-seen' missing in 'a'");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cancellation_can_be_replaced_between_evaluations() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut evaluator = Evaluator::builder().cancellation(token).build();
+        assert!(evaluator.eval_str("1").is_err());
+        evaluator.set_cancellation(CancellationToken::new());
+        assert!(matches!(evaluator.eval_str("1"), Ok(Value::Int(1))));
+    }
+
+    #[test]
+    fn functions_are_called_with_each_argument_in_turn() {
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let evaluator = Evaluator::builder().backend(backend).build();
+            let function = evaluator.eval_str("x: y: { sum = x + y; }").unwrap();
+            let value = evaluator
+                .call(function.clone(), [Value::Int(1), Value::Int(2)])
+                .unwrap();
+            assert_eq!(value.to_string(), "{ sum = 3; }");
+            let error = evaluator.call(Value::Int(1), [Value::Int(2)]).unwrap_err();
+            assert!(
+                error.to_string().contains("while a function was expected"),
+                "{}",
+                error
+            );
+            let partial = evaluator.call(function, [Value::Int(1)]).unwrap();
+            assert!(matches!(partial, Value::Lambda(_)), "{:?}", partial);
+        }
+    }
+
+    #[test]
+    fn unbound_names_are_reported_before_evaluating() {
+        // `f` is never called, so only lowering can find the misspelt name.
+        let source = "let undefinedName = 1; f = x: undefinedNme; in 1";
+        let rendered = on_both_backends(source, Limits::default());
+        assert_eq!(
+            rendered,
+            "error: undefined variable 'undefinedNme'\n \
+             --> «string»:1:31\n  |\n\
+             1 | let undefinedName = 1; f = x: undefinedNme; in 1\n  \
+             |                               ^^^^^^^^^^^^\n  |\n  \
+             = help: did you mean 'undefinedName'?\n"
+        );
+        let error = Evaluator::new().eval_str(source).unwrap_err();
+        let EvaluationError::UndefinedVariable { name, suggestions } = error.root_cause() else {
+            panic!("{:?}", error);
+        };
+        assert_eq!(name, "undefinedNme");
+        assert_eq!(suggestions, &["undefinedName"]);
+    }
+
+    #[test]
+    fn imported_files_are_lowered_while_evaluating() {
+        let dir = std::env::temp_dir().join(format!("tinynix-imports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("inner.nix"), "{ value = 41; }").unwrap();
+        std::fs::write(
+            dir.join("outer.nix"),
+            "let inner = import ./inner.nix; in inner.value",
+        )
+        .unwrap();
+        for backend in [Backend::TreeWalking, Backend::Bytecode] {
+            let evaluator = Evaluator::builder().backend(backend).base_dir(&dir).build();
+            let result = evaluator.eval_str("import ./outer.nix + 1");
+            assert!(matches!(result, Ok(Value::Int(42))), "{:?}", result);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
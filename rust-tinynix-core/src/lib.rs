@@ -1,9 +1,24 @@
+use eval::{EvaluationError, Scope};
+use evaluator::Evaluator;
 use indexmap::IndexMap;
+use parser::ParseError;
+use span::Span;
 use std::path::{Path, PathBuf};
+use value::Value;
 
+pub mod ast;
+pub mod builtins;
+pub mod cancellation;
 pub mod codegen;
+pub mod diagnostics;
 pub mod eval;
+pub mod evaluator;
+pub mod limits;
 pub mod parser;
+pub mod search_path;
+pub mod span;
+pub mod symbol;
+pub mod value;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NixValue {
@@ -21,24 +36,244 @@ pub enum NixStringPart {
     Interpolation(Box<NixExpr>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NixUnaryOp {
     Neg, // Arithmetic negation
     Not, // Logical negation
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NixBinaryOp {
     Add,
     Sub,
+    Update, // Attribute set update, `//`
+}
+
+/// An AST node together with the source region it was parsed from.
+///
+/// This is the owned form of the syntax tree, convenient to construct and to generate
+/// code from, but with an allocation per node; the parser and the evaluator work on the
+/// arena-backed [`ast::Ast`] instead. Cloning and dropping walk the tree with an
+/// explicit stack, so arbitrarily deep expressions don't overflow the native one.
+#[derive(Debug, PartialEq)]
+pub struct NixExpr {
+    pub kind: NixExprKind,
+    pub span: Span,
+}
+
+impl NixExpr {
+    pub fn new(kind: NixExprKind, span: Span) -> Self {
+        NixExpr { kind, span }
+    }
+
+    /// The expressions directly nested in this one, in source order.
+    pub fn children(&self) -> Vec<&NixExpr> {
+        match &self.kind {
+            NixExprKind::List(items) => items.iter().collect(),
+            NixExprKind::AttrSet { bindings, .. } => bindings.values().collect(),
+            NixExprKind::LetIn { bindings, body } => bindings.values().chain([&**body]).collect(),
+            NixExprKind::InterpolatedString(parts) | NixExprKind::InterpolatedPath(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    NixStringPart::Interpolation(expr) => Some(&**expr),
+                    NixStringPart::Literal(_) => None,
+                })
+                .collect(),
+            NixExprKind::UnaryOp { expr, .. } | NixExprKind::Lambda { body: expr, .. } => {
+                vec![expr]
+            }
+            NixExprKind::BinaryOp {
+                left: first,
+                right: second,
+                ..
+            }
+            | NixExprKind::With {
+                environment: first,
+                body: second,
+            }
+            | NixExprKind::Assert {
+                condition: first,
+                body: second,
+            }
+            | NixExprKind::Apply {
+                function: first,
+                argument: second,
+            } => vec![first, second],
+            NixExprKind::Value(_)
+            | NixExprKind::Ref(_)
+            | NixExprKind::Inherit(_)
+            | NixExprKind::SearchPath(_)
+            | NixExprKind::Error => Vec::new(),
+        }
+    }
+}
+
+impl Clone for NixExpr {
+    fn clone(&self) -> Self {
+        enum Task<'a> {
+            Visit(&'a NixExpr),
+            Build(&'a NixExpr, usize),
+        }
+        let mut tasks = vec![Task::Visit(self)];
+        let mut cloned: Vec<NixExpr> = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(expr) => {
+                    let children = expr.children();
+                    tasks.push(Task::Build(expr, children.len()));
+                    tasks.extend(children.into_iter().rev().map(Task::Visit));
+                }
+                Task::Build(expr, len) => {
+                    let children = cloned.split_off(cloned.len() - len);
+                    cloned.push(NixExpr::new(expr.kind.with_children(children), expr.span));
+                }
+            }
+        }
+        cloned.pop().expect("the root has been cloned")
+    }
+}
+
+impl Drop for NixExpr {
+    fn drop(&mut self) {
+        let mut pending = self.kind.take_children();
+        while let Some(mut expr) = pending.pop() {
+            pending.append(&mut expr.kind.take_children());
+        }
+    }
+}
+
+impl NixExprKind {
+    /// A copy of this node with `children`, in the order of [`NixExpr::children`], in
+    /// place of its own.
+    fn with_children(&self, children: Vec<NixExpr>) -> NixExprKind {
+        let mut children = children.into_iter();
+        let mut next = || Box::new(children.next().expect("one child per nested expression"));
+        match self {
+            NixExprKind::Value(value) => NixExprKind::Value(value.clone()),
+            NixExprKind::Ref(name) => NixExprKind::Ref(name.clone()),
+            NixExprKind::Inherit(name) => NixExprKind::Inherit(name.clone()),
+            NixExprKind::SearchPath(lookup) => NixExprKind::SearchPath(lookup.clone()),
+            NixExprKind::Error => NixExprKind::Error,
+            NixExprKind::InterpolatedString(parts) | NixExprKind::InterpolatedPath(parts) => {
+                let parts = parts
+                    .iter()
+                    .map(|part| match part {
+                        NixStringPart::Literal(s) => NixStringPart::Literal(s.clone()),
+                        NixStringPart::Interpolation(_) => NixStringPart::Interpolation(next()),
+                    })
+                    .collect();
+                match self {
+                    NixExprKind::InterpolatedPath(_) => NixExprKind::InterpolatedPath(parts),
+                    _ => NixExprKind::InterpolatedString(parts),
+                }
+            }
+            NixExprKind::List(_) => NixExprKind::List(children.collect()),
+            NixExprKind::Lambda { param, .. } => NixExprKind::Lambda {
+                param: param.clone(),
+                body: next(),
+            },
+            NixExprKind::AttrSet {
+                recursive,
+                bindings,
+            } => NixExprKind::AttrSet {
+                recursive: *recursive,
+                bindings: bindings.keys().cloned().zip(children).collect(),
+            },
+            NixExprKind::LetIn { bindings, .. } => NixExprKind::LetIn {
+                bindings: bindings.keys().cloned().zip(children.by_ref()).collect(),
+                body: Box::new(children.next().expect("a let has a body")),
+            },
+            NixExprKind::UnaryOp { op, .. } => NixExprKind::UnaryOp {
+                op: *op,
+                expr: next(),
+            },
+            NixExprKind::BinaryOp { op, .. } => NixExprKind::BinaryOp {
+                op: *op,
+                left: next(),
+                right: next(),
+            },
+            NixExprKind::With { .. } => NixExprKind::With {
+                environment: next(),
+                body: next(),
+            },
+            NixExprKind::Assert { .. } => NixExprKind::Assert {
+                condition: next(),
+                body: next(),
+            },
+            NixExprKind::Apply { .. } => NixExprKind::Apply {
+                function: next(),
+                argument: next(),
+            },
+        }
+    }
+
+    /// Moves the nested expressions out of this node, leaving placeholders behind.
+    fn take_children(&mut self) -> Vec<NixExpr> {
+        let take = |expr: &mut Box<NixExpr>| {
+            std::mem::replace(
+                &mut **expr,
+                NixExpr::new(NixExprKind::Value(NixValue::Null), Span::default()),
+            )
+        };
+        match self {
+            NixExprKind::List(items) => std::mem::take(items),
+            NixExprKind::AttrSet { bindings, .. } => {
+                std::mem::take(bindings).into_values().collect()
+            }
+            NixExprKind::LetIn { bindings, body } => {
+                let mut children: Vec<_> = std::mem::take(bindings).into_values().collect();
+                children.push(take(body));
+                children
+            }
+            NixExprKind::InterpolatedString(parts) | NixExprKind::InterpolatedPath(parts) => {
+                std::mem::take(parts)
+                    .into_iter()
+                    .filter_map(|part| match part {
+                        NixStringPart::Interpolation(expr) => Some(*expr),
+                        NixStringPart::Literal(_) => None,
+                    })
+                    .collect()
+            }
+            NixExprKind::UnaryOp { expr, .. } | NixExprKind::Lambda { body: expr, .. } => {
+                vec![take(expr)]
+            }
+            NixExprKind::BinaryOp {
+                left: first,
+                right: second,
+                ..
+            }
+            | NixExprKind::With {
+                environment: first,
+                body: second,
+            }
+            | NixExprKind::Assert {
+                condition: first,
+                body: second,
+            }
+            | NixExprKind::Apply {
+                function: first,
+                argument: second,
+            } => vec![take(first), take(second)],
+            NixExprKind::Value(_)
+            | NixExprKind::Ref(_)
+            | NixExprKind::Inherit(_)
+            | NixExprKind::SearchPath(_)
+            | NixExprKind::Error => Vec::new(),
+        }
+    }
 }
 
-// TODO: boxes
 #[derive(Debug, Clone, PartialEq)]
-pub enum NixExpr {
+pub enum NixExprKind {
     Value(NixValue),
     InterpolatedString(Vec<NixStringPart>),
+    /// A path with interpolations, like `./modules/${name}.nix`. The first part is the
+    /// literal prefix, already resolved like a plain path.
+    InterpolatedPath(Vec<NixStringPart>),
     Ref(String),
+    /// `inherit name;`. Unlike a [`NixExprKind::Ref`], it is resolved in the scope
+    /// around the `let` or `rec` set it appears in, not in the set itself.
+    Inherit(String),
     List(Vec<NixExpr>),
     AttrSet {
         recursive: bool,
@@ -62,18 +297,44 @@ pub enum NixExpr {
         environment: Box<NixExpr>,
         body: Box<NixExpr>,
     },
-    // Future additions:
-    // Function(...)
-    // Thunk(...)
+    Assert {
+        condition: Box<NixExpr>,
+        body: Box<NixExpr>,
+    },
+    Apply {
+        function: Box<NixExpr>,
+        argument: Box<NixExpr>,
+    },
+    /// A function of a single parameter, `param: body`.
+    Lambda {
+        param: String,
+        body: Box<NixExpr>,
+    },
+    /// Placeholder for a region that failed to parse, only produced by
+    /// [`parser::parse_recovering`].
+    Error,
 }
 
-pub fn nix_str(input: &str, root: &Path) -> Result<NixExpr, String> {
-    parser::parse(input, root).map_err(|e| e.to_string())
+pub fn nix_str(input: &str, root: &Path) -> Result<NixExpr, ParseError> {
+    parser::parse(input, root)
 }
 
-pub fn nix_file(path: impl AsRef<std::path::Path>, root: &Path) -> Result<NixExpr, String> {
+pub fn nix_file(path: impl AsRef<std::path::Path>, root: &Path) -> Result<NixExpr, ParseError> {
     let path_ref = path.as_ref();
-    let content = std::fs::read_to_string(path_ref)
-        .map_err(|e| format!("Failed to read file '{}': {}", path_ref.display(), e))?;
+    let content = std::fs::read_to_string(path_ref).map_err(|e| ParseError::Io {
+        path: path_ref.to_path_buf(),
+        message: e.to_string(),
+    })?;
     nix_str(&content, root)
 }
+
+/// Evaluates a file with `scope` visible to it and to every file it imports.
+pub fn nix_file_with_scope(path: impl AsRef<Path>, scope: Scope) -> Result<Value, EvaluationError> {
+    let evaluator = scope
+        .into_iter()
+        .fold(Evaluator::builder(), |builder, (name, value)| {
+            builder.builtin(name, value)
+        })
+        .build();
+    evaluator.eval_file(path)
+}
@@ -0,0 +1,127 @@
+use crate::eval::EvaluationError;
+use crate::value::Value;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Bounds on the resources an evaluation may use, for evaluating untrusted input.
+/// `None` means unlimited, which is the default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// How deeply expressions may be nested during evaluation, where the body of a
+    /// thunk or function is nested in the expression that forces or calls it.
+    /// Evaluation doesn't use the native stack for nesting, so this only bounds the
+    /// memory it takes.
+    pub max_depth: Option<usize>,
+    /// How many expressions a single evaluation may evaluate, or instructions with
+    /// [`Backend::Bytecode`](crate::eval::Backend::Bytecode).
+    pub max_steps: Option<u64>,
+    /// The longest string, in bytes, evaluation may produce.
+    pub max_string_length: Option<usize>,
+    pub max_list_length: Option<usize>,
+    /// The most attributes a single attribute set may have.
+    pub max_attrset_size: Option<usize>,
+    /// How long a single evaluation may take before it is abandoned.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::unlimited()
+    }
+}
+
+impl Limits {
+    /// No limits at all.
+    pub fn unlimited() -> Self {
+        Limits {
+            max_depth: None,
+            max_steps: None,
+            max_string_length: None,
+            max_list_length: None,
+            max_attrset_size: None,
+            timeout: None,
+        }
+    }
+
+    /// Checks the limits that apply before evaluating one more expression, `depth`
+    /// levels deep and as the `steps`th expression of an evaluation that has to finish
+    /// by `deadline`.
+    pub(crate) fn check_step(
+        &self,
+        depth: usize,
+        steps: u64,
+        deadline: Option<Instant>,
+    ) -> Result<(), EvaluationError> {
+        if let Some(max) = self.max_depth
+            && depth > max
+        {
+            return Err(exceeded(ResourceLimit::RecursionDepth(max)));
+        }
+        if let Some(max) = self.max_steps
+            && steps > max
+        {
+            return Err(exceeded(ResourceLimit::Steps(max)));
+        }
+        if let Some(deadline) = deadline
+            && Instant::now() >= deadline
+            && let Some(timeout) = self.timeout
+        {
+            return Err(exceeded(ResourceLimit::Timeout(timeout)));
+        }
+        Ok(())
+    }
+
+    /// Checks the size limits against a freshly evaluated value.
+    pub(crate) fn check_value(&self, value: &Value) -> Result<(), EvaluationError> {
+        let (len, max, limit): (_, _, fn(usize) -> ResourceLimit) = match value {
+            Value::String(s) => (
+                s.as_str().len(),
+                self.max_string_length,
+                ResourceLimit::StringLength,
+            ),
+            Value::List(items) => (items.len(), self.max_list_length, ResourceLimit::ListLength),
+            Value::Attrs(bindings) => (
+                bindings.len(),
+                self.max_attrset_size,
+                ResourceLimit::AttrsetSize,
+            ),
+            _ => return Ok(()),
+        };
+        match max {
+            Some(max) if len > max => Err(exceeded(limit(max))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn exceeded(limit: ResourceLimit) -> EvaluationError {
+    EvaluationError::ResourceLimitExceeded(limit)
+}
+
+/// A limit from [`Limits`] that evaluation ran into, along with its configured value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    RecursionDepth(usize),
+    Steps(u64),
+    StringLength(usize),
+    ListLength(usize),
+    AttrsetSize(usize),
+    Timeout(Duration),
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceLimit::RecursionDepth(max) => write!(f, "maximum recursion depth of {}", max),
+            ResourceLimit::Steps(max) => write!(f, "maximum of {} evaluation steps", max),
+            ResourceLimit::StringLength(max) => {
+                write!(f, "maximum string length of {} bytes", max)
+            }
+            ResourceLimit::ListLength(max) => write!(f, "maximum list length of {}", max),
+            ResourceLimit::AttrsetSize(max) => {
+                write!(f, "maximum of {} attributes per set", max)
+            }
+            ResourceLimit::Timeout(timeout) => write!(f, "time limit of {:?}", timeout),
+        }
+    }
+}
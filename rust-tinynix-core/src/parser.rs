@@ -1,251 +1,1660 @@
-use crate::{NixBinaryOp, NixExpr, NixStringPart, NixUnaryOp, NixValue};
+use crate::ast::{Ast, Binding, ExprId, ExprKind, Literal, Slice, StringPart};
+use crate::diagnostics::Diagnostic;
+use crate::span::{FileId, LineIndex, Location, Span};
+use crate::{NixBinaryOp, NixExpr, NixUnaryOp};
 use indexmap::IndexMap;
-use pest::Parser;
-use pest::iterators::{Pair, Pairs};
-use pest::pratt_parser::{Assoc, Op, PrattParser};
+use lexer::{Token, TokenKind};
+use std::borrow::Cow;
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
 
-#[derive(pest_derive::Parser)]
-#[grammar = "grammar.pest"]
-struct NixParser;
+mod lexer;
+#[cfg(test)]
+mod parity;
+mod recovery;
+mod token_stream;
 
-static PRATT_PARSER: OnceLock<PrattParser<Rule>> = OnceLock::new();
+pub use recovery::{PartialParse, parse_recovering, parse_source_recovering};
+pub use token_stream::{TokenSource, parse_tokens};
 
-fn get_pratt_parser() -> &'static PrattParser<Rule> {
-    PRATT_PARSER.get_or_init(|| {
-        use Assoc::*;
-        use Rule::*;
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The input does not match the grammar.
+    Syntax { message: String, location: Location },
+    /// An integer literal does not fit into an `i64`.
+    IntegerOverflow { literal: String, location: Location },
+    /// A float literal could not be converted into an `f64`.
+    InvalidFloat { literal: String, location: Location },
+    /// The same attribute is defined more than once, first at `previous`.
+    DuplicateAttribute {
+        path: String,
+        location: Location,
+        previous: Location,
+    },
+    /// An attribute path descends into an attribute that is not an attribute set, but
+    /// the value defined at `previous`.
+    ConflictingAttributePath {
+        path: String,
+        location: Location,
+        previous: Location,
+    },
+    /// A `~/` path was used but the home directory could not be determined.
+    HomeDirectoryNotFound { location: Location },
+    /// The source file could not be read.
+    Io { path: PathBuf, message: String },
+}
+
+impl ParseError {
+    /// Where in the source the error occurred, if it is tied to a position at all.
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            ParseError::Syntax { location, .. }
+            | ParseError::IntegerOverflow { location, .. }
+            | ParseError::InvalidFloat { location, .. }
+            | ParseError::DuplicateAttribute { location, .. }
+            | ParseError::ConflictingAttributePath { location, .. }
+            | ParseError::HomeDirectoryNotFound { location } => Some(*location),
+            ParseError::Io { .. } => None,
+        }
+    }
+
+    /// The error message without its location.
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::Syntax { message, .. } => format!("syntax error: {}", message),
+            ParseError::IntegerOverflow { literal, .. } => {
+                format!("integer literal '{}' is out of range", literal)
+            }
+            ParseError::InvalidFloat { literal, .. } => {
+                format!("invalid float literal '{}'", literal)
+            }
+            ParseError::DuplicateAttribute { path, .. } => {
+                format!("attribute '{}' already defined", path)
+            }
+            ParseError::ConflictingAttributePath { path, .. } => format!(
+                "attribute path '{}' conflicts with an existing non-attribute-set value",
+                path
+            ),
+            ParseError::HomeDirectoryNotFound { .. } => {
+                "could not determine the home directory".to_string()
+            }
+            ParseError::Io { path, message } => {
+                format!("failed to read file '{}': {}", path.display(), message)
+            }
+        }
+    }
+
+    /// Turns the error into a [`Diagnostic`] pointing into `file`.
+    pub fn to_diagnostic(&self, file: FileId) -> Diagnostic {
+        let diagnostic = Diagnostic::error(self.message());
+        let Some(location) = self.location() else {
+            return diagnostic;
+        };
+        let span = Span::new(file, location, location);
+        match self {
+            ParseError::DuplicateAttribute { previous, .. } => diagnostic
+                .with_label(span, "attribute defined again here")
+                .with_secondary_label(
+                    Span::new(file, *previous, *previous),
+                    "attribute defined here",
+                ),
+            ParseError::ConflictingAttributePath { previous, .. } => diagnostic
+                .with_label(span, "this path descends into a non-attribute-set value")
+                .with_secondary_label(Span::new(file, *previous, *previous), "value defined here")
+                .with_help("define the intermediate attributes as an attribute set"),
+            ParseError::IntegerOverflow { .. } => diagnostic
+                .with_label(span, "does not fit into a 64-bit signed integer")
+                .with_note(format!("the largest integer is {}", i64::MAX)),
+            _ => diagnostic.with_label(span, ""),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location() {
+            Some(location) => write!(f, "{}: {}", location, self.message()),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A parser building into an [`Ast`].
+///
+/// It parses `text` from `pos` on. [`recovery`] re-parses fragments of a broken source
+/// by ending `text` early, so offsets are always relative to the whole `source`.
+///
+/// Constructs that contain others, like parentheses, sets and strings, are suspended as
+/// a [`Frame`] while the nested construct is parsed, instead of recursing on the native
+/// stack, so expressions can nest as deeply as memory allows.
+struct Parser<'src, 'a> {
+    source: &'src str,
+    text: &'src str,
+    pos: usize,
+    root: &'a Path,
+    file: FileId,
+    lines: &'a LineIndex,
+    ast: &'a mut Ast<'src>,
+}
+
+/// What wraps the body of a chain like `x: let y = x; in with y; body`.
+enum Head<'src> {
+    Lambda(&'src str),
+    Let(Slice<Binding<'src>>),
+    With(ExprId),
+    Assert(ExprId),
+}
+
+/// The parts of an indented string before its indentation is stripped.
+enum Piece<'src> {
+    Text(&'src str),
+    Escaped(Cow<'src, str>),
+    Interpolation(ExprId),
+}
+
+/// A construct that is waiting for the one nested inside it to be parsed.
+enum Frame<'src> {
+    /// An expression, with the heads wrapping its body so far, and what the nested
+    /// construct being parsed will become, along with where that starts.
+    Expr {
+        heads: Vec<(usize, Head<'src>)>,
+        awaiting: Option<(usize, Awaiting)>,
+    },
+    /// The body of an expression: applications, prefix operators, `+`, `-` and `//`.
+    Operators(Operators),
+    /// `(` expr `)`, after the `(`.
+    Paren,
+    List {
+        start: usize,
+        items: Vec<ExprId>,
+    },
+    /// The bindings of a set, from its `{` on, or of a `let`, up to and including the
+    /// `in`. Sets know where they start and whether they are recursive.
+    Bindings {
+        set: Option<(usize, bool)>,
+        pending: PendingBindings<'src>,
+        expected: &'static str,
+    },
+    Binding {
+        expected: &'static str,
+        stage: BindingStage<'src>,
+    },
+    /// A dotted attribute path, which is a variable reference if `reference` is set.
+    /// `start` is known once its first component has been checked.
+    AttrPath {
+        start: Option<usize>,
+        expected: &'static str,
+        reference: bool,
+    },
+    /// A `"` string, after its opening quote. Quoted attribute names are `name`s, whose
+    /// text is taken from the source later on.
+    String {
+        start: usize,
+        parts: Vec<StringPart<'src>>,
+        name: bool,
+    },
+    /// A `''` string, after its opening quotes.
+    IndentedString {
+        start: usize,
+        pieces: Vec<Piece<'src>>,
+    },
+    /// A path with interpolations, after the literal part of its first token.
+    Path {
+        start: usize,
+        parts: Vec<StringPart<'src>>,
+    },
+}
 
-        PrattParser::new().op(Op::infix(infix_op, Left))
-    })
+/// What the construct nested in an expression is for.
+enum Awaiting {
+    Let,
+    With,
+    Assert,
+    Body,
 }
 
-fn parse_op_expr(pairs: Pairs<Rule>, root: &Path) -> NixExpr {
-    get_pratt_parser()
-        .map_primary(|primary| build_nix_expr_from_pair(primary, root))
-        .map_infix(|lhs, op, rhs| {
-            let op_pair = op.into_inner().next().unwrap();
-            let op = match op_pair.as_rule() {
-                Rule::add => NixBinaryOp::Add,
-                Rule::sub => NixBinaryOp::Sub,
-                _ => unreachable!("Encountered non-infix operator in infix position"),
+#[derive(Default)]
+struct Operators {
+    /// The operands of `//` so far, each a complete sum.
+    updates: Vec<ExprId>,
+    /// The sum so far, and the operator following it.
+    sum: Option<(ExprId, NixBinaryOp)>,
+    /// The prefix operators of the current term, with where they start.
+    prefixes: Vec<(NixUnaryOp, usize)>,
+    /// The application in the current term so far.
+    function: Option<ExprId>,
+}
+
+enum BindingStage<'src> {
+    Start,
+    /// The attribute path of `path = value;` is being parsed. The binding starts at the
+    /// offset.
+    Path(usize),
+    Value(usize, &'src str),
+    /// The scope of `inherit (scope) names;` is being parsed.
+    InheritScope,
+}
+
+/// A binding as written, before it is merged into the bindings around it.
+enum ParsedBinding<'src> {
+    Value {
+        path: &'src str,
+        value: ExprId,
+        span: Span,
+    },
+    Inherit {
+        scope: Option<&'src str>,
+        names: Vec<Token>,
+    },
+}
+
+/// What a finished construct hands to the one it is nested in.
+enum Output<'src> {
+    Expr(ExprId),
+    Bindings(Slice<Binding<'src>>),
+    Binding(ParsedBinding<'src>),
+    Path(&'src str),
+    /// A quoted attribute name has been parsed.
+    Name,
+}
+
+impl<'src> Output<'src> {
+    fn expr(self) -> ExprId {
+        match self {
+            Output::Expr(expr) => expr,
+            _ => unreachable!("the nested construct is an expression"),
+        }
+    }
+
+    fn path(self) -> &'src str {
+        match self {
+            Output::Path(path) => path,
+            _ => unreachable!("the nested construct is an attribute path"),
+        }
+    }
+}
+
+enum Step<'src> {
+    /// Suspends the current construct to parse a nested one.
+    Push(Frame<'src>),
+    Done(Output<'src>),
+}
+
+/// An atom that was either parsed right away, or starts a construct of its own.
+enum Atom<'src> {
+    Parsed(ExprId),
+    Nested(Frame<'src>),
+}
+
+impl<'src> Frame<'src> {
+    fn expr() -> Self {
+        Frame::Expr {
+            heads: Vec::new(),
+            awaiting: None,
+        }
+    }
+
+    fn attr_path(expected: &'static str) -> Self {
+        Frame::AttrPath {
+            start: None,
+            expected,
+            reference: false,
+        }
+    }
+
+    fn binding(expected: &'static str) -> Self {
+        Frame::Binding {
+            expected,
+            stage: BindingStage::Start,
+        }
+    }
+}
+
+impl<'src, 'a> Parser<'src, 'a> {
+    fn new(
+        source: &'src str,
+        pos: usize,
+        end: usize,
+        root: &'a Path,
+        file: FileId,
+        lines: &'a LineIndex,
+        ast: &'a mut Ast<'src>,
+    ) -> Self {
+        Parser {
+            source,
+            text: &source[..end],
+            pos,
+            root,
+            file,
+            lines,
+            ast,
+        }
+    }
+
+    fn location(&self, offset: usize) -> Location {
+        self.lines.location(self.source, offset)
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(self.file, self.location(start), self.location(end))
+    }
+
+    fn push(&mut self, kind: ExprKind<'src>, start: usize, end: usize) -> ExprId {
+        let span = self.span(start, end);
+        self.ast.push(kind, span)
+    }
+
+    /// Wraps `body` in a node starting at `start`.
+    fn push_around(&mut self, kind: ExprKind<'src>, start: usize, body: ExprId) -> ExprId {
+        let span = Span::new(self.file, self.location(start), self.ast[body].span.end);
+        self.ast.push(kind, span)
+    }
+
+    /// The next token after `pos`, skipping whitespace and comments.
+    fn token_after(&self, pos: usize) -> Token {
+        lexer::skip_trivia(self.text, pos)
+            .map_or_else(|token| token, |pos| lexer::token_at(self.text, pos))
+    }
+
+    fn peek(&self) -> Token {
+        self.token_after(self.pos)
+    }
+
+    fn eat(&mut self, kind: TokenKind) -> Option<Token> {
+        let token = self.peek();
+        (token.kind == kind).then(|| {
+            self.pos = token.end;
+            token
+        })
+    }
+
+    fn expect(&mut self, kind: TokenKind, expected: &str) -> Result<Token, ParseError> {
+        let token = self.peek();
+        if token.kind != kind {
+            return Err(self.unexpected(token, expected));
+        }
+        self.pos = token.end;
+        Ok(token)
+    }
+
+    fn unexpected(&self, token: Token, expected: &str) -> ParseError {
+        // Where a fragment ends early, report what follows it in the whole source.
+        let token = match token.kind {
+            TokenKind::Eof => lexer::skip_trivia(self.source, token.start)
+                .map_or_else(|token| token, |pos| lexer::token_at(self.source, pos)),
+            _ => token,
+        };
+        let message = match token.kind {
+            TokenKind::Eof => format!("expected {}, found the end of the input", expected),
+            TokenKind::UnterminatedComment => "unterminated comment".to_string(),
+            _ => format!(
+                "expected {}, found '{}'",
+                expected,
+                &self.source[token.start..token.end]
+            ),
+        };
+        self.syntax_error(token.start, message)
+    }
+
+    fn syntax_error(&self, offset: usize, message: impl Into<String>) -> ParseError {
+        ParseError::Syntax {
+            message: message.into(),
+            location: self.location(offset),
+        }
+    }
+
+    /// Parses the construct `frame` stands for, along with everything nested in it.
+    fn run(&mut self, frame: Frame<'src>) -> Result<Output<'src>, ParseError> {
+        let mut frames = vec![frame];
+        let mut input = None;
+        while let Some(frame) = frames.last_mut() {
+            match self.resume(frame, input.take())? {
+                Step::Push(nested) => frames.push(nested),
+                Step::Done(output) => {
+                    frames.pop();
+                    input = Some(output);
+                }
+            }
+        }
+        Ok(input.expect("the outermost construct has been parsed"))
+    }
+
+    /// Continues parsing `frame`, with what the construct nested in it turned into, or
+    /// `None` if it has just been started.
+    fn resume(
+        &mut self,
+        frame: &mut Frame<'src>,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        match frame {
+            Frame::Expr { heads, awaiting } => self.expr(heads, awaiting, input),
+            Frame::Operators(operators) => self.operators(operators, input),
+            Frame::Paren => match input {
+                None => Ok(Step::Push(Frame::expr())),
+                Some(output) => {
+                    self.expect(TokenKind::RightParen, "')'")?;
+                    Ok(Step::Done(output))
+                }
+            },
+            Frame::List { start, items } => self.list(*start, items, input),
+            Frame::Bindings {
+                set,
+                pending,
+                expected,
+            } => self.bindings(*set, pending, expected, input),
+            Frame::Binding { expected, stage } => self.binding(expected, stage, input),
+            Frame::AttrPath {
+                start,
+                expected,
+                reference,
+            } => self.attr_path(start, expected, *reference, input),
+            Frame::String { start, parts, name } => self.string_parts(*start, parts, *name, input),
+            Frame::IndentedString { start, pieces } => {
+                self.indented_string_parts(*start, pieces, input)
+            }
+            Frame::Path { start, parts } => self.path_parts(*start, parts, input),
+        }
+    }
+
+    /// A whole source: one expression and nothing after it.
+    fn source(&mut self) -> Result<ExprId, ParseError> {
+        let expr = self.run(Frame::expr())?.expr();
+        self.expect(TokenKind::Eof, "the end of the input")?;
+        Ok(expr)
+    }
+
+    /// A single binding and nothing after it.
+    fn binding_entry(&mut self, bindings: &mut PendingBindings<'src>) -> Result<(), ParseError> {
+        let Output::Binding(binding) = self.run(Frame::binding("a binding"))? else {
+            unreachable!("a binding has been parsed");
+        };
+        self.insert_binding(bindings, binding)?;
+        self.expect(TokenKind::Eof, "the end of the binding")?;
+        Ok(())
+    }
+
+    /// An attribute path and nothing after it.
+    fn attr_path_entry(&mut self) -> Result<&'src str, ParseError> {
+        let path = self.run(Frame::attr_path("an attribute path"))?.path();
+        self.expect(TokenKind::Eof, "'='")?;
+        Ok(path)
+    }
+
+    /// Lambdas, `let`, `with` and `assert` extend as far to the right as possible, so
+    /// chains of them are collected first and wrapped around the final body from the
+    /// inside out.
+    fn expr(
+        &mut self,
+        heads: &mut Vec<(usize, Head<'src>)>,
+        awaiting: &mut Option<(usize, Awaiting)>,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        if let Some((start, awaiting)) = awaiting.take() {
+            let output = input.expect("the nested construct has been parsed");
+            let head = match (awaiting, output) {
+                (Awaiting::Let, Output::Bindings(bindings)) => Head::Let(bindings),
+                (Awaiting::With, Output::Expr(environment)) => {
+                    self.expect(TokenKind::Semicolon, "';'")?;
+                    Head::With(environment)
+                }
+                (Awaiting::Assert, Output::Expr(condition)) => {
+                    self.expect(TokenKind::Semicolon, "';'")?;
+                    Head::Assert(condition)
+                }
+                (Awaiting::Body, Output::Expr(mut body)) => {
+                    for (start, head) in heads.drain(..).rev() {
+                        let kind = match head {
+                            Head::Lambda(param) => ExprKind::Lambda { param, body },
+                            Head::Let(bindings) => ExprKind::LetIn { bindings, body },
+                            Head::With(environment) => ExprKind::With { environment, body },
+                            Head::Assert(condition) => ExprKind::Assert { condition, body },
+                        };
+                        body = self.push_around(kind, start, body);
+                    }
+                    return Ok(Step::Done(Output::Expr(body)));
+                }
+                _ => unreachable!("heads are built from what they were waiting for"),
             };
-            NixExpr::BinaryOp {
-                op,
-                left: Box::new(lhs),
-                right: Box::new(rhs),
+            heads.push((start, head));
+        }
+        loop {
+            let token = self.peek();
+            let (next, nested) = match token.kind {
+                TokenKind::Identifier if self.token_after(token.end).kind == TokenKind::Colon => {
+                    self.pos = self.token_after(token.end).end;
+                    let param = &self.text[token.start..token.end];
+                    heads.push((token.start, Head::Lambda(param)));
+                    continue;
+                }
+                TokenKind::Let => {
+                    self.pos = token.end;
+                    let bindings = Frame::Bindings {
+                        set: None,
+                        pending: PendingBindings::default(),
+                        expected: "a binding",
+                    };
+                    (Awaiting::Let, bindings)
+                }
+                TokenKind::With => {
+                    self.pos = token.end;
+                    (Awaiting::With, Frame::expr())
+                }
+                TokenKind::Assert => {
+                    self.pos = token.end;
+                    (Awaiting::Assert, Frame::expr())
+                }
+                _ => (Awaiting::Body, Frame::Operators(Operators::default())),
+            };
+            *awaiting = Some((token.start, next));
+            return Ok(Step::Push(nested));
+        }
+    }
+
+    /// Applications bind most tightly, then prefix operators, then `+` and `-` to the
+    /// left, and finally `//` to the right.
+    fn operators(
+        &mut self,
+        operators: &mut Operators,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        let mut parsed = match input {
+            Some(output) => Some(output.expr()),
+            None => {
+                self.prefix_operators(&mut operators.prefixes);
+                None
             }
-        })
-        .parse(pairs)
-}
-
-fn build_nix_expr_from_pair(pair: Pair<Rule>, root: &Path) -> NixExpr {
-    match pair.as_rule() {
-        // --- Structural Rules that simply wrap another rule ---
-        Rule::nix_expression | Rule::atomic_expr | Rule::literal | Rule::path_types => {
-            build_nix_expr_from_pair(pair.into_inner().next().unwrap(), root)
-        }
-
-        // --- Logic Rules ---
-        Rule::op_expr => parse_op_expr(pair.into_inner(), root),
-        Rule::let_in_expr => {
-            let mut pairs = pair.into_inner();
-            let body_pair = pairs
-                .next_back()
-                .expect("let-in expression must have a body");
-            let body = build_nix_expr_from_pair(body_pair, root);
-            let bindings = build_bindings_from_pairs(pairs, root);
-            NixExpr::LetIn {
-                bindings,
-                body: Box::new(body),
-            }
-        }
-        Rule::with_expr => {
-            let mut pairs = pair.into_inner();
-            let environment_pair = pairs
-                .next()
-                .expect("with expression must have an environment");
-            let body_pair = pairs.next().expect("with expression must have a body");
-            let environment = build_nix_expr_from_pair(environment_pair, root);
-            let body = build_nix_expr_from_pair(body_pair, root);
-            NixExpr::With {
-                environment: Box::new(environment),
-                body: Box::new(body),
-            }
-        }
-        Rule::term => {
-            let mut pairs = pair.into_inner();
-            let atomic_pair = pairs.next_back().unwrap();
-            let mut current_expr = build_nix_expr_from_pair(atomic_pair, root);
-
-            // The remaining pairs are prefix_ops. We iterate in reverse to apply them
-            // from the inside out (closest to the atomic_expr first).
-            for op_pair in pairs.rev() {
-                let inner_op = op_pair.into_inner().next().unwrap();
-                let op = match inner_op.as_rule() {
-                    Rule::arith_neg => NixUnaryOp::Neg,
-                    Rule::logic_neg => NixUnaryOp::Not,
-                    _ => unreachable!("Encountered a non-prefix operator in a term"),
-                };
-                current_expr = NixExpr::UnaryOp {
-                    op,
-                    expr: Box::new(current_expr),
+        };
+        loop {
+            if let Some(argument) = parsed.take() {
+                let function = match operators.function.take() {
+                    Some(function) => {
+                        let span = self.ast[function].span.to(self.ast[argument].span);
+                        let kind = ExprKind::Apply { function, argument };
+                        self.ast.push(kind, span)
+                    }
+                    None => argument,
                 };
+                if starts_atom(self.peek().kind) {
+                    operators.function = Some(function);
+                } else {
+                    let mut term = function;
+                    for (op, start) in operators.prefixes.drain(..).rev() {
+                        term = self.push_around(ExprKind::UnaryOp { op, expr: term }, start, term);
+                    }
+                    let sum = match operators.sum.take() {
+                        Some((left, op)) => self.binary(op, left, term),
+                        None => term,
+                    };
+                    let op = match self.peek().kind {
+                        TokenKind::Plus => Some(NixBinaryOp::Add),
+                        TokenKind::Minus => Some(NixBinaryOp::Sub),
+                        _ => None,
+                    };
+                    if let Some(op) = op {
+                        self.pos = self.peek().end;
+                        operators.sum = Some((sum, op));
+                    } else {
+                        operators.updates.push(sum);
+                        if self.eat(TokenKind::Update).is_none() {
+                            let updates = &mut operators.updates;
+                            let mut right = updates.pop().expect("there is at least one operand");
+                            while let Some(left) = updates.pop() {
+                                right = self.binary(NixBinaryOp::Update, left, right);
+                            }
+                            return Ok(Step::Done(Output::Expr(right)));
+                        }
+                    }
+                    self.prefix_operators(&mut operators.prefixes);
+                }
+            }
+            match self.atom()? {
+                Atom::Parsed(atom) => parsed = Some(atom),
+                Atom::Nested(frame) => return Ok(Step::Push(frame)),
             }
-            current_expr
         }
+    }
 
-        // --- Concrete Atomic Rules ---
-        Rule::integer => NixExpr::Value(NixValue::Int(pair.as_str().parse().unwrap())),
-        Rule::float => NixExpr::Value(NixValue::Float(pair.as_str().parse().unwrap())),
-        Rule::boolean => NixExpr::Value(NixValue::Bool(pair.as_str() == "true")),
-        Rule::null => NixExpr::Value(NixValue::Null),
-        Rule::identifier => NixExpr::Ref(pair.as_str().to_string()),
-        Rule::list => NixExpr::List(
-            pair.into_inner()
-                .map(|p| build_nix_expr_from_pair(p, root))
-                .collect(),
-        ),
-        Rule::attrset => {
-            let mut inner = pair.into_inner();
-            let mut recursive = false;
-            if let Some(token) = inner.peek() {
-                if token.as_rule() == Rule::rec {
-                    recursive = true;
-                    inner.next();
-                }
-            }
-            let bindings = build_bindings_from_pairs(inner, root);
-            NixExpr::AttrSet {
-                recursive,
-                bindings,
+    /// Collects the prefix operators of a term.
+    fn prefix_operators(&mut self, prefixes: &mut Vec<(NixUnaryOp, usize)>) {
+        loop {
+            let token = self.peek();
+            let op = match token.kind {
+                TokenKind::Minus => NixUnaryOp::Neg,
+                TokenKind::Bang => NixUnaryOp::Not,
+                _ => return,
+            };
+            self.pos = token.end;
+            prefixes.push((op, token.start));
+        }
+    }
+
+    fn binary(&mut self, op: NixBinaryOp, left: ExprId, right: ExprId) -> ExprId {
+        let span = self.ast[left].span.to(self.ast[right].span);
+        self.ast.push(ExprKind::BinaryOp { op, left, right }, span)
+    }
+
+    fn atom(&mut self) -> Result<Atom<'src>, ParseError> {
+        let token = self.peek();
+        let text = self.text;
+        let kind = match token.kind {
+            TokenKind::Integer | TokenKind::Float => ExprKind::Value(self.number(token)?),
+            TokenKind::True => ExprKind::Value(Literal::Bool(true)),
+            TokenKind::False => ExprKind::Value(Literal::Bool(false)),
+            TokenKind::Null => ExprKind::Value(Literal::Null),
+            TokenKind::SearchPath => {
+                ExprKind::SearchPath(text[token.start + 1..token.end - 1].trim())
+            }
+            TokenKind::Quote => {
+                self.pos = token.end;
+                return Ok(Atom::Nested(Frame::String {
+                    start: token.start,
+                    parts: Vec::new(),
+                    name: false,
+                }));
+            }
+            TokenKind::IndentQuote => {
+                self.pos = token.end;
+                return Ok(Atom::Nested(Frame::IndentedString {
+                    start: token.start,
+                    pieces: Vec::new(),
+                }));
             }
+            TokenKind::Path => {
+                self.pos = token.end;
+                return self.path(token);
+            }
+            TokenKind::LeftBrace | TokenKind::Rec => {
+                let recursive = token.kind == TokenKind::Rec;
+                if recursive {
+                    self.pos = token.end;
+                }
+                return Ok(Atom::Nested(Frame::Bindings {
+                    set: Some((token.start, recursive)),
+                    pending: PendingBindings::default(),
+                    expected: "a binding or '}'",
+                }));
+            }
+            TokenKind::LeftBracket => {
+                return Ok(Atom::Nested(Frame::List {
+                    start: token.start,
+                    items: Vec::new(),
+                }));
+            }
+            TokenKind::Identifier => {
+                return Ok(Atom::Nested(Frame::AttrPath {
+                    start: None,
+                    expected: "an expression",
+                    reference: true,
+                }));
+            }
+            TokenKind::LeftParen => {
+                self.pos = token.end;
+                return Ok(Atom::Nested(Frame::Paren));
+            }
+            _ => return Err(self.unexpected(token, "an expression")),
+        };
+        self.pos = token.end;
+        Ok(Atom::Parsed(self.push(kind, token.start, token.end)))
+    }
+
+    fn number(&self, token: Token) -> Result<Literal<'src>, ParseError> {
+        let literal = &self.text[token.start..token.end];
+        if token.kind == TokenKind::Float {
+            return literal
+                .parse()
+                .map(Literal::Float)
+                .map_err(|_| ParseError::InvalidFloat {
+                    literal: literal.to_string(),
+                    location: self.location(token.start),
+                });
         }
-        Rule::string => {
-            let mut parts: Vec<NixStringPart> = Vec::new();
-            for string_content_pair in pair.into_inner() {
-                let part = string_content_pair.into_inner().next().unwrap();
-                match part.as_rule() {
-                    Rule::string_literal_part
-                    | Rule::dollar_literal
-                    | Rule::single_quote_literal => {
-                        parts.push(NixStringPart::Literal(part.as_str().to_string()));
+        literal
+            .parse()
+            .map(Literal::Int)
+            .map_err(|_| ParseError::IntegerOverflow {
+                literal: literal.to_string(),
+                location: self.location(token.start),
+            })
+    }
+
+    fn list(
+        &mut self,
+        start: usize,
+        items: &mut Vec<ExprId>,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        match input {
+            Some(item) => items.push(item.expr()),
+            None => {
+                self.expect(TokenKind::LeftBracket, "'['")?;
+            }
+        }
+        loop {
+            let token = self.peek();
+            if token.kind == TokenKind::RightBracket {
+                self.pos = token.end;
+                let items = self.ast.push_items(std::mem::take(items));
+                let list = self.push(ExprKind::List(items), start, token.end);
+                return Ok(Step::Done(Output::Expr(list)));
+            }
+            if !starts_atom(token.kind) {
+                return Err(self.unexpected(token, "a list element or ']'"));
+            }
+            match self.atom()? {
+                Atom::Parsed(item) => items.push(item),
+                Atom::Nested(frame) => return Ok(Step::Push(frame)),
+            }
+        }
+    }
+
+    /// The bindings of a set, or those of a `let`, which are followed by `in` rather
+    /// than enclosed in braces.
+    fn bindings(
+        &mut self,
+        set: Option<(usize, bool)>,
+        pending: &mut PendingBindings<'src>,
+        expected: &mut &'static str,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        match input {
+            Some(Output::Binding(binding)) => {
+                self.insert_binding(pending, binding)?;
+                if set.is_none() {
+                    if self.eat(TokenKind::In).is_some() {
+                        let bindings = std::mem::take(pending).finish(self.ast);
+                        return Ok(Step::Done(Output::Bindings(bindings)));
                     }
-                    Rule::escaped_quote => parts.push(NixStringPart::Literal("\"".to_string())),
-                    Rule::escaped_interpolation => {
-                        parts.push(NixStringPart::Literal("${".to_string()))
+                    *expected = "a binding or 'in'";
+                }
+            }
+            Some(_) => unreachable!("bindings contain bindings"),
+            None if set.is_some() => {
+                self.expect(TokenKind::LeftBrace, "'{'")?;
+            }
+            None => {}
+        }
+        if let Some((start, recursive)) = set
+            && let Some(close) = self.eat(TokenKind::RightBrace)
+        {
+            let bindings = std::mem::take(pending).finish(self.ast);
+            let kind = ExprKind::AttrSet {
+                recursive,
+                bindings,
+            };
+            return Ok(Step::Done(Output::Expr(self.push(kind, start, close.end))));
+        }
+        Ok(Step::Push(Frame::binding(expected)))
+    }
+
+    fn binding(
+        &mut self,
+        expected: &'static str,
+        stage: &mut BindingStage<'src>,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        match std::mem::replace(stage, BindingStage::Start) {
+            BindingStage::Start => {
+                let token = self.peek();
+                match token.kind {
+                    TokenKind::Identifier | TokenKind::Quote => {
+                        *stage = BindingStage::Path(token.start);
+                        Ok(Step::Push(Frame::attr_path("an attribute path")))
                     }
-                    Rule::interpolation => {
-                        let inner_expr = part.into_inner().next().unwrap();
-                        parts.push(NixStringPart::Interpolation(Box::new(
-                            build_nix_expr_from_pair(inner_expr, root),
-                        )));
+                    TokenKind::Inherit => {
+                        self.pos = token.end;
+                        if self.eat(TokenKind::LeftParen).is_some() {
+                            *stage = BindingStage::InheritScope;
+                            return Ok(Step::Push(Frame::attr_path("an attribute path")));
+                        }
+                        self.inherit(None)
                     }
-                    _ => unreachable!("Unexpected string part: {:?}", part.as_rule()),
+                    _ => Err(self.unexpected(token, expected)),
+                }
+            }
+            BindingStage::Path(start) => {
+                let path = input.expect("the path has been parsed").path();
+                self.expect(TokenKind::Equals, "'='")?;
+                *stage = BindingStage::Value(start, path);
+                Ok(Step::Push(Frame::expr()))
+            }
+            BindingStage::Value(start, path) => {
+                let value = input.expect("the value has been parsed").expr();
+                let semicolon = self.expect(TokenKind::Semicolon, "';'")?;
+                let span = self.span(start, semicolon.end);
+                let binding = ParsedBinding::Value { path, value, span };
+                Ok(Step::Done(Output::Binding(binding)))
+            }
+            BindingStage::InheritScope => {
+                let scope = input.expect("the scope has been parsed").path();
+                self.expect(TokenKind::RightParen, "')'")?;
+                self.inherit(Some(scope))
+            }
+        }
+    }
+
+    /// The names of an `inherit`, after its scope if it has one.
+    fn inherit(&mut self, scope: Option<&'src str>) -> Result<Step<'src>, ParseError> {
+        let mut names = Vec::new();
+        while let Some(name) = self.eat(TokenKind::Identifier) {
+            names.push(name);
+        }
+        if names.is_empty() {
+            return Err(self.unexpected(self.peek(), "an identifier"));
+        }
+        self.expect(TokenKind::Semicolon, "an identifier or ';'")?;
+        let binding = ParsedBinding::Inherit { scope, names };
+        Ok(Step::Done(Output::Binding(binding)))
+    }
+
+    fn insert_binding(
+        &mut self,
+        bindings: &mut PendingBindings<'src>,
+        binding: ParsedBinding<'src>,
+    ) -> Result<(), ParseError> {
+        match binding {
+            ParsedBinding::Value { path, value, span } => {
+                insert_at_path(bindings, &split_attr_path(path), value, span, self.ast)
+            }
+            ParsedBinding::Inherit { scope, names } => {
+                for token in names {
+                    let name = &self.text[token.start..token.end];
+                    let kind = match scope {
+                        Some(scope) => ExprKind::Ref(Cow::Owned(format!("{}.{}", scope, name))),
+                        None => ExprKind::Inherit(name),
+                    };
+                    let value = self.push(kind, token.start, token.end);
+                    let span = self.ast[value].span;
+                    insert_at_path(bindings, &[Cow::Borrowed(name)], value, span, self.ast)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// A dotted attribute path such as `services."my.service".enable`, with no
+    /// whitespace around the dots, as written in the source.
+    fn attr_path(
+        &mut self,
+        start: &mut Option<usize>,
+        expected: &str,
+        reference: bool,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        let text = self.text;
+        let bytes = text.as_bytes();
+        // After a quoted component, which is the only thing nested in a path.
+        let mut component_parsed = input.is_some();
+        let start = match *start {
+            Some(start) => start,
+            None => {
+                let token = self.peek();
+                if !matches!(token.kind, TokenKind::Identifier | TokenKind::Quote) {
+                    return Err(self.unexpected(token, expected));
+                }
+                self.pos = token.start;
+                *start.insert(token.start)
+            }
+        };
+        loop {
+            if !component_parsed {
+                if bytes[self.pos] == b'"' {
+                    self.pos += 1;
+                    // Only the text of the path is kept, the parts are split off later.
+                    return Ok(Step::Push(Frame::String {
+                        start: self.pos - 1,
+                        parts: Vec::new(),
+                        name: true,
+                    }));
                 }
+                self.pos = lexer::identifier_end(text, self.pos);
             }
-            if parts.len() == 1 {
-                if let NixStringPart::Literal(s) = &parts[0] {
-                    return NixExpr::Value(NixValue::String(s.clone()));
+            component_parsed = false;
+            let next = self.pos + 1;
+            let continues = bytes.get(self.pos) == Some(&b'.')
+                && match bytes.get(next) {
+                    Some(b'"') => true,
+                    Some(&byte) if lexer::is_identifier_start(byte) => {
+                        let word = &text[next..lexer::identifier_end(text, next)];
+                        lexer::keyword(word).is_none()
+                    }
+                    _ => false,
+                };
+            if !continues {
+                let path = &text[start..self.pos];
+                if !reference {
+                    return Ok(Step::Done(Output::Path(path)));
                 }
+                let kind = ExprKind::Ref(Cow::Borrowed(path));
+                return Ok(Step::Done(Output::Expr(self.push(kind, start, self.pos))));
             }
-            NixExpr::InterpolatedString(parts)
+            self.pos = next;
+        }
+    }
+
+    /// A string node from its parts, collapsing a single literal into a constant.
+    fn string(&mut self, parts: Vec<StringPart<'src>>, start: usize) -> ExprId {
+        let kind = match parts.as_slice() {
+            [StringPart::Literal(s)] => ExprKind::Value(Literal::String(s.clone())),
+            _ => ExprKind::InterpolatedString(self.ast.push_parts(parts)),
+        };
+        self.push(kind, start, self.pos)
+    }
+
+    /// The parts of a `"` string, up to and including its closing quote. Escapes, `$` and
+    /// `'` each make up a part of their own.
+    fn string_parts(
+        &mut self,
+        start: usize,
+        parts: &mut Vec<StringPart<'src>>,
+        name: bool,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        if let Some(interpolation) = input {
+            self.expect(TokenKind::RightBrace, "'}'")?;
+            parts.push(StringPart::Interpolation(interpolation.expr()));
         }
-        Rule::path => {
-            let path_str = pair.as_str();
-            let mut path_buf;
-            if let Some(stripped) = path_str.strip_prefix("~/") {
-                path_buf = home::home_dir().expect("Could not find home directory");
-                path_buf.push(stripped);
+        let text = self.text;
+        loop {
+            let rest = &text[self.pos..];
+            let (part, len) = if rest.starts_with('"') {
+                self.pos += 1;
+                if name {
+                    return Ok(Step::Done(Output::Name));
+                }
+                let string = self.string(std::mem::take(parts), start);
+                return Ok(Step::Done(Output::Expr(string)));
+            } else if rest.starts_with("${") {
+                self.pos += 2;
+                return Ok(Step::Push(Frame::expr()));
+            } else if rest.starts_with("''${") {
+                (Cow::Borrowed("${"), 4)
+            } else if let Some(escaped) = rest.strip_prefix('\\') {
+                let Some(c) = escaped.chars().next() else {
+                    return Err(self.syntax_error(start, "unterminated string"));
+                };
+                (unescape(&escaped[..c.len_utf8()]), 1 + c.len_utf8())
+            } else if rest.starts_with(['$', '\'']) {
+                (Cow::Borrowed(&rest[..1]), 1)
+            } else if rest.is_empty() {
+                return Err(self.syntax_error(start, "unterminated string"));
             } else {
-                path_buf = PathBuf::from(path_str);
+                let len = rest.find(['"', '$', '\\', '\'']).unwrap_or(rest.len());
+                (Cow::Borrowed(&rest[..len]), len)
+            };
+            self.pos += len;
+            parts.push(StringPart::Literal(part));
+        }
+    }
+
+    /// The parts of a `''` string, up to and including its closing quotes, with the
+    /// indentation common to all lines removed.
+    fn indented_string_parts(
+        &mut self,
+        start: usize,
+        pieces: &mut Vec<Piece<'src>>,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        let text = self.text;
+        match input {
+            Some(interpolation) => {
+                self.expect(TokenKind::RightBrace, "'}'")?;
+                pieces.push(Piece::Interpolation(interpolation.expr()));
+            }
+            None => {
+                // A blank rest of the opening line is not part of the string.
+                let rest = &text[self.pos..];
+                let blank = rest.trim_start_matches(' ');
+                if let Some(content) = blank.strip_prefix('\n') {
+                    self.pos = text.len() - content.len();
+                }
             }
-            let final_path = if path_buf.is_absolute() {
-                path_buf
+        }
+        loop {
+            let rest = &text[self.pos..];
+            let (piece, len) = if rest.starts_with("'''") {
+                (Piece::Escaped(Cow::Borrowed("''")), 3)
+            } else if rest.starts_with("''$") {
+                (Piece::Escaped(Cow::Borrowed("$")), 3)
+            } else if let Some(escaped) = rest.strip_prefix("''\\") {
+                let Some(c) = escaped.chars().next() else {
+                    return Err(self.syntax_error(start, "unterminated string"));
+                };
+                (
+                    Piece::Escaped(unescape(&escaped[..c.len_utf8()])),
+                    3 + c.len_utf8(),
+                )
+            } else if rest.starts_with("''") {
+                self.pos += 2;
+                let parts = strip_indentation(std::mem::take(pieces));
+                return Ok(Step::Done(Output::Expr(self.string(parts, start))));
+            } else if rest.starts_with("${") {
+                self.pos += 2;
+                return Ok(Step::Push(Frame::expr()));
+            } else if rest.starts_with(['$', '\'']) {
+                (Piece::Text(&rest[..1]), 1)
+            } else if rest.is_empty() {
+                return Err(self.syntax_error(start, "unterminated string"));
             } else {
-                root.join(path_buf)
+                let len = rest.find(['$', '\'']).unwrap_or(rest.len());
+                (Piece::Text(&rest[..len]), len)
             };
-            NixExpr::Value(NixValue::Path(final_path))
-        }
-        Rule::search_path => {
-            let content = pair.into_inner().next().unwrap().as_str();
-            NixExpr::SearchPath(content.to_string())
-        }
-
-        // --- This catch-all prevents panics for truly unhandled rules.
-        _ => unreachable!(
-            "build_nix_expr_from_pair: Unhandled rule: `{:?}` with content `{}`",
-            pair.as_rule(),
-            pair.as_str()
-        ),
-    }
-}
-
-// build_bindings_from_pairs, insert_at_path, and parse are unchanged
-fn build_bindings_from_pairs(pairs: Pairs<Rule>, root: &Path) -> IndexMap<String, NixExpr> {
-    let mut bindings: IndexMap<String, NixExpr> = IndexMap::new();
-    for attr_binding_pair in pairs {
-        let binding_rule_pair = attr_binding_pair.into_inner().next().unwrap();
-        match binding_rule_pair.as_rule() {
-            Rule::binding => {
-                let mut inner_rules = binding_rule_pair.into_inner();
-                let path_pair = inner_rules.next().unwrap();
-                let path_str = path_pair.as_str().to_string();
-                let path: Vec<&str> = path_str.split('.').collect();
-                let expr = build_nix_expr_from_pair(inner_rules.next().unwrap(), root);
-                insert_at_path(&mut bindings, &path, expr);
-            }
-            Rule::inherit_binding => {
-                let mut inner_inherit = binding_rule_pair.into_inner();
-                let mut scope_ident: Option<String> = None;
-                if let Some(token) = inner_inherit.peek() {
-                    if token.as_rule() == Rule::identifier {
-                        scope_ident = Some(token.as_str().to_string());
-                        inner_inherit.next();
+            self.pos += len;
+            pieces.push(piece);
+        }
+    }
+
+    /// A path, from the token holding it up to its first interpolation. Relative paths
+    /// are resolved against the root directory.
+    fn path(&mut self, token: Token) -> Result<Atom<'src>, ParseError> {
+        let text = self.text;
+        let literal = &text[token.start..token.end];
+        let path: Cow<Path> = match literal.strip_prefix("~/") {
+            Some(stripped) => Cow::Owned(
+                home::home_dir()
+                    .ok_or(ParseError::HomeDirectoryNotFound {
+                        location: self.location(token.start),
+                    })?
+                    .join(stripped),
+            ),
+            None => Cow::Borrowed(Path::new(literal)),
+        };
+        let path = if path.is_absolute() {
+            path
+        } else {
+            Cow::Owned(self.root.join(path))
+        };
+        if !text[self.pos..].starts_with("${") {
+            let kind = ExprKind::Value(Literal::Path(path));
+            return Ok(Atom::Parsed(self.push(kind, token.start, token.end)));
+        }
+
+        let prefix = path.to_string_lossy().into_owned();
+        Ok(Atom::Nested(Frame::Path {
+            start: token.start,
+            parts: vec![StringPart::Literal(Cow::Owned(prefix))],
+        }))
+    }
+
+    /// The rest of a path with interpolations, which ends where neither literal path
+    /// characters nor interpolations follow.
+    fn path_parts(
+        &mut self,
+        start: usize,
+        parts: &mut Vec<StringPart<'src>>,
+        input: Option<Output<'src>>,
+    ) -> Result<Step<'src>, ParseError> {
+        if let Some(interpolation) = input {
+            self.expect(TokenKind::RightBrace, "'}'")?;
+            parts.push(StringPart::Interpolation(interpolation.expr()));
+        }
+        let text = self.text;
+        loop {
+            if text[self.pos..].starts_with("${") {
+                self.pos += 2;
+                return Ok(Step::Push(Frame::expr()));
+            }
+            let end = lexer::path_run_end(text, self.pos);
+            if end == self.pos {
+                break;
+            }
+            parts.push(StringPart::Literal(Cow::Borrowed(&text[self.pos..end])));
+            self.pos = end;
+        }
+        let parts = self.ast.push_parts(std::mem::take(parts));
+        let path = self.push(ExprKind::InterpolatedPath(parts), start, self.pos);
+        Ok(Step::Done(Output::Expr(path)))
+    }
+}
+
+fn starts_atom(kind: TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Identifier
+            | TokenKind::Integer
+            | TokenKind::Float
+            | TokenKind::Path
+            | TokenKind::SearchPath
+            | TokenKind::True
+            | TokenKind::False
+            | TokenKind::Null
+            | TokenKind::Quote
+            | TokenKind::IndentQuote
+            | TokenKind::LeftBrace
+            | TokenKind::Rec
+            | TokenKind::LeftBracket
+            | TokenKind::LeftParen
+    )
+}
+
+/// The character an escape sequence like `\n` stands for, given what follows the
+/// backslash.
+fn unescape(escaped: &str) -> Cow<'_, str> {
+    match escaped {
+        "n" => Cow::Borrowed("\n"),
+        "r" => Cow::Borrowed("\r"),
+        "t" => Cow::Borrowed("\t"),
+        _ => Cow::Borrowed(escaped),
+    }
+}
+
+/// Removes the indentation shared by all lines of an indented string, and its last line
+/// if that only holds spaces. Lines that are blank don't count towards the indentation,
+/// while escapes and interpolations do, like other text.
+fn strip_indentation(pieces: Vec<Piece<'_>>) -> Vec<StringPart<'_>> {
+    let mut min_indent = usize::MAX;
+    let mut indent = 0;
+    let mut at_line_start = true;
+    for piece in &pieces {
+        let Piece::Text(text) = piece else {
+            if at_line_start {
+                at_line_start = false;
+                min_indent = min_indent.min(indent);
+            }
+            continue;
+        };
+        for byte in text.bytes() {
+            match (at_line_start, byte) {
+                (true, b' ') => indent += 1,
+                (true, b'\n') => indent = 0,
+                (true, _) => {
+                    at_line_start = false;
+                    min_indent = min_indent.min(indent);
+                }
+                (false, b'\n') => {
+                    at_line_start = true;
+                    indent = 0;
+                }
+                (false, _) => {}
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut at_line_start = true;
+    let mut dropped = 0;
+    let last = pieces.len();
+    for (index, piece) in pieces.into_iter().enumerate() {
+        match piece {
+            Piece::Text(text) => {
+                for c in text.chars() {
+                    match (at_line_start, c) {
+                        (true, ' ') => {
+                            if dropped >= min_indent {
+                                literal.push(c);
+                            }
+                            dropped += 1;
+                        }
+                        (true, '\n') => {
+                            dropped = 0;
+                            literal.push(c);
+                        }
+                        (true, _) => {
+                            at_line_start = false;
+                            dropped = 0;
+                            literal.push(c);
+                        }
+                        (false, _) => {
+                            at_line_start = c == '\n';
+                            literal.push(c);
+                        }
                     }
                 }
-                for ident_to_inherit_pair in inner_inherit {
-                    let ident_name = ident_to_inherit_pair.as_str().to_string();
-                    let value_expr = match &scope_ident {
-                        Some(scope) => NixExpr::Ref(format!("{}.{}", scope, ident_name)),
-                        None => NixExpr::Ref(ident_name.clone()),
+                if index + 1 == last
+                    && let Some(newline) = literal.rfind('\n')
+                    && literal[newline + 1..].bytes().all(|byte| byte == b' ')
+                {
+                    literal.truncate(newline + 1);
+                }
+            }
+            Piece::Escaped(text) => {
+                at_line_start = false;
+                dropped = 0;
+                literal.push_str(&text);
+            }
+            Piece::Interpolation(expr) => {
+                at_line_start = false;
+                dropped = 0;
+                if !literal.is_empty() {
+                    parts.push(StringPart::Literal(Cow::Owned(std::mem::take(
+                        &mut literal,
+                    ))));
+                }
+                parts.push(StringPart::Interpolation(expr));
+            }
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(StringPart::Literal(Cow::Owned(literal)));
+    }
+    parts
+}
+
+/// Splits a dotted attribute path such as `services."my.service".enable` into its
+/// components, stripping the quotes of quoted components.
+pub(crate) fn split_attr_path(path: &str) -> Vec<Cow<'_, str>> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let mut chars = path.char_indices();
+    let component = |start: usize, end: usize, quoted: bool, current: &mut String| {
+        if quoted {
+            Cow::Owned(std::mem::take(current))
+        } else {
+            current.clear();
+            Cow::Borrowed(&path[start..end])
+        }
+    };
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                quoted = true;
+            }
+            '\\' if in_quotes => current.extend(chars.next().map(|(_, c)| c)),
+            '.' if !in_quotes => {
+                parts.push(component(start, offset, quoted, &mut current));
+                start = offset + 1;
+                quoted = false;
+            }
+            _ => current.push(c),
+        }
+    }
+    parts.push(component(start, path.len(), quoted, &mut current));
+    parts
+}
+
+/// The bindings of a set or `let` while it is being built, so that dotted paths such as
+/// `a.b = 1; a.c = 2;` can still be merged into a single nested set.
+#[derive(Default)]
+struct PendingBindings<'src> {
+    entries: IndexMap<Cow<'src, str>, Pending<'src>>,
+}
+
+enum Pending<'src> {
+    /// A value that is already part of the tree, bound where `defined` starts.
+    Expr { value: ExprId, defined: Span },
+    /// A non-recursive set, created implicitly by a dotted path, or an explicit set that
+    /// later bindings were merged into.
+    Set {
+        recursive: bool,
+        bindings: PendingBindings<'src>,
+        span: Span,
+    },
+}
+
+impl<'src> PendingBindings<'src> {
+    /// Adds the bindings to `ast`, along with the sets they were merged into.
+    fn finish(mut self, ast: &mut Ast<'src>) -> Slice<Binding<'src>> {
+        // The sets being finished, innermost last, each with the name it is bound to and
+        // its bindings finished so far.
+        let mut sets = vec![(
+            None,
+            std::mem::take(&mut self.entries).into_iter(),
+            Vec::new(),
+        )];
+        loop {
+            let (_, entries, finished) = sets.last_mut().expect("the outermost set is open");
+            match entries.next() {
+                Some((name, Pending::Expr { value, .. })) => finished.push(Binding { name, value }),
+                Some((
+                    name,
+                    Pending::Set {
+                        recursive,
+                        mut bindings,
+                        span,
+                    },
+                )) => {
+                    let entries = std::mem::take(&mut bindings.entries).into_iter();
+                    sets.push((Some((name, recursive, span)), entries, Vec::new()));
+                }
+                None => {
+                    let (set, _, finished) = sets.pop().expect("the set is open");
+                    let bindings = ast.push_bindings(finished);
+                    let Some((name, recursive, span)) = set else {
+                        return bindings;
+                    };
+                    let kind = ExprKind::AttrSet {
+                        recursive,
+                        bindings,
                     };
-                    bindings.insert(ident_name, value_expr);
+                    let value = ast.push(kind, span);
+                    let (_, _, finished) = sets.last_mut().expect("nested sets have a parent");
+                    finished.push(Binding { name, value });
                 }
             }
-            _ => unreachable!(
-                "Unexpected rule inside attr_binding: {:?}",
-                binding_rule_pair.as_rule()
-            ),
         }
     }
-    bindings
 }
 
-fn insert_at_path(attrset: &mut IndexMap<String, NixExpr>, path: &[&str], value: NixExpr) {
-    let key = path[0].to_string();
-    if path.len() == 1 {
-        attrset.insert(key, value);
-        return;
+impl Drop for PendingBindings<'_> {
+    fn drop(&mut self) {
+        let mut pending: Vec<_> = std::mem::take(&mut self.entries).into_values().collect();
+        while let Some(entry) = pending.pop() {
+            if let Pending::Set { mut bindings, .. } = entry {
+                pending.extend(std::mem::take(&mut bindings.entries).into_values());
+            }
+        }
     }
-    let entry = attrset.entry(key).or_insert_with(|| NixExpr::AttrSet {
-        recursive: false,
-        bindings: IndexMap::new(),
-    });
-    if let NixExpr::AttrSet { bindings, .. } = entry {
-        insert_at_path(bindings, &path[1..], value);
-    } else {
-        panic!("Attribute path conflicts with an existing value.");
+}
+
+impl<'src> Pending<'src> {
+    /// Whether this is a recursive set, and its bindings, which more bindings can then
+    /// be merged into. `None` if it is not an attribute set.
+    fn open(&mut self, ast: &Ast<'src>) -> Option<(bool, &mut PendingBindings<'src>)> {
+        if let Pending::Expr { value: id, .. } = *self
+            && let ExprKind::AttrSet {
+                recursive,
+                bindings,
+            } = ast[id].kind
+        {
+            let entries = ast
+                .bindings(bindings)
+                .iter()
+                .map(|binding| {
+                    let pending = Pending::Expr {
+                        value: binding.value,
+                        defined: ast[binding.value].span,
+                    };
+                    (binding.name.clone(), pending)
+                })
+                .collect();
+            *self = Pending::Set {
+                recursive,
+                bindings: PendingBindings { entries },
+                span: ast[id].span,
+            };
+        }
+        match self {
+            Pending::Set {
+                recursive,
+                bindings,
+                ..
+            } => Some((*recursive, bindings)),
+            Pending::Expr { .. } => None,
+        }
+    }
+
+    /// Where this attribute was first defined.
+    fn defined(&self) -> Span {
+        match self {
+            Pending::Expr { defined, .. } | Pending::Set { span: defined, .. } => *defined,
+        }
+    }
+}
+
+/// Inserts `value` at a dotted attribute path, creating intermediate attribute sets
+/// spanning the whole binding as needed.
+fn insert_at_path<'src>(
+    bindings: &mut PendingBindings<'src>,
+    path: &[Cow<'src, str>],
+    value: ExprId,
+    binding_span: Span,
+    ast: &Ast<'src>,
+) -> Result<(), ParseError> {
+    let (name, parents) = path.split_last().expect("attribute paths are not empty");
+    let mut path = parents.to_vec();
+    // The values still to insert, with the position of their name in the path. Merging a
+    // set into an existing one inserts its bindings one level deeper, so everything
+    // before that position is shared with the value inserted last.
+    let mut pending = vec![(parents.len(), name.clone(), value, binding_span)];
+    while let Some((depth, name, value, binding_span)) = pending.pop() {
+        path.truncate(depth);
+        path.push(name);
+        let location = binding_span.start;
+        let dotted = |end: usize| path[..end].join(".");
+
+        let mut bindings = &mut *bindings;
+        for (index, key) in path[..depth].iter().enumerate() {
+            let entry = bindings
+                .entries
+                .entry(key.clone())
+                .or_insert_with(|| Pending::Set {
+                    recursive: false,
+                    bindings: PendingBindings::default(),
+                    span: binding_span,
+                });
+            let previous = entry.defined().start;
+            bindings = match entry.open(ast) {
+                Some((_, nested)) => nested,
+                None => {
+                    return Err(ParseError::ConflictingAttributePath {
+                        path: dotted(index + 1),
+                        location,
+                        previous,
+                    });
+                }
+            };
+        }
+
+        let key = &path[depth];
+        let Some(existing) = bindings.entries.get_mut(key) else {
+            let pending = Pending::Expr {
+                value,
+                defined: binding_span,
+            };
+            bindings.entries.insert(key.clone(), pending);
+            continue;
+        };
+        let previous = existing.defined().start;
+        // `a.b = 1; a = { c = 2; };` merges both definitions of `a`, like Nix does.
+        if let ExprKind::AttrSet {
+            recursive: false,
+            bindings: nested,
+        } = ast[value].kind
+            && let Some((false, _)) = existing.open(ast)
+        {
+            let nested = ast.bindings(nested).iter().rev().map(|binding| {
+                let span = ast[binding.value].span;
+                (depth + 1, binding.name.clone(), binding.value, span)
+            });
+            pending.extend(nested);
+            continue;
+        }
+        return Err(ParseError::DuplicateAttribute {
+            path: dotted(depth + 1),
+            location,
+            previous,
+        });
     }
+    Ok(())
+}
+
+pub fn parse(input: &str, root: &Path) -> Result<NixExpr, ParseError> {
+    parse_source(input, root, FileId::UNKNOWN)
+}
+
+/// Like [`parse`], but attributes every span to `file`, typically an id handed out by a
+/// [`crate::span::SourceMap`].
+pub fn parse_source(input: &str, root: &Path, file: FileId) -> Result<NixExpr, ParseError> {
+    parse_ast(input, root, file).map(|ast| ast.to_nix_expr())
 }
 
-pub fn parse(input: &str, root: &Path) -> Result<NixExpr, pest::error::Error<Rule>> {
-    let expr_pair = NixParser::parse(Rule::source, input)?
-        .next()
-        .unwrap()
-        .into_inner()
-        .next()
-        .unwrap();
-    Ok(build_nix_expr_from_pair(expr_pair, root))
+/// Like [`parse_source`], but builds an [`Ast`] that borrows from `input` rather than an
+/// owned [`NixExpr`].
+pub fn parse_ast<'src>(
+    input: &'src str,
+    root: &Path,
+    file: FileId,
+) -> Result<Ast<'src>, ParseError> {
+    let lines = LineIndex::new(input);
+    let mut ast = Ast::new();
+    let expr = Parser::new(input, 0, input.len(), root, file, &lines, &mut ast).source()?;
+    ast.set_root(expr);
+    Ok(ast)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NixExpr, NixExprKind, NixStringPart, NixUnaryOp, NixValue};
+
+    /// The text of the string `source`, with `${}` in place of interpolations.
+    fn string(source: &str) -> String {
+        match &parse(source, Path::new("/")).unwrap().kind {
+            NixExprKind::Value(NixValue::String(text)) => text.clone(),
+            NixExprKind::InterpolatedString(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    NixStringPart::Literal(text) => text.as_str(),
+                    NixStringPart::Interpolation(_) => "${}",
+                })
+                .collect(),
+            kind => panic!("expected a string, got {:?}", kind),
+        }
+    }
+
+    // The previous parser, generated from a PEG grammar, got these wrong.
+    #[test]
+    fn strings_keep_their_text() {
+        assert_eq!(string(r#""a\"b\\c\nd\$e""#), "a\"b\\c\nd$e");
+        assert_eq!(string(r#""${x} and ${y}""#), "${} and ${}");
+        assert_eq!(string(r#""it''s""#), "it''s");
+        assert_eq!(string("''\n  a\n    b\n  c\n''"), "a\n  b\nc\n");
+        assert_eq!(string("''\n  ''' ''$ ''\\n''${ x}\n''"), "'' $ \n${ x}\n");
+    }
+
+    #[test]
+    fn comments_and_primes_are_accepted() {
+        let source = "let a' = 1; in a' /* block */ + # line\n 2";
+        assert!(parse(source, Path::new("/")).is_ok());
+    }
+
+    #[test]
+    fn list_items_are_not_applied() {
+        let list = parse("[ f x ]", Path::new("/")).unwrap();
+        let NixExprKind::List(items) = &list.kind else {
+            panic!("expected a list, got {:?}", list);
+        };
+        let kinds: Vec<_> = items.iter().map(|item| &item.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &NixExprKind::Ref("f".to_string()),
+                &NixExprKind::Ref("x".to_string())
+            ]
+        );
+
+        let list = parse("[ (-1) ]", Path::new("/")).unwrap();
+        let NixExprKind::List(items) = &list.kind else {
+            panic!("expected a list, got {:?}", list);
+        };
+        assert!(matches!(
+            &items[..],
+            [NixExpr {
+                kind: NixExprKind::UnaryOp {
+                    op: NixUnaryOp::Neg,
+                    ..
+                },
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn paths_end_at_the_first_other_character() {
+        let path = |source| {
+            let expr = parse(source, Path::new("/")).ok()?;
+            match &expr.kind {
+                NixExprKind::Value(NixValue::Path(path)) => Some(path.clone()),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            path("./a-b_c.d+e~f/g"),
+            Some(PathBuf::from("/a-b_c.d+e~f/g"))
+        );
+        assert_eq!(
+            path("/nix/store/x.nix"),
+            Some(PathBuf::from("/nix/store/x.nix"))
+        );
+        assert_eq!(path("dir/file.nix"), Some(PathBuf::from("/dir/file.nix")));
+        for source in ["./a,b", "./a@b", "./a;b", "./a=b"] {
+            assert!(parse(source, Path::new("/")).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let error = |source| parse(source, Path::new("/")).unwrap_err();
+        let unterminated = error("{ a = \"abc; }");
+        let location = Location::new(6, 1, 7);
+        assert_eq!(
+            unterminated,
+            ParseError::Syntax {
+                message: "unterminated string".to_string(),
+                location,
+            }
+        );
+        assert_eq!(
+            unterminated.to_diagnostic(FileId(1)).primary_span(),
+            Some(Span::new(FileId(1), location, location))
+        );
+        assert_eq!(
+            error("{ a = 1\n}"),
+            ParseError::Syntax {
+                message: "expected ';', found '}'".to_string(),
+                location: Location::new(8, 2, 1),
+            }
+        );
+        assert_eq!(
+            error("let a = 1; in a )"),
+            ParseError::Syntax {
+                message: "expected the end of the input, found ')'".to_string(),
+                location: Location::new(16, 1, 17),
+            }
+        );
+    }
+
+    #[test]
+    fn nested_nodes_have_their_own_spans() {
+        let text = |span: Span, source: &str| span.text(source).to_string();
+        let source = "{ x = a + b.c; }";
+        let set = parse(source, Path::new("/")).unwrap();
+        assert_eq!(text(set.span, source), source);
+        let NixExprKind::AttrSet { bindings, .. } = &set.kind else {
+            panic!("expected a set, got {:?}", set);
+        };
+        let sum = &bindings["x"];
+        assert_eq!(text(sum.span, source), "a + b.c");
+        let NixExprKind::BinaryOp { left, right, .. } = &sum.kind else {
+            panic!("expected a sum, got {:?}", sum);
+        };
+        assert_eq!(text(left.span, source), "a");
+        assert_eq!(text(right.span, source), "b.c");
+        assert_eq!(
+            (right.span.start, right.span.end),
+            (Location::new(10, 1, 11), Location::new(13, 1, 14))
+        );
+    }
+
+    #[test]
+    fn nesting_is_bounded_by_memory() {
+        let depth = 20_000;
+        let sources = [
+            format!("{}1{}", "(".repeat(depth), ")".repeat(depth)),
+            format!("{}1{}", "[".repeat(depth), "]".repeat(depth)),
+            format!("{}1{}", "{ a = ".repeat(depth), "; }".repeat(depth)),
+            format!("{}1{}", "\"${".repeat(depth), "}\"".repeat(depth)),
+            format!("{}1{}", "let a = ".repeat(depth), "; in a".repeat(depth)),
+        ];
+        for source in sources {
+            assert!(parse_ast(&source, Path::new("/"), FileId::UNKNOWN).is_ok());
+        }
+    }
+
+    #[test]
+    fn long_attribute_paths_are_merged() {
+        let path: Vec<_> = (0..20_000).map(|i| format!("a{}", i)).collect();
+        let path = path.join(".");
+        let source = format!("{{ {p}.x = 1; {p} = {{ y = 2; }}; }}", p = path);
+        assert!(parse(&source, Path::new("/")).is_ok());
+        let source = format!("{{ {p}.x = 1; {p}.x = 2; }}", p = path);
+        let error = parse(&source, Path::new("/")).unwrap_err();
+        assert!(matches!(error, ParseError::DuplicateAttribute { .. }));
+    }
 }
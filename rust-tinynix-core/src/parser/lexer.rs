@@ -0,0 +1,405 @@
+//! Tokens of the expression syntax. Strings, indented strings and the interpolations in
+//! paths are lexed by the parser itself, since what a character means there depends on
+//! where it appears.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TokenKind {
+    Identifier,
+    Integer,
+    Float,
+    /// The part of a path up to its first interpolation, if any.
+    Path,
+    /// `<nixpkgs>`, possibly with whitespace inside the brackets.
+    SearchPath,
+    Let,
+    In,
+    With,
+    Assert,
+    Rec,
+    Inherit,
+    True,
+    False,
+    Null,
+    /// The `"` opening a string.
+    Quote,
+    /// The `''` opening an indented string.
+    IndentQuote,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    LeftParen,
+    RightParen,
+    Semicolon,
+    Colon,
+    Equals,
+    Dot,
+    Plus,
+    Minus,
+    Bang,
+    Update,
+    UnterminatedComment,
+    /// Any other character.
+    Unknown,
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Skips whitespace and comments from `pos`. An unterminated `/*` comment is returned as
+/// a token, so the parser can report it where it starts.
+pub(super) fn skip_trivia(text: &str, mut pos: usize) -> Result<usize, Token> {
+    let bytes = text.as_bytes();
+    while let Some(&byte) = bytes.get(pos) {
+        match byte {
+            b' ' | b'\t' | b'\n' | b'\r' => pos += 1,
+            b'#' => {
+                pos = text[pos..]
+                    .find('\n')
+                    .map_or(text.len(), |newline| pos + newline)
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => match text[pos + 2..].find("*/") {
+                Some(close) => pos += close + 4,
+                None => {
+                    return Err(Token {
+                        kind: TokenKind::UnterminatedComment,
+                        start: pos,
+                        end: text.len(),
+                    });
+                }
+            },
+            _ => break,
+        }
+    }
+    Ok(pos)
+}
+
+/// The token starting at `pos`, which must not be whitespace or a comment.
+pub(super) fn token_at(text: &str, pos: usize) -> Token {
+    let bytes = text.as_bytes();
+    let token = |kind, len| Token {
+        kind,
+        start: pos,
+        end: pos + len,
+    };
+    let Some(&byte) = bytes.get(pos) else {
+        return token(TokenKind::Eof, 0);
+    };
+    let next = bytes.get(pos + 1).copied();
+    match byte {
+        b'~' if next == Some(b'/') => path_token(text, pos, pos + 2),
+        b'.' if next == Some(b'/') => path_token(text, pos, pos + 2),
+        b'.' if next == Some(b'.') && bytes.get(pos + 2) == Some(&b'/') => {
+            path_token(text, pos, pos + 3)
+        }
+        b'/' if next == Some(b'/') => token(TokenKind::Update, 2),
+        b'/' => path_token(text, pos, pos + 1),
+        b'\'' if next == Some(b'\'') => token(TokenKind::IndentQuote, 2),
+        b'"' => token(TokenKind::Quote, 1),
+        b'{' => token(TokenKind::LeftBrace, 1),
+        b'}' => token(TokenKind::RightBrace, 1),
+        b'[' => token(TokenKind::LeftBracket, 1),
+        b']' => token(TokenKind::RightBracket, 1),
+        b'(' => token(TokenKind::LeftParen, 1),
+        b')' => token(TokenKind::RightParen, 1),
+        b';' => token(TokenKind::Semicolon, 1),
+        b':' => token(TokenKind::Colon, 1),
+        b'=' => token(TokenKind::Equals, 1),
+        b'.' => token(TokenKind::Dot, 1),
+        b'+' => token(TokenKind::Plus, 1),
+        b'-' => token(TokenKind::Minus, 1),
+        b'!' => token(TokenKind::Bang, 1),
+        b'<' => search_path_token(text, pos).unwrap_or(token(TokenKind::Unknown, 1)),
+        b'0'..=b'9' => {
+            let integer = pos + digits(&bytes[pos..]);
+            if bytes.get(integer) == Some(&b'.') {
+                let fraction = digits(&bytes[integer + 1..]);
+                if fraction > 0 {
+                    return token(TokenKind::Float, integer + 1 + fraction - pos);
+                }
+            }
+            token(TokenKind::Integer, integer - pos)
+        }
+        _ if is_identifier_start(byte) => {
+            let end = identifier_end(text, pos);
+            let kind = keyword(&text[pos..end]).unwrap_or(TokenKind::Identifier);
+            if kind == TokenKind::Identifier && starts_path_segment(text, end) {
+                return path_token(text, pos, end);
+            }
+            Token {
+                kind,
+                start: pos,
+                end,
+            }
+        }
+        _ => {
+            let len = text[pos..].chars().next().map_or(1, char::len_utf8);
+            token(TokenKind::Unknown, len)
+        }
+    }
+}
+
+pub(super) fn is_identifier_start(byte: u8) -> bool {
+    byte.is_ascii_alphabetic() || byte == b'_'
+}
+
+fn is_identifier_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'\'')
+}
+
+/// The end of the identifier starting at `pos`.
+pub(super) fn identifier_end(text: &str, pos: usize) -> usize {
+    let bytes = text.as_bytes();
+    let mut end = pos + 1;
+    while bytes.get(end).is_some_and(|&byte| is_identifier_char(byte)) {
+        end += 1;
+    }
+    end
+}
+
+pub(super) fn keyword(word: &str) -> Option<TokenKind> {
+    Some(match word {
+        "let" => TokenKind::Let,
+        "in" => TokenKind::In,
+        "with" => TokenKind::With,
+        "assert" => TokenKind::Assert,
+        "rec" => TokenKind::Rec,
+        "inherit" => TokenKind::Inherit,
+        "true" => TokenKind::True,
+        "false" => TokenKind::False,
+        "null" => TokenKind::Null,
+        _ => return None,
+    })
+}
+
+fn digits(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count()
+}
+
+fn is_path_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'_' | b'-' | b'+' | b'~')
+}
+
+/// Whether a `/` at `pos` continues a path, rather than starting `//` or a comment.
+fn is_path_slash(text: &str, pos: usize) -> bool {
+    let bytes = text.as_bytes();
+    bytes.get(pos) == Some(&b'/') && !matches!(bytes.get(pos + 1), Some(b'/' | b'*'))
+}
+
+/// Whether `pos` is the `/` of a path like `dir/file`, after its first component.
+fn starts_path_segment(text: &str, pos: usize) -> bool {
+    is_path_slash(text, pos)
+        && (text
+            .as_bytes()
+            .get(pos + 1)
+            .is_some_and(|&byte| is_path_char(byte))
+            || text[pos + 1..].starts_with("${"))
+}
+
+/// The end of the literal characters of a path from `pos`, which stop at the end of the
+/// path or at an interpolation.
+pub(super) fn path_run_end(text: &str, mut pos: usize) -> usize {
+    let bytes = text.as_bytes();
+    while let Some(&byte) = bytes.get(pos) {
+        if is_path_char(byte) || is_path_slash(text, pos) {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+    pos
+}
+
+fn path_token(text: &str, start: usize, prefix_end: usize) -> Token {
+    Token {
+        kind: TokenKind::Path,
+        start,
+        end: path_run_end(text, prefix_end),
+    }
+}
+
+/// The end of the literal text of a string from `pos`: the offset of its closing quotes
+/// or of an interpolation, or the end of `text` if there is neither. Escapes are skipped,
+/// so quotes and interpolations never hide in them.
+pub(super) fn string_run_end(text: &str, mut pos: usize, indented: bool) -> usize {
+    let char_len = |pos: usize| text[pos..].chars().next().map_or(0, char::len_utf8);
+    while pos < text.len() {
+        let rest = &text[pos..];
+        pos += if rest.starts_with("${") {
+            break;
+        } else if indented {
+            if rest.starts_with("'''") || rest.starts_with("''$") {
+                3
+            } else if rest.starts_with("''\\") {
+                3 + char_len(pos + 3)
+            } else if rest.starts_with("''") {
+                break;
+            } else {
+                char_len(pos)
+            }
+        } else if rest.starts_with('"') {
+            break;
+        } else if rest.starts_with("''${") {
+            4
+        } else if rest.starts_with('\\') {
+            1 + char_len(pos + 1)
+        } else {
+            char_len(pos)
+        };
+    }
+    pos
+}
+
+fn search_path_token(text: &str, start: usize) -> Option<Token> {
+    let bytes = text.as_bytes();
+    let skip_whitespace = |mut pos: usize| {
+        while bytes.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+        pos
+    };
+    let lookup = skip_whitespace(start + 1);
+    if !bytes.get(lookup).copied().is_some_and(is_identifier_start) {
+        return None;
+    }
+    let mut pos = identifier_end(text, lookup);
+    while bytes.get(pos) == Some(&b'/') {
+        let segment = pos + 1;
+        pos = segment;
+        while bytes
+            .get(pos)
+            .is_some_and(|&byte| byte.is_ascii_alphanumeric() || b"_-.+".contains(&byte))
+        {
+            pos += 1;
+        }
+        if pos == segment {
+            return None;
+        }
+    }
+    let close = skip_whitespace(pos);
+    (bytes.get(close) == Some(&b'>')).then_some(Token {
+        kind: TokenKind::SearchPath,
+        start,
+        end: close + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kinds and texts of the tokens of `text`, up to the end or an unterminated
+    /// comment.
+    fn tokens(text: &str) -> Vec<(TokenKind, &str)> {
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        loop {
+            let token = match skip_trivia(text, pos) {
+                Ok(pos) => token_at(text, pos),
+                Err(token) => token,
+            };
+            tokens.push((token.kind, &text[token.start..token.end]));
+            if matches!(token.kind, TokenKind::Eof | TokenKind::UnterminatedComment) {
+                return tokens;
+            }
+            pos = token.end;
+        }
+    }
+
+    #[test]
+    fn keywords_end_where_identifiers_do() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("let letter in-put a'-b_ rec"),
+            [
+                (Let, "let"),
+                (Identifier, "letter"),
+                (Identifier, "in-put"),
+                (Identifier, "a'-b_"),
+                (Rec, "rec"),
+                (Eof, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_need_digits_after_the_point() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("1 2.5 3. x.4"),
+            [
+                (Integer, "1"),
+                (Float, "2.5"),
+                (Integer, "3"),
+                (Dot, "."),
+                (Identifier, "x"),
+                (Dot, "."),
+                (Integer, "4"),
+                (Eof, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_stop_before_updates_and_comments() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("./a/b.nix ../x ~/h /abs a/b a//b a/*c*/"),
+            [
+                (Path, "./a/b.nix"),
+                (Path, "../x"),
+                (Path, "~/h"),
+                (Path, "/abs"),
+                (Path, "a/b"),
+                (Identifier, "a"),
+                (Update, "//"),
+                (Identifier, "b"),
+                (Identifier, "a"),
+                (Eof, ""),
+            ]
+        );
+        assert_eq!(tokens("a/${b}")[0], (Path, "a/"));
+    }
+
+    #[test]
+    fn search_paths_may_have_whitespace_inside() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("<nixpkgs/lib> < a > <1>"),
+            [
+                (SearchPath, "<nixpkgs/lib>"),
+                (SearchPath, "< a >"),
+                (Unknown, "<"),
+                (Integer, "1"),
+                (Unknown, ">"),
+                (Eof, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_comments_are_tokens() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("# line\n1 /* open"),
+            [(Integer, "1"), (UnterminatedComment, "/* open")]
+        );
+    }
+
+    #[test]
+    fn string_runs_skip_escapes() {
+        let plain = r#"a\"b''${c}\${d}${e}" f"#;
+        assert_eq!(&plain[string_run_end(plain, 0, false)..], r#"${e}" f"#);
+        let indented = "a'''b''${c}''\\nd${e}'' f";
+        assert_eq!(&indented[string_run_end(indented, 0, true)..], "${e}'' f");
+        assert_eq!(string_run_end("open", 0, false), 4);
+    }
+}
@@ -0,0 +1,592 @@
+use super::lexer::{self, TokenKind};
+use super::{ParseError, Parser, PendingBindings, insert_at_path, split_attr_path};
+use crate::NixExpr;
+use crate::ast::{Ast, Binding, ExprId, ExprKind, Slice};
+use crate::span::{FileId, LineIndex, Location, Span};
+use std::path::Path;
+
+/// How many levels of nested constructs recovery descends into, each taking a few native
+/// stack frames. A broken region nested any deeper is reported as a whole.
+const MAX_DEPTH: usize = 128;
+
+/// The outcome of [`parse_recovering`]: a best-effort AST in which every region that
+/// failed to parse is replaced by a [`crate::NixExprKind::Error`] node, plus every error
+/// found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialParse {
+    pub expr: NixExpr,
+    pub errors: Vec<ParseError>,
+}
+
+impl PartialParse {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Like [`super::parse`], but keeps going after a syntax error by resynchronizing at
+/// `;`, `}`, `]` and `in`, so a single run reports every broken binding.
+pub fn parse_recovering(input: &str, root: &Path) -> PartialParse {
+    parse_source_recovering(input, root, FileId::UNKNOWN)
+}
+
+/// Like [`parse_recovering`], but attributes every span to `file`.
+pub fn parse_source_recovering(input: &str, root: &Path, file: FileId) -> PartialParse {
+    let first_error = match super::parse_source(input, root, file) {
+        Ok(expr) => {
+            return PartialParse {
+                expr,
+                errors: Vec::new(),
+            };
+        }
+        Err(error) => error,
+    };
+
+    let lines = LineIndex::new(input);
+    let mut recovery = Recovery {
+        input,
+        root,
+        file,
+        lines: &lines,
+        ast: Ast::new(),
+        errors: Vec::new(),
+        depth: 0,
+    };
+    let root = recovery.expression(0, input.len());
+    recovery.ast.set_root(root);
+    let expr = recovery.ast.to_nix_expr();
+    let mut errors = recovery.errors;
+    // Fragments can be more lenient than the whole, e.g. `let in x`; never report success
+    // for a source the regular parser rejected.
+    if errors.is_empty() {
+        errors.push(first_error);
+    }
+    errors.sort_by_key(|error| error.location().map(|location| location.offset));
+    // Nested regions often run into the same spot, which is worth only one error.
+    errors.dedup_by(|error, previous| error.location() == previous.location());
+    PartialParse { expr, errors }
+}
+
+struct Recovery<'a> {
+    input: &'a str,
+    root: &'a Path,
+    file: FileId,
+    lines: &'a LineIndex,
+    ast: Ast<'a>,
+    errors: Vec<ParseError>,
+    /// How many regions the one being recovered is nested in.
+    depth: usize,
+}
+
+impl<'a> Recovery<'a> {
+    fn location(&self, offset: usize) -> Location {
+        self.lines.location(self.input, offset)
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(self.file, self.location(start), self.location(end))
+    }
+
+    fn syntax_error(&self, offset: usize, message: impl Into<String>) -> ParseError {
+        ParseError::Syntax {
+            message: message.into(),
+            location: self.location(offset),
+        }
+    }
+
+    fn error_node(&mut self, start: usize, end: usize, error: ParseError) -> ExprId {
+        self.errors.push(error);
+        let span = self.span(start, end);
+        self.ast.push(ExprKind::Error, span)
+    }
+
+    /// Runs `parse` on a parser confined to `input[start..end]`, which adds the nodes it
+    /// builds to the tree being recovered.
+    fn try_parse<T>(
+        &mut self,
+        start: usize,
+        end: usize,
+        parse: impl FnOnce(&mut Parser<'a, '_>) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        let mut parser = Parser::new(
+            self.input,
+            start,
+            end,
+            self.root,
+            self.file,
+            self.lines,
+            &mut self.ast,
+        );
+        parse(&mut parser)
+    }
+
+    /// Shrinks a region to exclude surrounding whitespace and leading comments. An
+    /// unterminated comment is kept for the parser to report.
+    fn trim(&self, start: usize, end: usize) -> (usize, usize) {
+        let start = lexer::skip_trivia(&self.input[..end], start).unwrap_or(start);
+        (start, start + self.input[start..end].trim_end().len())
+    }
+
+    fn expression(&mut self, start: usize, end: usize) -> ExprId {
+        let (start, end) = self.trim(start, end);
+        let error = match self.try_parse(start, end, |parser| parser.source()) {
+            Ok(expr) => return expr,
+            Err(error) => error,
+        };
+        if start == end || self.depth == MAX_DEPTH {
+            return self.error_node(start, end, error);
+        }
+        self.depth += 1;
+        let marks = top_level(self.input, start, end);
+        let expr = self.structure(start, end, &marks);
+        self.depth -= 1;
+        expr.unwrap_or_else(|| self.error_node(start, end, error))
+    }
+
+    /// Recovers inside the constructs that delimit their children: `let`, `with`,
+    /// `assert`, attribute sets, lists and parentheses. `None` if the region is none of those.
+    fn structure(&mut self, start: usize, end: usize, marks: &[(usize, char)]) -> Option<ExprId> {
+        if keyword_at(self.input, start, "let") {
+            return Some(self.let_in(start, end, marks));
+        }
+        if keyword_at(self.input, start, "with") {
+            let (environment, body) = self.statement(start + "with".len(), end, marks);
+            let span = self.span(start, end);
+            return Some(self.ast.push(ExprKind::With { environment, body }, span));
+        }
+        if keyword_at(self.input, start, "assert") {
+            let (condition, body) = self.statement(start + "assert".len(), end, marks);
+            let span = self.span(start, end);
+            return Some(self.ast.push(ExprKind::Assert { condition, body }, span));
+        }
+
+        let recursive = keyword_at(self.input, start, "rec");
+        let open_index = if recursive {
+            marks
+                .iter()
+                .position(|&(offset, c)| offset >= start + 3 && !c.is_whitespace())?
+        } else {
+            0
+        };
+        let (open, opener) = *marks.get(open_index)?;
+        let closer = match opener {
+            '{' => '}',
+            '[' if !recursive => ']',
+            '(' if !recursive => ')',
+            _ => return None,
+        };
+        let close = match marks.get(open_index + 1) {
+            Some(&(offset, c))
+                if c == closer
+                    && marks[open_index + 2..]
+                        .iter()
+                        .all(|(_, c)| c.is_whitespace()) =>
+            {
+                Some(offset)
+            }
+            // The bracket closes before the region ends, e.g. `{ ... } + 1`.
+            Some(_) => return None,
+            None => {
+                let error = self.syntax_error(end, format!("expected '{}'", closer));
+                self.errors.push(error);
+                None
+            }
+        };
+
+        let content_end = close.unwrap_or(end);
+        let span = self.span(start, close.map_or(end, |close| close + 1));
+        let expr = match opener {
+            '{' => {
+                let bindings = self.bindings(open + 1, content_end);
+                self.ast.push(
+                    ExprKind::AttrSet {
+                        recursive,
+                        bindings,
+                    },
+                    span,
+                )
+            }
+            '[' => {
+                let items = self.list(open + 1, content_end);
+                let items = self.ast.push_items(items);
+                self.ast.push(ExprKind::List(items), span)
+            }
+            _ => self.expression(open + 1, content_end),
+        };
+        Some(expr)
+    }
+
+    fn let_in(&mut self, start: usize, end: usize, marks: &[(usize, char)]) -> ExprId {
+        let bindings_start = start + "let".len();
+        let mut depth = 1;
+        let mut body_keyword = None;
+        for &(offset, _) in marks.iter().filter(|(offset, _)| *offset >= bindings_start) {
+            if keyword_at(self.input, offset, "let") {
+                depth += 1;
+            } else if keyword_at(self.input, offset, "in") {
+                depth -= 1;
+                if depth == 0 {
+                    body_keyword = Some(offset);
+                    break;
+                }
+            }
+        }
+
+        let (bindings, body) = match body_keyword {
+            Some(offset) => (
+                self.bindings(bindings_start, offset),
+                self.expression(offset + "in".len(), end),
+            ),
+            None => {
+                let bindings = self.bindings(bindings_start, end);
+                let error = self.syntax_error(end, "expected 'in'");
+                (bindings, self.error_node(end, end, error))
+            }
+        };
+        let span = self.span(start, end);
+        self.ast.push(ExprKind::LetIn { bindings, body }, span)
+    }
+
+    /// Recovers the `head; body` part of `with` and `assert` expressions.
+    fn statement(
+        &mut self,
+        head_start: usize,
+        end: usize,
+        marks: &[(usize, char)],
+    ) -> (ExprId, ExprId) {
+        let semicolon = marks
+            .iter()
+            .find(|&&(offset, c)| offset >= head_start && c == ';')
+            .map(|&(offset, _)| offset);
+
+        match semicolon {
+            Some(offset) => (
+                self.expression(head_start, offset),
+                self.expression(offset + 1, end),
+            ),
+            None => {
+                let head = self.expression(head_start, end);
+                let error = self.syntax_error(end, "expected ';'");
+                (head, self.error_node(end, end, error))
+            }
+        }
+    }
+
+    /// Recovers the bindings between `{` and `}` or `let` and `in`, one `;`-terminated
+    /// segment at a time.
+    fn bindings(&mut self, start: usize, end: usize) -> Slice<Binding<'a>> {
+        let marks = top_level(self.input, start, end);
+        let mut bindings = PendingBindings::default();
+        let mut segment_start = start;
+        for &(offset, _) in marks.iter().filter(|(_, c)| *c == ';') {
+            self.binding(&mut bindings, segment_start, offset + 1, &marks);
+            segment_start = offset + 1;
+        }
+        let (rest_start, rest_end) = self.trim(segment_start, end);
+        if rest_start < rest_end {
+            self.binding(&mut bindings, rest_start, rest_end, &marks);
+        }
+        bindings.finish(&mut self.ast)
+    }
+
+    fn binding(
+        &mut self,
+        bindings: &mut PendingBindings<'a>,
+        start: usize,
+        end: usize,
+        marks: &[(usize, char)],
+    ) {
+        let (start, end) = self.trim(start, end);
+        let error = match self.try_parse(start, end, |parser| parser.binding_entry(bindings)) {
+            Ok(()) => return,
+            Err(
+                error @ (ParseError::DuplicateAttribute { .. }
+                | ParseError::ConflictingAttributePath { .. }),
+            ) => {
+                self.errors.push(error);
+                return;
+            }
+            Err(error) => error,
+        };
+
+        // Salvage `path = value` by recovering the value on its own.
+        let Some(&(equals, _)) = marks
+            .iter()
+            .find(|&&(offset, c)| c == '=' && (start..end).contains(&offset))
+        else {
+            self.errors.push(error);
+            return;
+        };
+        let path = match self.try_parse(start, equals, |parser| parser.attr_path_entry()) {
+            Ok(path) => split_attr_path(path),
+            Err(error) => {
+                self.errors.push(error);
+                return;
+            }
+        };
+        let value_end = if self.input[..end].ends_with(';') {
+            end - 1
+        } else {
+            let error = self.syntax_error(end, "expected ';'");
+            self.errors.push(error);
+            end
+        };
+        let value = self.expression(equals + 1, value_end);
+        let span = self.span(start, end);
+        if let Err(error) = insert_at_path(bindings, &path, value, span, &self.ast) {
+            self.errors.push(error);
+        }
+    }
+
+    /// Recovers the elements of a list, which are separated by top-level whitespace.
+    fn list(&mut self, start: usize, end: usize) -> Vec<ExprId> {
+        let marks = top_level(self.input, start, end);
+        let mut items = Vec::new();
+        let mut item_start = None;
+        for &(offset, c) in &marks {
+            if c.is_whitespace() {
+                if let Some(item_start) = item_start.take() {
+                    items.push(self.expression(item_start, offset));
+                }
+            } else if item_start.is_none() {
+                item_start = Some(offset);
+            }
+        }
+        if let Some(item_start) = item_start {
+            items.push(self.expression(item_start, end));
+        }
+        items
+    }
+}
+
+/// The tokens of `input[start..end]` that are not nested inside brackets, strings or
+/// comments, each reported as its first character along with its offset. The brackets
+/// and quotes delimiting a nested region are included, and whitespace and comments are
+/// reported as a single space.
+fn top_level(input: &str, start: usize, end: usize) -> Vec<(usize, char)> {
+    enum Nesting {
+        /// A bracket or interpolation, closed by the token of the given kind.
+        Bracket(TokenKind),
+        String {
+            indented: bool,
+        },
+    }
+    let slot = |closer| match closer {
+        TokenKind::RightBrace => 0,
+        TokenKind::RightBracket => 1,
+        _ => 2,
+    };
+
+    let text = &input[..end];
+    let first_char = |offset: usize| text[offset..].chars().next().unwrap_or(' ');
+    let mut stack = Vec::new();
+    // How many brackets of each kind are open inside the innermost string, or outside
+    // of any.
+    let mut open = vec![[0; 3]];
+    let mut marks = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        if let Some(&Nesting::String { indented }) = stack.last() {
+            pos = lexer::string_run_end(text, pos, indented);
+            if text[pos..].starts_with("${") {
+                stack.push(Nesting::Bracket(TokenKind::RightBrace));
+                open.push([1, 0, 0]);
+                pos += 2;
+            } else if pos < end {
+                stack.pop();
+                if stack.is_empty() {
+                    marks.push((pos, first_char(pos)));
+                }
+                pos += if indented { 2 } else { 1 };
+            }
+            continue;
+        }
+
+        let at_top_level = stack.is_empty();
+        let token = match lexer::skip_trivia(text, pos) {
+            Ok(next) if next == pos => lexer::token_at(text, pos),
+            trivia => {
+                if at_top_level {
+                    marks.push((pos, ' '));
+                }
+                // An unterminated comment runs to the end.
+                pos = trivia.unwrap_or(end);
+                continue;
+            }
+        };
+        let counts = open
+            .last_mut()
+            .expect("the outermost counts are never popped");
+        match token.kind {
+            TokenKind::Quote | TokenKind::IndentQuote => {
+                let indented = token.kind == TokenKind::IndentQuote;
+                stack.push(Nesting::String { indented });
+            }
+            TokenKind::LeftBrace | TokenKind::LeftBracket | TokenKind::LeftParen => {
+                let closer = match token.kind {
+                    TokenKind::LeftBrace => TokenKind::RightBrace,
+                    TokenKind::LeftBracket => TokenKind::RightBracket,
+                    _ => TokenKind::RightParen,
+                };
+                counts[slot(closer)] += 1;
+                stack.push(Nesting::Bracket(closer));
+            }
+            // A closing bracket also closes the brackets left open inside the one it
+            // matches, but never reaches out of a string. Stray ones are left for the
+            // parser to report.
+            TokenKind::RightBrace | TokenKind::RightBracket | TokenKind::RightParen
+                if counts[slot(token.kind)] > 0 =>
+            {
+                while let Some(Nesting::Bracket(closer)) = stack.pop() {
+                    counts[slot(closer)] -= 1;
+                    if closer == token.kind {
+                        break;
+                    }
+                }
+                // An interpolation has just been closed, back inside its string.
+                if counts == &[0; 3] && open.len() > 1 {
+                    open.pop();
+                }
+            }
+            _ => {}
+        }
+        if at_top_level || stack.is_empty() {
+            marks.push((token.start, first_char(token.start)));
+        }
+        pos = token.end;
+    }
+    marks
+}
+
+/// Whether `keyword` starts at `offset` as a whole word.
+fn keyword_at(input: &str, offset: usize, keyword: &str) -> bool {
+    let is_identifier_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\'');
+    input[offset..].starts_with(keyword)
+        && !input[..offset]
+            .chars()
+            .next_back()
+            .is_some_and(is_identifier_char)
+        && !input[offset + keyword.len()..]
+            .chars()
+            .next()
+            .is_some_and(is_identifier_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::{NixExprKind, NixValue};
+
+    fn errors(source: &str) -> Vec<String> {
+        let parsed = parse_recovering(source, Path::new("/"));
+        parsed.errors.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn every_broken_binding_is_reported() {
+        let source = "{ a = 1 +; b = ; c = 3; }";
+        assert_eq!(
+            errors(source),
+            [
+                "1:10: syntax error: expected an expression, found ';'",
+                "1:16: syntax error: expected an expression, found ';'",
+            ]
+        );
+        let parsed = parse_recovering(source, Path::new("/"));
+        let NixExprKind::AttrSet { bindings, .. } = &parsed.expr.kind else {
+            panic!("expected a set, got {:?}", parsed.expr);
+        };
+        let kinds: Vec<_> = bindings.values().map(|value| &value.kind).collect();
+        assert!(matches!(
+            kinds[..],
+            [
+                NixExprKind::Error,
+                NixExprKind::Error,
+                NixExprKind::Value(NixValue::Int(3)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn valid_parts_around_an_error_are_kept() {
+        let parsed = parse_recovering("[ 1 ) 2 ]", Path::new("/"));
+        let NixExprKind::List(items) = &parsed.expr.kind else {
+            panic!("expected a list, got {:?}", parsed.expr);
+        };
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[1].kind, NixExprKind::Error);
+
+        let parsed = parse_recovering("let a = 1; in", Path::new("/"));
+        let NixExprKind::LetIn { bindings, body } = &parsed.expr.kind else {
+            panic!("expected a let, got {:?}", parsed.expr);
+        };
+        assert!(bindings.contains_key("a"));
+        assert_eq!(body.kind, NixExprKind::Error);
+    }
+
+    #[test]
+    fn valid_sources_have_no_errors() {
+        let parsed = parse_recovering("let a = 1; in a", Path::new("/"));
+        assert!(parsed.is_ok());
+        assert_eq!(
+            parsed.expr,
+            parse("let a = 1; in a", Path::new("/")).unwrap()
+        );
+    }
+
+    #[test]
+    fn strings_and_comments_are_opaque() {
+        let source = "{ a = ''\n x; y }\n''; b = 1 +; }";
+        let parsed = parse_recovering(source, Path::new("/"));
+        let NixExprKind::AttrSet { bindings, .. } = &parsed.expr.kind else {
+            panic!("expected a set, got {:?}", parsed.expr);
+        };
+        assert_eq!(bindings.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(
+            errors("{ /* ; } */ a = 1 +; b = 2; }"),
+            ["1:20: syntax error: expected an expression, found ';'"]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_token_ending_a_region() {
+        assert_eq!(
+            errors("{ b = 1 +; }"),
+            ["1:10: syntax error: expected an expression, found ';'"]
+        );
+    }
+
+    #[test]
+    fn empty_regions_name_the_token_ending_them() {
+        assert_eq!(
+            errors("[ () ]"),
+            ["1:4: syntax error: expected an expression, found ')'"]
+        );
+        assert_eq!(
+            errors("let a = 1; in"),
+            ["1:14: syntax error: expected an expression, found the end of the input"]
+        );
+    }
+
+    #[test]
+    fn mismatched_brackets_are_reported_once() {
+        assert_eq!(
+            errors("[ 1 ( ] 2 ]"),
+            ["1:7: syntax error: expected an expression, found ']'"]
+        );
+    }
+
+    #[test]
+    fn deeply_nested_errors_are_recovered() {
+        let depth = 1_000;
+        let sources = [
+            format!("{}1 +{}", "[".repeat(depth), "]".repeat(depth)),
+            format!("{}1 +{}", "{ a = ".repeat(depth), "; }".repeat(depth)),
+            format!("{}1 +{}", "with (".repeat(depth), "); a".repeat(depth)),
+        ];
+        for source in sources {
+            assert_eq!(errors(&source).len(), 1);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Nix source rebuilt from the Rust tokens handed to the `nix!` macro.
+//!
+//! Rust has already split the input into its own tokens, which don't line up with Nix
+//! ones: `./src/main.rs` arrives as seven tokens and a `#` comment as loose punctuation
+//! and identifiers. The tokens are laid out again at the lines and columns the compiler
+//! reports for them, so that everything between them, and thereby every path, string and
+//! comment, reads as it was written. Where the positions are unknown, e.g. for tokens
+//! produced by other macros, the spacing of punctuation decides whether two tokens touch.
+
+use super::{ParseError, parse_source};
+use crate::NixExpr;
+use crate::span::{self, FileId};
+use proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+use std::ops::Range;
+use std::path::Path;
+
+/// The Nix text of a token stream, and where in it each Rust token went.
+#[derive(Debug, Clone, Default)]
+pub struct TokenSource {
+    text: String,
+    tokens: Vec<(Range<usize>, Span)>,
+    line: usize,
+    column: usize,
+    /// Whether the last token was punctuation joined to the one after it.
+    joint: bool,
+}
+
+impl TokenSource {
+    pub fn new(tokens: TokenStream) -> Self {
+        let mut source = TokenSource {
+            line: 1,
+            ..TokenSource::default()
+        };
+        let mut groups = vec![(tokens.into_iter(), None)];
+        while let Some((trees, _)) = groups.last_mut() {
+            let Some(tree) = trees.next() else {
+                if let Some((_, Some((close, span)))) = groups.pop() {
+                    source.push(close, span, Spacing::Alone);
+                }
+                continue;
+            };
+            match tree {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    source.push(open, group.span_open(), Spacing::Alone);
+                    groups.push((
+                        group.stream().into_iter(),
+                        Some((close, group.span_close())),
+                    ));
+                }
+                TokenTree::Ident(ident) => {
+                    source.push(&ident.to_string(), ident.span(), Spacing::Alone)
+                }
+                TokenTree::Punct(punct) => {
+                    let mut buffer = [0; 4];
+                    let text = punct.as_char().encode_utf8(&mut buffer);
+                    source.push(text, punct.span(), punct.spacing());
+                }
+                TokenTree::Literal(literal) => {
+                    source.push(&literal.to_string(), literal.span(), Spacing::Alone)
+                }
+            }
+        }
+        source
+    }
+
+    /// The rebuilt source. Tokens whose position is known sit at the same line and column
+    /// as in the Rust source.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The Rust span of the token at `offset` in the text, or of the last one before it
+    /// if `offset` falls between tokens.
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        let index = self
+            .tokens
+            .partition_point(|(range, _)| range.start <= offset);
+        self.tokens
+            .get(index.saturating_sub(1))
+            .map(|(_, span)| *span)
+    }
+
+    /// The Rust span covering a region of the text. Spans can only be joined on nightly
+    /// compilers, elsewhere this is the span of the first token in the region.
+    pub fn rust_span(&self, span: span::Span) -> Option<Span> {
+        let first = self.span_at(span.start.offset)?;
+        let last = self.span_at(span.end.offset.max(span.start.offset + 1) - 1)?;
+        Some(first.join(last).unwrap_or(first))
+    }
+
+    fn push(&mut self, text: &str, span: Span, spacing: Spacing) {
+        if text.is_empty() {
+            return;
+        }
+        let position = span.start();
+        // Lines are 1-based, so 0 means the compiler didn't tell where the token is.
+        if position.line > self.line {
+            self.text
+                .extend(std::iter::repeat_n('\n', position.line - self.line));
+            self.text.extend(std::iter::repeat_n(' ', position.column));
+            self.line = position.line;
+            self.column = position.column;
+        } else if position.line == self.line && position.column >= self.column {
+            self.text
+                .extend(std::iter::repeat_n(' ', position.column - self.column));
+            self.column = position.column;
+        } else if !self.joint && !self.text.is_empty() {
+            self.text.push(' ');
+            self.column += 1;
+        }
+
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.tokens.push((start..self.text.len(), span));
+        match text.rsplit_once('\n') {
+            Some((before, last)) => {
+                self.line += before.matches('\n').count() + 1;
+                self.column = last.chars().count();
+            }
+            None => self.column += text.chars().count(),
+        }
+        self.joint = spacing == Spacing::Joint;
+    }
+}
+
+/// Parses the tokens of a `nix!` invocation.
+pub fn parse_tokens(tokens: TokenStream, root: &Path) -> Result<NixExpr, ParseError> {
+    let source = TokenSource::new(tokens);
+    parse_source(source.text(), root, FileId::UNKNOWN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NixExprKind, NixValue};
+    use proc_macro2::{Ident, Punct};
+
+    fn source(input: &str) -> TokenSource {
+        TokenSource::new(input.parse().unwrap())
+    }
+
+    /// The values of the bindings of a set, in order.
+    fn bindings(expr: &NixExpr) -> Vec<&NixExprKind> {
+        match &expr.kind {
+            NixExprKind::AttrSet { bindings, .. } => {
+                bindings.values().map(|value| &value.kind).collect()
+            }
+            other => panic!("{:?} is not a set", other),
+        }
+    }
+
+    #[test]
+    fn paths_are_rebuilt_from_their_tokens() {
+        let input = "{\n  src = ./src/main.rs;\n  up = ../b/c.nix;\n  \
+                     home = ~/config/nix;\n  pkgs = <nixpkgs/lib>;\n}";
+        let source = source(input);
+        assert_eq!(source.text(), input);
+
+        let expr = parse_tokens(input.parse().unwrap(), Path::new("/root/project")).unwrap();
+        let home = home::home_dir().unwrap();
+        assert_eq!(
+            bindings(&expr),
+            [
+                &NixExprKind::Value(NixValue::Path("/root/project/src/main.rs".into())),
+                &NixExprKind::Value(NixValue::Path("/root/project/../b/c.nix".into())),
+                &NixExprKind::Value(NixValue::Path(home.join("config/nix"))),
+                &NixExprKind::SearchPath("nixpkgs/lib".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nix_spans_map_back_to_rust_tokens() {
+        let input = "{ a = 1;\n  b = ./x/y.nix; }";
+        let source = source(input);
+        assert_eq!(source.text(), input);
+        let expr = parse_source(source.text(), Path::new("/"), FileId::UNKNOWN).unwrap();
+        let NixExprKind::AttrSet { bindings, .. } = &expr.kind else {
+            panic!("{:?} is not a set", expr);
+        };
+
+        let path = source.rust_span(bindings["b"].span).unwrap();
+        assert_eq!((path.start().line, path.start().column), (2, 6));
+        assert_eq!((path.end().line, path.end().column), (2, 15));
+        let between = source.span_at(input.find("= ./").unwrap() + 1).unwrap();
+        assert_eq!((between.start().line, between.start().column), (2, 4));
+        let after = source.span_at(input.len() + 10).unwrap();
+        assert_eq!((after.start().line, after.start().column), (2, 17));
+    }
+
+    #[test]
+    fn tokens_without_positions_are_joined_by_their_spacing() {
+        let tokens: TokenStream = [
+            TokenTree::Punct(Punct::new('.', Spacing::Joint)),
+            TokenTree::Punct(Punct::new('/', Spacing::Joint)),
+            TokenTree::Ident(Ident::new("a", Span::call_site())),
+            TokenTree::Punct(Punct::new('+', Spacing::Alone)),
+            TokenTree::Ident(Ident::new("b", Span::call_site())),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(TokenSource::new(tokens).text(), "./a + b");
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// A single entry of a Nix search path, as found in `NIX_PATH` or passed via `-I`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchPathEntry {
+    /// A plain directory, `<name/rest>` is looked up as `dir/name/rest`.
+    Dir(PathBuf),
+    /// A `prefix=path` mapping, `<prefix/rest>` is looked up as `path/rest`.
+    Prefixed { prefix: String, path: PathBuf },
+}
+
+impl SearchPathEntry {
+    /// Parses a single `-I`-style entry, either `path` or `prefix=path`.
+    pub fn parse(entry: &str) -> Self {
+        match entry.split_once('=') {
+            Some((prefix, path)) => SearchPathEntry::Prefixed {
+                prefix: prefix.to_string(),
+                path: PathBuf::from(path),
+            },
+            None => SearchPathEntry::Dir(PathBuf::from(entry)),
+        }
+    }
+
+    /// The on-disk location this entry maps `lookup` to, if the entry applies to it at all.
+    fn candidate(&self, lookup: &str) -> Option<PathBuf> {
+        match self {
+            SearchPathEntry::Dir(dir) => Some(dir.join(lookup)),
+            SearchPathEntry::Prefixed { prefix, path } => {
+                if prefix.is_empty() {
+                    return Some(path.join(lookup));
+                }
+                let rest = lookup.strip_prefix(prefix.as_str())?;
+                if rest.is_empty() {
+                    Some(path.clone())
+                } else {
+                    rest.strip_prefix('/').map(|rest| path.join(rest))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for SearchPathEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchPathEntry::Dir(dir) => write!(f, "{}", dir.display()),
+            SearchPathEntry::Prefixed { prefix, path } => {
+                write!(f, "{}={}", prefix, path.display())
+            }
+        }
+    }
+}
+
+/// The list of locations `<name>` lookups are resolved against.
+///
+/// Entries added through [`SearchPath::add_include`] (the `-I` flag) always take
+/// precedence over the ones coming from a `NIX_PATH`-formatted string, regardless
+/// of the order in which they were added, mirroring the behaviour of `nix`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchPath {
+    includes: Vec<SearchPathEntry>,
+    nix_path: Vec<SearchPathEntry>,
+}
+
+impl SearchPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a search path from a `NIX_PATH`-formatted string, e.g.
+    /// `nixpkgs=/path/to/nixpkgs:/some/dir`.
+    pub fn from_nix_path(nix_path: &str) -> Self {
+        let mut search_path = Self::new();
+        search_path.add_nix_path(nix_path);
+        search_path
+    }
+
+    /// Builds a search path from the `NIX_PATH` environment variable, if it is set.
+    pub fn from_env() -> Self {
+        std::env::var("NIX_PATH")
+            .map(|nix_path| Self::from_nix_path(&nix_path))
+            .unwrap_or_default()
+    }
+
+    /// Appends all entries of a `NIX_PATH`-formatted string.
+    pub fn add_nix_path(&mut self, nix_path: &str) {
+        self.nix_path
+            .extend(split_nix_path(nix_path).map(SearchPathEntry::parse));
+    }
+
+    /// Adds a `-I`-style entry, either `path` or `prefix=path`.
+    pub fn add_include(&mut self, entry: &str) {
+        self.includes.push(SearchPathEntry::parse(entry));
+    }
+
+    /// Adds a `prefix=path` mapping with `-I` precedence.
+    pub fn add_prefix(&mut self, prefix: impl Into<String>, path: impl Into<PathBuf>) {
+        self.includes.push(SearchPathEntry::Prefixed {
+            prefix: prefix.into(),
+            path: path.into(),
+        });
+    }
+
+    /// All entries in lookup order.
+    pub fn entries(&self) -> impl Iterator<Item = &SearchPathEntry> {
+        self.includes.iter().chain(self.nix_path.iter())
+    }
+
+    /// Resolves a `<lookup>` expression to the first existing on-disk path.
+    pub fn resolve(&self, lookup: &str) -> Option<PathBuf> {
+        self.entries()
+            .filter_map(|entry| entry.candidate(lookup))
+            .find(|candidate| candidate.exists())
+    }
+}
+
+/// Splits a `NIX_PATH` on `:`, keeping URLs such as `https://...` in one piece.
+fn split_nix_path(nix_path: &str) -> impl Iterator<Item = &str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for (idx, _) in nix_path.match_indices(':') {
+        if nix_path[idx + 1..].starts_with("//") {
+            continue;
+        }
+        entries.push(&nix_path[start..idx]);
+        start = idx + 1;
+    }
+    entries.push(&nix_path[start..]);
+    entries.into_iter().filter(|entry| !entry.is_empty())
+}
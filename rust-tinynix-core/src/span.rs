@@ -0,0 +1,186 @@
+use std::fmt;
+
+/// Identifies a source file registered in a [`SourceMap`].
+///
+/// [`FileId::UNKNOWN`] is used for sources that were parsed without one, e.g. by
+/// [`crate::parser::parse`] or the `nix!` macro.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FileId(pub u32);
+
+impl FileId {
+    pub const UNKNOWN: FileId = FileId(0);
+}
+
+/// A 1-based line/column position in the parsed source, plus its byte offset.
+/// Columns count characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Location {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub const fn new(offset: usize, line: usize, column: usize) -> Self {
+        Location {
+            offset,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The region of a source file an AST node was parsed from; `end` is exclusive.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: Location,
+    pub end: Location,
+}
+
+impl Span {
+    pub const fn new(file: FileId, start: Location, end: Location) -> Self {
+        Span { file, start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        let start = if other.start.offset < self.start.offset {
+            other.start
+        } else {
+            self.start
+        };
+        let end = if other.end.offset > self.end.offset {
+            other.end
+        } else {
+            self.end
+        };
+        Span::new(self.file, start, end)
+    }
+
+    /// The source text covered by this span.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        source
+            .get(self.start.offset..self.end.offset)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Span(#{} {}..{})", self.file.0, self.start, self.end)
+    }
+}
+
+/// Maps byte offsets of a source text to line/column locations.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+    /// Whether each line is pure ASCII, so that its columns are byte offsets.
+    ascii_lines: Vec<bool>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        let ascii_lines = line_starts
+            .iter()
+            .zip(line_starts.iter().skip(1).chain([&source.len()]))
+            .map(|(&start, &end)| source.as_bytes()[start..end].is_ascii())
+            .collect();
+        LineIndex {
+            line_starts,
+            ascii_lines,
+        }
+    }
+
+    pub fn location(&self, source: &str, offset: usize) -> Location {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = if self.ascii_lines[line] {
+            offset - line_start
+        } else {
+            source
+                .get(line_start..offset)
+                .map_or(offset - line_start, |prefix| prefix.chars().count())
+        };
+        Location::new(offset, line + 1, column + 1)
+    }
+
+    /// The byte range of the given 1-based line, without its line terminator.
+    pub fn line_range(&self, source: &str, line: usize) -> Option<std::ops::Range<usize>> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(source.len(), |next| next - 1);
+        Some(start..end.max(start))
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    pub lines: LineIndex,
+}
+
+/// Owns the text of every parsed file so spans can be turned back into source snippets.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: impl Into<String>, source: impl Into<String>) -> FileId {
+        let source = source.into();
+        self.files.push(SourceFile {
+            name: name.into(),
+            lines: LineIndex::new(&source),
+            source,
+        });
+        FileId(self.files.len() as u32)
+    }
+
+    /// Swaps the text of an already registered file for `source`.
+    pub fn replace(&mut self, file: FileId, source: impl Into<String>) {
+        let Some(index) = file.0.checked_sub(1) else {
+            return;
+        };
+        if let Some(file) = self.files.get_mut(index as usize) {
+            let source = source.into();
+            file.lines = LineIndex::new(&source);
+            file.source = source;
+        }
+    }
+
+    pub fn get(&self, file: FileId) -> Option<&SourceFile> {
+        let index = file.0.checked_sub(1)?;
+        self.files.get(index as usize)
+    }
+
+    /// Where `span` starts, as the name of its file followed by the line and column,
+    /// e.g. `x.nix:3:5`.
+    pub fn locate(&self, span: Span) -> String {
+        let name = self
+            .get(span.file)
+            .map_or("<unknown>", |file| file.name.as_str());
+        format!("{}:{}", name, span.start)
+    }
+}
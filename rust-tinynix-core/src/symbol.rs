@@ -0,0 +1,131 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/// An identifier or attribute name.
+///
+/// The names in a program are interned in the [`SymbolTable`] of the evaluation, which
+/// numbers them in the order it first sees them. Symbols are compared, hashed and
+/// ordered by that number rather than by their text, so attribute sets are only sorted
+/// alphabetically when they are listed. Symbols of different tables are never equal.
+#[derive(Clone)]
+pub struct Symbol {
+    id: u32,
+    name: Rc<str>,
+}
+
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && Rc::ptr_eq(&self.name, &other.name)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Only symbols of different tables have the same number but different names.
+        self.id.cmp(&other.id).then_with(|| {
+            let address = |symbol: &Symbol| Rc::as_ptr(&symbol.name).cast::<u8>();
+            address(self).cmp(&address(other))
+        })
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// The names interned by one evaluation context, shared by every file it evaluates.
+/// A name is freed once neither the table nor any value refers to it anymore.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    ids: HashMap<Rc<str>, u32>,
+}
+
+impl SymbolTable {
+    /// The symbol for `name`, adding it to the table if it is new.
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.get(name) {
+            return symbol;
+        }
+        let id = u32::try_from(self.ids.len()).expect("fewer than 2^32 names");
+        let name: Rc<str> = name.into();
+        self.ids.insert(name.clone(), id);
+        Symbol { id, name }
+    }
+
+    /// The symbol for `name`, unless it has never been interned, in which case no set
+    /// built with the table can have it as an attribute.
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.ids.get_key_value(name).map(|(name, &id)| Symbol {
+            id,
+            name: name.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interned_names_are_shared() {
+        let mut table = SymbolTable::default();
+        let a = table.intern("name");
+        let b = table.intern("name");
+        assert!(Rc::ptr_eq(&a.name, &b.name));
+        assert_eq!(a, b);
+        assert_eq!(table.get("name"), Some(a.clone()));
+        assert_ne!(a, table.intern("other"));
+        assert_eq!(table.get("missing"), None);
+    }
+
+    #[test]
+    fn symbols_are_ordered_by_interning() {
+        let mut table = SymbolTable::default();
+        let b = table.intern("b");
+        let a = table.intern("a");
+        assert!(b < a);
+        assert_eq!(a.to_string(), "a");
+    }
+
+    #[test]
+    fn symbols_of_different_tables_differ() {
+        let mut first = SymbolTable::default();
+        let mut second = SymbolTable::default();
+        let a = first.intern("a");
+        let b = second.intern("b");
+        assert_ne!(a, b);
+        assert_ne!(a, second.intern("a"));
+        assert_ne!(a.cmp(&b), Ordering::Equal);
+    }
+}
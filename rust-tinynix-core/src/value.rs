@@ -0,0 +1,474 @@
+use crate::NixValue;
+use crate::ast::Literal;
+use crate::eval::bytecode::Chunk;
+use crate::eval::env::{Env, Environment};
+use crate::eval::ir::Expr;
+use crate::span::Span;
+use crate::symbol::Symbol;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::rc::{Rc, Weak};
+
+pub mod attrs;
+
+pub use attrs::AttrSet;
+
+/// The result of evaluation. Compound values share their contents through reference
+/// counting, so cloning one is cheap.
+///
+/// The elements of lists and the attributes of sets may be [`Value::Thunk`]s; use
+/// [`Value::forced`] to look through them. Values returned by the evaluator are forced
+/// deeply, so all of their thunks have been forced.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(NixString),
+    Path(Rc<Path>),
+    Null,
+    List(Rc<Vec<Value>>),
+    Attrs(AttrSet),
+    Lambda(Rc<Lambda>),
+    /// A builtin function, possibly applied to some of its arguments already.
+    Primop(Rc<PrimOp>),
+    /// An expression whose evaluation has been deferred until its value is needed.
+    Thunk(Thunk),
+}
+
+impl Value {
+    /// This value with a forced thunk looked through, or `None` for a thunk that has
+    /// not been forced yet.
+    pub fn forced(&self) -> Option<&Value> {
+        match self {
+            Value::Thunk(thunk) => thunk.value(),
+            value => Some(value),
+        }
+    }
+
+    /// Describes the type of the value for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Bool(_) => "a Boolean",
+            Value::String(_) => "a string",
+            Value::Path(_) => "a path",
+            Value::Null => "null",
+            Value::List(_) => "a list",
+            Value::Attrs(_) => "a set",
+            Value::Lambda(_) => "a function",
+            Value::Primop(_) => "a built-in function",
+            Value::Thunk(thunk) => thunk.value().map_or("a thunk", Value::type_name),
+        }
+    }
+
+    /// Identifies a shared list or attribute set, for detecting values that contain
+    /// themselves.
+    pub(crate) fn identity(&self) -> Option<*const ()> {
+        match self {
+            Value::List(items) => Some(Rc::as_ptr(items).cast()),
+            Value::Attrs(attrs) => attrs.as_ptr(),
+            _ => None,
+        }
+    }
+
+    /// Moves the values nested in this one out of it, as far as nothing else shares them.
+    fn take_children(&mut self) -> Vec<Value> {
+        match self {
+            Value::List(items) => Rc::get_mut(items).map(std::mem::take).unwrap_or_default(),
+            Value::Attrs(attrs) => attrs.take_values(),
+            Value::Lambda(lambda) => Rc::get_mut(lambda)
+                .and_then(|lambda| lambda.env.get_mut().as_mut().map(Environment::take_values))
+                .unwrap_or_default(),
+            Value::Primop(primop) => Rc::get_mut(primop)
+                .map(|primop| std::mem::take(&mut primop.args))
+                .unwrap_or_default(),
+            Value::Thunk(thunk) => Rc::get_mut(&mut thunk.0)
+                .map(|inner| {
+                    let env = inner
+                        .suspended
+                        .get_mut()
+                        .as_mut()
+                        .map(|suspended| Environment::take_values(&mut suspended.env))
+                        .unwrap_or_default();
+                    inner.value.take().into_iter().chain(env).collect()
+                })
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        // Deeply nested values would overflow the stack with the derived drop glue.
+        let mut pending = self.take_children();
+        while let Some(mut value) = pending.pop() {
+            pending.append(&mut value.take_children());
+        }
+    }
+}
+
+impl From<&NixValue> for Value {
+    fn from(value: &NixValue) -> Self {
+        match value {
+            NixValue::Int(i) => Value::Int(*i),
+            NixValue::Float(f) => Value::Float(*f),
+            NixValue::Bool(b) => Value::Bool(*b),
+            NixValue::String(s) => Value::String(NixString::new(s.as_str())),
+            NixValue::Path(path) => Value::Path(Rc::from(path.as_path())),
+            NixValue::Null => Value::Null,
+        }
+    }
+}
+
+impl From<&Literal<'_>> for Value {
+    fn from(literal: &Literal<'_>) -> Self {
+        match literal {
+            Literal::Int(i) => Value::Int(*i),
+            Literal::Float(f) => Value::Float(*f),
+            Literal::Bool(b) => Value::Bool(*b),
+            Literal::String(s) => Value::String(NixString::new(&**s)),
+            Literal::Path(path) => Value::Path(Rc::from(&**path)),
+            Literal::Null => Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Prints the value in Nix syntax. Unforced thunks are printed as `«thunk»`, and
+    /// sets and lists that contain themselves as `«repeated»`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        enum Task<'a> {
+            Print(&'a Value),
+            Text(&'static str),
+            Name(&'a str),
+        }
+        let mut tasks = vec![Task::Print(self)];
+        let mut seen = HashSet::new();
+        while let Some(task) = tasks.pop() {
+            let value = match task {
+                Task::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Task::Name(name) => {
+                    write_attr_name(f, name)?;
+                    continue;
+                }
+                Task::Print(value) => value,
+            };
+            let Some(value) = value.forced() else {
+                f.write_str("«thunk»")?;
+                continue;
+            };
+            if let Some(identity) = value.identity()
+                && !seen.insert(identity)
+            {
+                f.write_str("«repeated»")?;
+                continue;
+            }
+            match value {
+                Value::Int(i) => write!(f, "{}", i)?,
+                Value::Float(x) => write!(f, "{}", x)?,
+                Value::Bool(b) => write!(f, "{}", b)?,
+                Value::String(s) => write_string(f, s.as_str())?,
+                Value::Path(path) => write!(f, "{}", path.display())?,
+                Value::Null => f.write_str("null")?,
+                Value::List(items) => {
+                    f.write_str("[ ")?;
+                    tasks.push(Task::Text("]"));
+                    for item in items.iter().rev() {
+                        tasks.push(Task::Text(" "));
+                        tasks.push(Task::Print(item));
+                    }
+                }
+                Value::Attrs(attrs) => {
+                    f.write_str("{ ")?;
+                    tasks.push(Task::Text("}"));
+                    for (name, value) in attrs.sorted().into_iter().rev() {
+                        tasks.push(Task::Text("; "));
+                        tasks.push(Task::Print(value));
+                        tasks.push(Task::Text(" = "));
+                        tasks.push(Task::Name(name.as_str()));
+                    }
+                }
+                Value::Lambda(_) => f.write_str("«lambda»")?,
+                Value::Primop(primop) if primop.args.is_empty() => f.write_str("«primop»")?,
+                Value::Primop(_) => f.write_str("«primop-app»")?,
+                Value::Thunk(_) => unreachable!("forced values are never thunks"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '$' if chars.peek() == Some(&'{') => f.write_str("\\$")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+fn write_attr_name(f: &mut fmt::Formatter<'_>, name: &str) -> fmt::Result {
+    let mut chars = name.chars();
+    let is_identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '\'');
+    if is_identifier {
+        f.write_str(name)
+    } else {
+        write_string(f, name)
+    }
+}
+
+/// A string together with its context: the store paths it refers to, which are
+/// carried along through concatenation and interpolation.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NixString {
+    text: Rc<str>,
+    context: BTreeSet<String>,
+}
+
+impl NixString {
+    pub fn new(text: impl Into<Rc<str>>) -> Self {
+        NixString {
+            text: text.into(),
+            context: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_context(text: impl Into<Rc<str>>, context: BTreeSet<String>) -> Self {
+        NixString {
+            text: text.into(),
+            context,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn context(&self) -> &BTreeSet<String> {
+        &self.context
+    }
+}
+
+impl fmt::Display for NixString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// A function value: the parameter and body of a lambda together with the scope it was
+/// defined in.
+pub struct Lambda {
+    pub(crate) param: Symbol,
+    pub(crate) body: Code,
+    /// Only taken away from functions nothing can call anymore, to free the reference
+    /// cycle they are part of; see [`crate::eval::cycles`].
+    pub(crate) env: RefCell<Option<Env>>,
+    span: Span,
+}
+
+impl Lambda {
+    pub(crate) fn new(param: Symbol, body: Code, env: Env, span: Span) -> Self {
+        Lambda {
+            param,
+            body,
+            env: RefCell::new(Some(env)),
+            span,
+        }
+    }
+
+    /// The scope the lambda was defined in.
+    pub(crate) fn env(&self) -> Env {
+        self.env
+            .borrow()
+            .clone()
+            .expect("functions are only cut loose from their scope once unreachable")
+    }
+
+    pub fn param(&self) -> &str {
+        self.param.as_str()
+    }
+
+    /// Where the lambda was defined.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl fmt::Debug for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lambda")
+            .field("param", &self.param)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A builtin function together with the arguments it has been applied to so far.
+#[derive(Debug, Clone)]
+pub struct PrimOp {
+    pub(crate) name: &'static str,
+    pub(crate) arity: usize,
+    pub(crate) args: Vec<Value>,
+}
+
+impl PrimOp {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn args(&self) -> &[Value] {
+        &self.args
+    }
+}
+
+/// A deferred computation, evaluated at most once. Forcing a thunk whose evaluation is
+/// already in progress is infinite recursion.
+#[derive(Clone)]
+pub struct Thunk(Rc<ThunkInner>);
+
+struct ThunkInner {
+    value: OnceCell<Value>,
+    /// What to evaluate. Taken out while the thunk is being forced.
+    suspended: RefCell<Option<Suspended>>,
+    /// The binding the thunk was created for, if any.
+    origin: Option<Origin>,
+    span: Span,
+}
+
+/// An unevaluated expression together with its environment.
+pub(crate) struct Suspended {
+    pub code: Code,
+    pub env: Env,
+}
+
+/// The body of a thunk or function, in the form of the backend that created it.
+#[derive(Clone)]
+pub(crate) enum Code {
+    /// An expression for the tree-walking machine.
+    Expr(Rc<Expr>),
+    /// Bytecode for the virtual machine.
+    Chunk(Rc<Chunk>),
+}
+
+impl Code {
+    pub fn span(&self) -> Span {
+        match self {
+            Code::Expr(expr) => expr.span,
+            Code::Chunk(chunk) => chunk.span,
+        }
+    }
+}
+
+/// The named binding a thunk holds the value of.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Origin {
+    LetBinding(Symbol),
+    Attribute(Symbol),
+}
+
+impl Thunk {
+    pub(crate) fn new(code: Code, env: Env, origin: Option<Origin>) -> Self {
+        let span = code.span();
+        Thunk(Rc::new(ThunkInner {
+            value: OnceCell::new(),
+            suspended: RefCell::new(Some(Suspended { code, env })),
+            origin,
+            span,
+        }))
+    }
+
+    /// The value of the thunk, if it has been forced.
+    pub fn value(&self) -> Option<&Value> {
+        self.0.value.get()
+    }
+
+    /// Where the expression of the thunk is.
+    pub fn span(&self) -> Span {
+        self.0.span
+    }
+
+    pub(crate) fn origin(&self) -> Option<&Origin> {
+        self.0.origin.as_ref()
+    }
+
+    pub(crate) fn ptr_eq(&self, other: &Thunk) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0).cast()
+    }
+
+    pub(crate) fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakThunk {
+        WeakThunk(Rc::downgrade(&self.0))
+    }
+
+    /// The environment of a thunk that has not been forced yet.
+    pub(crate) fn suspended_env(&self) -> Option<Env> {
+        let suspended = self.0.suspended.borrow();
+        suspended.as_ref().map(|suspended| suspended.env.clone())
+    }
+
+    /// Starts forcing the thunk. `None` means it is already being forced.
+    pub(crate) fn take_suspended(&self) -> Option<Suspended> {
+        self.0.suspended.borrow_mut().take()
+    }
+
+    /// Undoes [`Thunk::take_suspended`] after forcing has failed.
+    pub(crate) fn restore(&self, suspended: Suspended) {
+        *self.0.suspended.borrow_mut() = Some(suspended);
+    }
+
+    pub(crate) fn set_value(&self, value: Value) {
+        let _ = self.0.value.set(value);
+    }
+
+    /// Replaces the environment of a thunk that has not been forced yet, to close the
+    /// knot of recursive bindings.
+    pub(crate) fn set_env(&self, env: Env) {
+        if let Some(suspended) = self.0.suspended.borrow_mut().as_mut() {
+            suspended.env = env;
+        }
+    }
+}
+
+/// A reference to a thunk that doesn't keep it alive.
+#[derive(Debug, Clone)]
+pub(crate) struct WeakThunk(Weak<ThunkInner>);
+
+impl WeakThunk {
+    pub(crate) fn upgrade(&self) -> Option<Thunk> {
+        self.0.upgrade().map(Thunk)
+    }
+}
+
+impl fmt::Debug for Thunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value() {
+            Some(value) => f.debug_tuple("Thunk").field(value).finish(),
+            None => f.write_str("Thunk(«unforced»)"),
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use super::Value;
+use crate::symbol::Symbol;
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+/// The attributes of a set, ordered by their [`Symbol`]s rather than alphabetically.
+///
+/// The map is persistent: it is a balanced tree whose nodes are shared between
+/// versions, so cloning is constant time, and inserting or removing an attribute only
+/// copies the nodes on the path to it instead of the whole set.
+#[derive(Clone, Default)]
+pub struct AttrSet {
+    root: Link,
+    len: usize,
+}
+
+type Link = Option<Rc<Node>>;
+
+#[derive(Clone)]
+struct Node {
+    name: Symbol,
+    value: Value,
+    left: Link,
+    right: Link,
+    height: u8,
+}
+
+impl AttrSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, name: &Symbol) -> Option<&Value> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            link = match name.cmp(&node.name) {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => return Some(&node.value),
+            };
+        }
+        None
+    }
+
+    pub fn contains_key(&self, name: &Symbol) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Binds `name` to `value`, replacing any previous value.
+    pub fn insert(&mut self, name: Symbol, value: Value) {
+        if insert(&mut self.root, name, value) {
+            self.len += 1;
+        }
+    }
+
+    /// Removes `name`, returning its value if it was bound.
+    pub fn remove(&mut self, name: &Symbol) -> Option<Value> {
+        // Check first, so removing a missing name doesn't copy the path to it.
+        if !self.contains_key(name) {
+            return None;
+        }
+        self.len -= 1;
+        remove(&mut self.root, name)
+    }
+
+    /// The attributes of `self` and `other`, with those of `other` taking precedence,
+    /// as in `self // other`. Only the smaller of the two sets is walked.
+    pub fn update(&self, other: &AttrSet) -> AttrSet {
+        if other.len <= self.len {
+            let mut result = self.clone();
+            for (name, value) in other {
+                result.insert(name.clone(), value.clone());
+            }
+            result
+        } else {
+            let mut result = other.clone();
+            for (name, value) in self {
+                if !other.contains_key(name) {
+                    result.insert(name.clone(), value.clone());
+                }
+            }
+            result
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        let mut iter = Iter {
+            front: Vec::new(),
+            back: Vec::new(),
+            remaining: self.len,
+        };
+        iter.push_left(&self.root);
+        iter.push_right(&self.root);
+        iter
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &Symbol> {
+        self.iter().map(|(name, _)| name)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &Value> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// The attributes in alphabetical order, as they are shown to users.
+    pub fn sorted(&self) -> Vec<(&Symbol, &Value)> {
+        let mut attrs: Vec<_> = self.iter().collect();
+        attrs.sort_unstable_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        attrs
+    }
+
+    /// The root of the tree, unless the set is empty.
+    pub(crate) fn root(&self) -> Option<NodeRef> {
+        self.root.clone().map(NodeRef)
+    }
+
+    /// Identifies the tree, for detecting values that contain themselves.
+    pub(crate) fn as_ptr(&self) -> Option<*const ()> {
+        self.root.as_ref().map(|root| Rc::as_ptr(root).cast())
+    }
+
+    /// Moves the values out of the nodes that no other set shares, so they can be
+    /// dropped without recursion.
+    pub(crate) fn take_values(&mut self) -> Vec<Value> {
+        let mut values = Vec::new();
+        let mut pending: Vec<Rc<Node>> = self.root.take().into_iter().collect();
+        self.len = 0;
+        while let Some(node) = pending.pop() {
+            if let Some(node) = Rc::into_inner(node) {
+                values.push(node.value);
+                pending.extend(node.left);
+                pending.extend(node.right);
+            }
+        }
+        values
+    }
+}
+
+/// A shared node of the tree behind an [`AttrSet`], for finding reference cycles.
+pub(crate) struct NodeRef(Rc<Node>);
+
+impl NodeRef {
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0).cast()
+    }
+
+    pub fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0.value
+    }
+
+    /// The roots of the subtrees below the node.
+    pub fn children(&self) -> impl Iterator<Item = NodeRef> {
+        [&self.0.left, &self.0.right]
+            .into_iter()
+            .filter_map(|link| link.clone().map(NodeRef))
+    }
+}
+
+fn height(link: &Link) -> u8 {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn balance(link: &Link) -> i16 {
+    link.as_ref().map_or(0, |node| {
+        i16::from(height(&node.left)) - i16::from(height(&node.right))
+    })
+}
+
+fn update_height(node: &mut Node) {
+    node.height = 1 + height(&node.left).max(height(&node.right));
+}
+
+/// Inserts into the subtree at `link`, returning whether `name` is new.
+fn insert(link: &mut Link, name: Symbol, value: Value) -> bool {
+    let Some(node) = link else {
+        *link = Some(Rc::new(Node {
+            name,
+            value,
+            left: None,
+            right: None,
+            height: 1,
+        }));
+        return true;
+    };
+    let node = Rc::make_mut(node);
+    let added = match name.cmp(&node.name) {
+        Ordering::Less => insert(&mut node.left, name, value),
+        Ordering::Greater => insert(&mut node.right, name, value),
+        Ordering::Equal => {
+            node.value = value;
+            return false;
+        }
+    };
+    rebalance(link);
+    added
+}
+
+/// Removes `name`, which must be present, from the subtree at `link`.
+fn remove(link: &mut Link, name: &Symbol) -> Option<Value> {
+    let node = Rc::make_mut(link.as_mut()?);
+    let removed = match name.cmp(&node.name) {
+        Ordering::Less => remove(&mut node.left, name),
+        Ordering::Greater => remove(&mut node.right, name),
+        Ordering::Equal => match (node.left.take(), node.right.take()) {
+            (None, child) | (child, None) => {
+                let value = std::mem::replace(&mut node.value, Value::Null);
+                *link = child;
+                return Some(value);
+            }
+            (left, right) => {
+                node.left = left;
+                node.right = right;
+                let (name, value) = pop_min(&mut node.right);
+                node.name = name;
+                Some(std::mem::replace(&mut node.value, value))
+            }
+        },
+    };
+    rebalance(link);
+    removed
+}
+
+/// Removes the first attribute of the non-empty subtree at `link`.
+fn pop_min(link: &mut Link) -> (Symbol, Value) {
+    let node = Rc::make_mut(link.as_mut().expect("the subtree is not empty"));
+    if node.left.is_some() {
+        let min = pop_min(&mut node.left);
+        rebalance(link);
+        return min;
+    }
+    let right = node.right.take();
+    let min = (
+        node.name.clone(),
+        std::mem::replace(&mut node.value, Value::Null),
+    );
+    *link = right;
+    min
+}
+
+/// Restores the height invariant of the node at `link` after one of its subtrees
+/// changed height by at most one.
+fn rebalance(link: &mut Link) {
+    let Some(node) = link else {
+        return;
+    };
+    let node = Rc::make_mut(node);
+    update_height(node);
+    let balance_factor = i16::from(height(&node.left)) - i16::from(height(&node.right));
+    if balance_factor > 1 {
+        if balance(&node.left) < 0 {
+            rotate_left(&mut node.left);
+        }
+        rotate_right(link);
+    } else if balance_factor < -1 {
+        if balance(&node.right) > 0 {
+            rotate_right(&mut node.right);
+        }
+        rotate_left(link);
+    }
+}
+
+fn rotate_right(link: &mut Link) {
+    let mut node = link.take().expect("a node to rotate");
+    let top = Rc::make_mut(&mut node);
+    let mut left = top.left.take().expect("a left child to rotate up");
+    let new_top = Rc::make_mut(&mut left);
+    top.left = new_top.right.take();
+    update_height(top);
+    new_top.right = Some(node);
+    update_height(new_top);
+    *link = Some(left);
+}
+
+fn rotate_left(link: &mut Link) {
+    let mut node = link.take().expect("a node to rotate");
+    let top = Rc::make_mut(&mut node);
+    let mut right = top.right.take().expect("a right child to rotate up");
+    let new_top = Rc::make_mut(&mut right);
+    top.right = new_top.left.take();
+    update_height(top);
+    new_top.left = Some(node);
+    update_height(new_top);
+    *link = Some(right);
+}
+
+/// The attributes of an [`AttrSet`] in the order of their symbols.
+pub struct Iter<'a> {
+    front: Vec<&'a Node>,
+    back: Vec<&'a Node>,
+    remaining: usize,
+}
+
+impl<'a> Iter<'a> {
+    fn push_left(&mut self, mut link: &'a Link) {
+        while let Some(node) = link {
+            self.front.push(node);
+            link = &node.left;
+        }
+    }
+
+    fn push_right(&mut self, mut link: &'a Link) {
+        while let Some(node) = link {
+            self.back.push(node);
+            link = &node.right;
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Symbol, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = self.front.pop()?;
+        self.push_left(&node.right);
+        Some((&node.name, &node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = self.back.pop()?;
+        self.push_right(&node.left);
+        Some((&node.name, &node.value))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl<'a> IntoIterator for &'a AttrSet {
+    type Item = (&'a Symbol, &'a Value);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl FromIterator<(Symbol, Value)> for AttrSet {
+    fn from_iter<I: IntoIterator<Item = (Symbol, Value)>>(iter: I) -> Self {
+        let mut attrs = AttrSet::new();
+        for (name, value) in iter {
+            attrs.insert(name, value);
+        }
+        attrs
+    }
+}
+
+impl fmt::Debug for AttrSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.sorted()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::SymbolTable;
+    use std::collections::BTreeMap;
+
+    /// A xorshift generator, so the pseudo-random operations are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn int(value: &Value) -> i64 {
+        match value {
+            Value::Int(n) => *n,
+            other => panic!("{:?} is not an integer", other),
+        }
+    }
+
+    /// Checks the order and AVL invariants of a subtree, returning its size.
+    fn check(link: &Link) -> usize {
+        let Some(node) = link else {
+            return 0;
+        };
+        if let Some(left) = &node.left {
+            assert!(left.name < node.name);
+        }
+        if let Some(right) = &node.right {
+            assert!(node.name < right.name);
+        }
+        assert_eq!(node.height, 1 + height(&node.left).max(height(&node.right)));
+        assert!(balance(link).abs() <= 1, "{:?} is unbalanced", node.name);
+        1 + check(&node.left) + check(&node.right)
+    }
+
+    fn assert_matches(attrs: &AttrSet, model: &BTreeMap<Symbol, i64>) {
+        assert_eq!(check(&attrs.root), attrs.len());
+        assert_eq!(attrs.len(), model.len());
+        let entries: Vec<_> = attrs.iter().map(|(k, v)| (k.clone(), int(v))).collect();
+        let expected: Vec<_> = model.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        assert_eq!(entries, expected);
+        let mut reversed: Vec<_> = attrs
+            .iter()
+            .rev()
+            .map(|(k, v)| (k.clone(), int(v)))
+            .collect();
+        reversed.reverse();
+        assert_eq!(reversed, expected);
+    }
+
+    #[test]
+    fn random_operations_keep_the_tree_ordered_and_balanced() {
+        let mut table = SymbolTable::default();
+        let names: Vec<_> = (0..64)
+            .map(|i| table.intern(&format!("n{}", (i * 37) % 64)))
+            .collect();
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut attrs = AttrSet::new();
+        let mut model = BTreeMap::new();
+        for step in 0..2000 {
+            let name = &names[rng.below(names.len() as u64) as usize];
+            if rng.below(3) == 0 {
+                let removed = attrs.remove(name).map(|value| int(&value));
+                assert_eq!(removed, model.remove(name));
+            } else {
+                attrs.insert(name.clone(), Value::Int(step));
+                model.insert(name.clone(), step);
+            }
+            assert_matches(&attrs, &model);
+            for name in &names {
+                assert_eq!(attrs.get(name).map(int), model.get(name).copied());
+            }
+        }
+    }
+
+    #[test]
+    fn clones_are_unchanged_by_later_modifications() {
+        let mut table = SymbolTable::default();
+        let names: Vec<_> = (0..100).map(|i| table.intern(&format!("n{}", i))).collect();
+        let mut rng = Rng(42);
+        let mut attrs = AttrSet::new();
+        let mut model = BTreeMap::new();
+        let mut snapshots = Vec::new();
+        for step in 0..500 {
+            let name = &names[rng.below(names.len() as u64) as usize];
+            if rng.below(4) == 0 {
+                attrs.remove(name);
+                model.remove(name);
+            } else {
+                attrs.insert(name.clone(), Value::Int(step));
+                model.insert(name.clone(), step);
+            }
+            if step % 50 == 0 {
+                snapshots.push((attrs.clone(), model.clone()));
+            }
+        }
+        for (attrs, model) in &snapshots {
+            assert_matches(attrs, model);
+        }
+    }
+
+    #[test]
+    fn removing_a_missing_name_changes_nothing() {
+        let mut table = SymbolTable::default();
+        let [a, b, missing] = ["a", "b", "missing"].map(|name| table.intern(name));
+        let mut attrs: AttrSet = [(a, Value::Int(1)), (b, Value::Int(2))]
+            .into_iter()
+            .collect();
+        let before = attrs.as_ptr();
+        assert!(attrs.remove(&missing).is_none());
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs.as_ptr(), before);
+        assert!(AttrSet::new().remove(&missing).is_none());
+    }
+
+    #[test]
+    fn updates_prefer_the_right_hand_side() {
+        let mut table = SymbolTable::default();
+        let names: Vec<_> = (0..40).map(|i| table.intern(&format!("n{}", i))).collect();
+        let mut rng = Rng(7);
+        for _ in 0..50 {
+            let mut sides = [AttrSet::new(), AttrSet::new()];
+            let mut model = BTreeMap::new();
+            for (side, attrs) in sides.iter_mut().enumerate() {
+                for _ in 0..rng.below(30) {
+                    let name = &names[rng.below(names.len() as u64) as usize];
+                    let value = side as i64 * 100 + rng.below(100) as i64;
+                    attrs.insert(name.clone(), Value::Int(value));
+                    model.insert(name.clone(), value);
+                }
+            }
+            let [left, right] = &sides;
+            assert_matches(&left.update(right), &model);
+        }
+    }
+
+    #[test]
+    fn sets_are_sorted_alphabetically_for_display() {
+        let mut table = SymbolTable::default();
+        let attrs: AttrSet = ["c", "a", "b"]
+            .map(|name| (table.intern(name), Value::Int(0)))
+            .into_iter()
+            .collect();
+        let keys: Vec<_> = attrs.keys().map(Symbol::as_str).collect();
+        assert_eq!(keys, ["c", "a", "b"]);
+        let sorted: Vec<_> = attrs
+            .sorted()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(sorted, ["a", "b", "c"]);
+    }
+}
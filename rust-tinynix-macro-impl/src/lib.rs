@@ -1,24 +1,68 @@
 use proc_macro::TokenStream;
+use proc_macro2::{Delimiter, Group, Span, TokenTree};
+use rust_tinynix_core::diagnostics::Diagnostic;
+use rust_tinynix_core::parser::{self, TokenSource};
+use rust_tinynix_core::span::FileId;
 use syn::spanned::Spanned;
 
 #[proc_macro]
 pub fn nix(input: TokenStream) -> TokenStream {
     let tokens: proc_macro2::TokenStream = input.into();
-    let span = tokens.span(); // Used for error reporting
+    let span = tokens.span(); // For errors that can't be pinned on a single token
 
     let root = std::env::current_dir().expect("Could not get current working directory");
 
-    let code_as_string = tokens.to_string();
+    let source = TokenSource::new(tokens);
+    let parsed = parser::parse_source_recovering(source.text(), &root, FileId::UNKNOWN);
+    let errors = parsed
+        .errors
+        .iter()
+        .map(|error| to_syn_error(&error.to_diagnostic(FileId::UNKNOWN), &source, span))
+        .reduce(|mut errors, error| {
+            errors.combine(error);
+            errors
+        });
+    if let Some(errors) = errors {
+        // Several `compile_error!`s in a row only make a valid expression inside a block.
+        let block = Group::new(Delimiter::Brace, errors.to_compile_error());
+        return proc_macro2::TokenStream::from(TokenTree::Group(block)).into();
+    }
 
-    let ast = match rust_tinynix_core::parser::parse(&code_as_string, &root) {
-        Ok(ast) => ast,
-        Err(e) => {
-            // Use the span we captured earlier for a precise error location.
-            return syn::Error::new(span, format!("Nix parsing failed:\n{}", e))
-                .to_compile_error()
-                .into();
-        }
-    };
+    rust_tinynix_core::codegen::generate_token_stream(&parsed.expr).into()
+}
+
+/// An error at the tokens the diagnostic points at. `syn` has no way to attach notes
+/// to an error, so the secondary labels become lines of its message, with the location
+/// they point at where the compiler tells it.
+fn to_syn_error(diagnostic: &Diagnostic, source: &TokenSource, fallback: Span) -> syn::Error {
+    let locate = |span| source.rust_span(span).unwrap_or(fallback);
+
+    let mut message = format!("Nix parsing failed: {}", diagnostic.message);
+    if let Some(label) = diagnostic.labels.iter().find(|label| label.primary)
+        && !label.message.is_empty()
+    {
+        message = format!("{}\n{}", message, label.message);
+    }
+    for label in diagnostic.labels.iter().filter(|label| !label.primary) {
+        let start = locate(label.span).start();
+        message = match start.line {
+            0 => format!("{}\n= note: {}", message, label.message),
+            line => format!(
+                "{}\n= note: {} at line {}, column {}",
+                message,
+                label.message,
+                line,
+                start.column + 1
+            ),
+        };
+    }
+    for note in &diagnostic.notes {
+        message = format!("{}\n= note: {}", message, note);
+    }
+    for help in &diagnostic.help {
+        message = format!("{}\n= help: {}", message, help);
+    }
 
-    rust_tinynix_core::codegen::generate_token_stream(&ast).into()
+    let primary = diagnostic.primary_span().map_or(fallback, locate);
+    syn::Error::new(primary, message)
 }
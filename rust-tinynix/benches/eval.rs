@@ -0,0 +1,76 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rust_tinynix::{Backend, Evaluator};
+use std::fmt::Write;
+use std::path::Path;
+
+/// A library with many functions, of which each import only uses one.
+fn library() -> String {
+    let mut source = String::from("{\n");
+    for i in 0..300 {
+        writeln!(
+            source,
+            "  f{i} = a: b: let c = a + b + x; in {{ inherit c; d = c - {i}; }};"
+        )
+        .unwrap();
+    }
+    source.push_str("  result = x + 1;\n}\n");
+    source
+}
+
+/// Imports the library once for each of `count` different scopes.
+fn scoped_imports(library: &Path, count: usize) -> String {
+    let mut source = String::from("[\n");
+    for i in 0..count {
+        writeln!(
+            source,
+            "  (let lib = scopedImport {{ x = {i}; }} {}; in lib.result)",
+            library.display()
+        )
+        .unwrap();
+    }
+    source.push_str("]\n");
+    source
+}
+
+fn scoped_import(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("tinynix-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let library_path = dir.join("lib.nix");
+    std::fs::write(&library_path, library()).unwrap();
+    let source = scoped_imports(&library_path, 100);
+
+    let mut group = c.benchmark_group("scoped_import");
+    for backend in [Backend::TreeWalking, Backend::Bytecode] {
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", backend)), |b| {
+            let evaluator = Evaluator::builder().backend(backend).build();
+            b.iter(|| evaluator.eval_str(&source).unwrap())
+        });
+    }
+    group.finish();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Applies `twice` to itself `depth` times, which calls `inc` 2^`depth` times.
+fn calls_source(depth: usize) -> String {
+    format!(
+        "let twice = f: x: f (f x); inc = x: x + 1; in {}inc{} 0",
+        "twice (".repeat(depth),
+        ")".repeat(depth)
+    )
+}
+
+fn calls(c: &mut Criterion) {
+    let source = calls_source(14);
+
+    let mut group = c.benchmark_group("calls");
+    for backend in [Backend::TreeWalking, Backend::Bytecode] {
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", backend)), |b| {
+            let evaluator = Evaluator::builder().backend(backend).build();
+            b.iter(|| evaluator.eval_str(&source).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scoped_import, calls);
+criterion_main!(benches);
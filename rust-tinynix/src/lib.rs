@@ -1,6 +1,19 @@
 pub use rust_tinynix_core::{
-    NixBinaryOp, NixExpr, NixStringPart, NixUnaryOp, NixValue,
-    eval::{EvaluationError, Scope, nix_eval},
-    nix_file, nix_str,
+    NixBinaryOp, NixExpr, NixExprKind, NixStringPart, NixUnaryOp, NixValue,
+    ast::{Ast, ExprId},
+    cancellation::CancellationToken,
+    diagnostics::{ColorMode, Diagnostic, Label, Severity},
+    eval::{
+        Backend, CycleMember, EvalContext, EvaluationError, Scope, TraceFrame, TraceFrameKind,
+        TraceSink, nix_eval, nix_eval_ast, nix_eval_with,
+    },
+    evaluator::{Evaluator, EvaluatorBuilder},
+    limits::{Limits, ResourceLimit},
+    nix_file, nix_file_with_scope, nix_str,
+    parser::ParseError,
+    search_path::{SearchPath, SearchPathEntry},
+    span::{FileId, Location, SourceMap, Span},
+    symbol::Symbol,
+    value::{AttrSet, Lambda, NixString, PrimOp, Thunk, Value},
 };
 pub use rust_tinynix_macro_impl::nix;
@@ -6,7 +6,6 @@ use rust_tinynix::nix;
 // - error handling
 // - functions
 // - builtins
-// - ideally, we'd ditch pest and directly parse using syn
 // - implement thunks
 // - derivations
 // TODO: BOXES